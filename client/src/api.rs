@@ -0,0 +1,397 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_trait::async_trait;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::Method;
+
+use istruct_common::{
+    api::{
+        backup::v1::{BackupApi, Export, Import},
+        compute::{
+            devadm::v1::{DevAdmApi, DeviceType},
+            machine::{
+                device::v1::{CpuDevice, MachineDevApi, MemoryDevice},
+                v1::{
+                    Adopt, AttrValue, MachineAction, MachineApi, MachineEvent, MachineSpec,
+                    MachineStatus,
+                },
+            },
+        },
+        network::device::v1::NetworkDevApi,
+        operation::v1::{Operation, OperationApi, Pending, Wait},
+        reconcile::v1::{Reconcile, ReconcileApi, Report},
+        storage::device::v1::{BlockDevice, CdromDevice, StorageDevApi},
+        ApiBase,
+    },
+    error::{Error, Result},
+    etag::{IfMatch, Tagged},
+    event::EventStream,
+    id::{DeviceId, MachineId, OperationId},
+};
+
+use super::Remote;
+
+const MACHINE: &str = "is.compute.machine";
+const MACHINE_DEV: &str = "is.compute.machine.device";
+const DEVADM: &str = "is.compute.devadm";
+const STORAGE_DEV: &str = "is.storage.device";
+const NETWORK_DEV: &str = "is.network.device";
+const OPERATION: &str = "is.operation";
+const RECONCILE: &str = "is.reconcile";
+const BACKUP: &str = "is.backup";
+
+const NO_BODY: Option<&()> = None;
+
+/// Everything but the unreserved characters of RFC 3986.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Attribute keys can hold anything but `/`, so they're encoded to stay one path segment.
+fn attr_path(machine: MachineId, attr: &str) -> String {
+    format!(
+        "/attr/{}/io/{}",
+        machine,
+        utf8_percent_encode(attr, SEGMENT)
+    )
+}
+
+impl ApiBase for Remote {}
+
+#[async_trait]
+impl MachineApi for Remote {
+    async fn act(&self, machine: MachineId, action: MachineAction) -> Result<OperationId> {
+        let action = serde_json::to_value(action).expect("action serializes to a string");
+        let action = action.as_str().expect("action serializes to a string");
+
+        self.post(MACHINE, &format!("/act/{}/{}", machine, action), NO_BODY)
+            .await
+    }
+
+    async fn status(&self, machine: MachineId) -> Result<Option<MachineStatus>> {
+        self.get_opt(MACHINE, &format!("/status/{}", machine)).await
+    }
+
+    async fn get_attr(&self, machine: MachineId, attr: String) -> Result<Tagged<AttrValue>> {
+        self.get_tagged(MACHINE, &attr_path(machine, &attr))
+            .await?
+            .ok_or_else(|| Error::not_found("could not find attribute"))
+    }
+
+    async fn set_attr(
+        &self,
+        machine: MachineId,
+        attr: String,
+        if_match: IfMatch,
+        value: AttrValue,
+    ) -> Result<()> {
+        self.send_if(
+            Method::PUT,
+            MACHINE,
+            &attr_path(machine, &attr),
+            &if_match,
+            Some(&value),
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn delete_attr(&self, machine: MachineId, attr: String, if_match: IfMatch) -> Result<()> {
+        self.send_if(
+            Method::DELETE,
+            MACHINE,
+            &attr_path(machine, &attr),
+            &if_match,
+            NO_BODY,
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn get_attrs(&self, machine: MachineId) -> Result<Tagged<BTreeMap<String, AttrValue>>> {
+        self.get_tagged(MACHINE, &format!("/attr/{}/io", machine))
+            .await?
+            .ok_or_else(|| Error::not_found("could not find machine"))
+    }
+
+    async fn patch_attrs(
+        &self,
+        machine: MachineId,
+        if_match: IfMatch,
+        attrs: HashMap<String, Option<AttrValue>>,
+    ) -> Result<()> {
+        self.send_if(
+            Method::PATCH,
+            MACHINE,
+            &format!("/attr/{}/io", machine),
+            &if_match,
+            Some(&attrs),
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn list_attrs(&self, machine: MachineId) -> Result<Vec<String>> {
+        self.get(MACHINE, &format!("/attr/{}/ls", machine)).await
+    }
+
+    async fn dev_list(
+        &self,
+        machine: MachineId,
+    ) -> Result<Option<Tagged<HashMap<DeviceId, String>>>> {
+        self.get_tagged(MACHINE, &format!("/dev/{}", machine)).await
+    }
+
+    async fn dev_attach(
+        &self,
+        machine: MachineId,
+        device: DeviceId,
+        if_match: IfMatch,
+    ) -> Result<()> {
+        self.send_if(
+            Method::PUT,
+            MACHINE,
+            &format!("/dev/{}/plug/{}", machine, device),
+            &if_match,
+            NO_BODY,
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn dev_detach(
+        &self,
+        machine: MachineId,
+        device: DeviceId,
+        if_match: IfMatch,
+    ) -> Result<()> {
+        self.send_if(
+            Method::DELETE,
+            MACHINE,
+            &format!("/dev/{}/plug/{}", machine, device),
+            &if_match,
+            NO_BODY,
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn create(&self, spec: MachineSpec) -> Result<MachineId> {
+        self.post(MACHINE, "/m", Some(&spec)).await
+    }
+
+    async fn destroy(&self, machine: MachineId, if_match: IfMatch) -> Result<()> {
+        self.send_if(
+            Method::DELETE,
+            MACHINE,
+            &format!("/m/{}", machine),
+            &if_match,
+            NO_BODY,
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn list(&self) -> Result<Vec<MachineId>> {
+        self.get(MACHINE, "/m").await
+    }
+
+    async fn adoptable(&self) -> Result<Vec<MachineId>> {
+        self.get(MACHINE, "/adopt").await
+    }
+
+    async fn adopt(&self, machine: MachineId, adopt: Adopt) -> Result<HashMap<DeviceId, String>> {
+        self.post(MACHINE, &format!("/adopt/{}", machine), Some(&adopt))
+            .await
+    }
+
+    async fn events(&self) -> Result<EventStream<MachineEvent>> {
+        self.subscribe(MACHINE, "/events").await
+    }
+}
+
+#[async_trait]
+impl DevAdmApi for Remote {
+    async fn all(&self) -> Result<HashMap<DeviceId, DeviceType>> {
+        self.get(DEVADM, "/all").await
+    }
+
+    async fn get_type(&self, dev: DeviceId) -> Result<Option<DeviceType>> {
+        self.get_opt(DEVADM, &format!("/type/{}", dev)).await
+    }
+}
+
+#[async_trait]
+impl MachineDevApi for Remote {
+    async fn get_memory(&self, device: DeviceId) -> Result<Option<Tagged<MemoryDevice>>> {
+        self.get_tagged(MACHINE_DEV, &format!("/mem/{}", device))
+            .await
+    }
+
+    async fn set_memory(
+        &self,
+        device: DeviceId,
+        if_match: IfMatch,
+        memory: MemoryDevice,
+    ) -> Result<()> {
+        self.send_if(
+            Method::PATCH,
+            MACHINE_DEV,
+            &format!("/mem/{}", device),
+            &if_match,
+            Some(&memory),
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn get_cpu(&self, device: DeviceId) -> Result<Option<Tagged<CpuDevice>>> {
+        self.get_tagged(MACHINE_DEV, &format!("/cpu/{}", device))
+            .await
+    }
+
+    async fn set_cpu(&self, device: DeviceId, if_match: IfMatch, cpu: CpuDevice) -> Result<()> {
+        self.send_if(
+            Method::PATCH,
+            MACHINE_DEV,
+            &format!("/cpu/{}", device),
+            &if_match,
+            Some(&cpu),
+        )
+        .await
+        .map(drop)
+    }
+}
+
+#[async_trait]
+impl NetworkDevApi for Remote {
+    async fn create_nat(&self) -> Result<DeviceId> {
+        self.post(NETWORK_DEV, "/nat", NO_BODY).await
+    }
+
+    async fn delete_nat(&self, device: DeviceId) -> Result<()> {
+        self.send(
+            Method::DELETE,
+            NETWORK_DEV,
+            &format!("/nat/{}", device),
+            NO_BODY,
+        )
+        .await
+        .map(drop)
+    }
+}
+
+#[async_trait]
+impl StorageDevApi for Remote {
+    async fn get_block(&self, device: DeviceId) -> Result<Option<Tagged<BlockDevice>>> {
+        self.get_tagged(STORAGE_DEV, &format!("/block/{}", device))
+            .await
+    }
+
+    async fn create_block(&self, block: BlockDevice) -> Result<Pending<DeviceId>> {
+        self.post(STORAGE_DEV, "/block", Some(&block)).await
+    }
+
+    async fn delete_block(&self, device: DeviceId, if_match: IfMatch) -> Result<()> {
+        self.send_if(
+            Method::DELETE,
+            STORAGE_DEV,
+            &format!("/block/{}", device),
+            &if_match,
+            NO_BODY,
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn get_cdrom(&self, device: DeviceId) -> Result<Option<Tagged<CdromDevice>>> {
+        self.get_tagged(STORAGE_DEV, &format!("/cdrom/{}", device))
+            .await
+    }
+
+    async fn create_cdrom(&self, cdrom: CdromDevice) -> Result<DeviceId> {
+        self.post(STORAGE_DEV, "/cdrom", Some(&cdrom)).await
+    }
+
+    async fn delete_cdrom(&self, device: DeviceId, if_match: IfMatch) -> Result<()> {
+        self.send_if(
+            Method::DELETE,
+            STORAGE_DEV,
+            &format!("/cdrom/{}", device),
+            &if_match,
+            NO_BODY,
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn insert_media(&self, device: DeviceId, if_match: IfMatch, media: String) -> Result<()> {
+        self.send_if(
+            Method::PUT,
+            STORAGE_DEV,
+            &format!("/cdrom/{}/media", device),
+            &if_match,
+            Some(&media),
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn eject_media(&self, device: DeviceId, if_match: IfMatch) -> Result<()> {
+        self.send_if(
+            Method::DELETE,
+            STORAGE_DEV,
+            &format!("/cdrom/{}/media", device),
+            &if_match,
+            NO_BODY,
+        )
+        .await
+        .map(drop)
+    }
+}
+
+#[async_trait]
+impl OperationApi for Remote {
+    async fn list_ops(&self) -> Result<Vec<OperationId>> {
+        self.get(OPERATION, "/op").await
+    }
+
+    async fn get_op(&self, op: OperationId) -> Result<Option<Operation>> {
+        self.get_opt(OPERATION, &format!("/op/{}", op)).await
+    }
+
+    async fn wait_op(&self, op: OperationId, wait: Wait) -> Result<Operation> {
+        self.post(OPERATION, &format!("/op/{}/wait", op), Some(&wait))
+            .await
+    }
+
+    async fn cancel_op(&self, op: OperationId) -> Result<()> {
+        self.send(Method::DELETE, OPERATION, &format!("/op/{}", op), NO_BODY)
+            .await
+            .map(drop)
+    }
+}
+
+#[async_trait]
+impl ReconcileApi for Remote {
+    async fn drift(&self) -> Result<Option<Report>> {
+        self.get_opt(RECONCILE, "/drift").await
+    }
+
+    async fn reconcile(&self, run: Reconcile) -> Result<Report> {
+        self.post(RECONCILE, "/run", Some(&run)).await
+    }
+}
+
+#[async_trait]
+impl BackupApi for Remote {
+    async fn export(&self, export: Export) -> Result<OperationId> {
+        self.post(BACKUP, "/export", Some(&export)).await
+    }
+
+    async fn import(&self, import: Import) -> Result<OperationId> {
+        self.post(BACKUP, "/import", Some(&import)).await
+    }
+}
@@ -0,0 +1,682 @@
+//! HTTP client for istruct components.
+//!
+//! [`Remote`] implements the same API traits as an in-process component does,
+//! so it can be used as a drop-in for one.
+
+use std::collections::HashMap;
+
+use futures_util::StreamExt;
+use istruct_common::{
+    error::{Error, Result},
+    etag::{ETag, IfMatch, Tagged},
+    event::EventStream,
+    router::{Discovery, DISCOVERY_PATH},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+mod api;
+
+/// Every API prefix this client implements, with the `(major, minor)` version it was written against.
+pub const SUPPORTED: [(&str, usize, usize); 8] = [
+    ("is.compute.machine", 0, 1),
+    ("is.compute.machine.device", 0, 1),
+    ("is.compute.devadm", 0, 1),
+    ("is.storage.device", 0, 1),
+    ("is.network.device", 0, 1),
+    ("is.operation", 0, 1),
+    ("is.reconcile", 0, 1),
+    ("is.backup", 0, 1),
+];
+
+/// Which version prefix is used to talk to an API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// `/vMAJOR`, whichever minor the component resolves it to.
+    Major(usize),
+    /// `/vMAJOR.MINOR`
+    Pinned(usize, usize),
+}
+
+impl Version {
+    fn segment(&self) -> String {
+        match self {
+            Version::Major(major) => format!("v{}", major),
+            Version::Pinned(major, minor) => format!("v{}.{}", major, minor),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Remote {
+    http: reqwest::Client,
+    base: String,
+    versions: HashMap<&'static str, Version>,
+}
+
+impl Remote {
+    /// Creates a remote for the component at `base` (e.g. `http://[::1]:8989`),
+    /// pinned to the versions in [`SUPPORTED`].
+    pub fn new<I: Into<String>>(base: I) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base: base.into().trim_end_matches('/').to_string(),
+            versions: SUPPORTED
+                .iter()
+                .map(|&(prefix, major, minor)| (prefix, Version::Pinned(major, minor)))
+                .collect(),
+        }
+    }
+
+    /// Creates a remote and negotiates the versions with the component's discovery document.
+    ///
+    /// For every API, the highest minor version of the supported major is picked,
+    /// APIs the component doesn't serve are left pinned, and will fail when used.
+    pub async fn negotiate<I: Into<String>>(base: I) -> anyhow::Result<Self> {
+        let mut remote = Self::new(base);
+
+        let discovery = remote.discovery().await?;
+
+        for (prefix, major, minor) in SUPPORTED {
+            let available = discovery
+                .apis
+                .get(prefix)
+                .and_then(|versions| versions.iter().find(|v| v.major == major));
+
+            if let Some(available) = available {
+                if available.default_minor < minor {
+                    anyhow::bail!(
+                        "{} is served at v{}.{}, need at least v{}.{}",
+                        prefix,
+                        major,
+                        available.default_minor,
+                        major,
+                        minor
+                    );
+                }
+
+                remote.set_version(prefix, Version::Pinned(major, available.default_minor))?;
+            }
+        }
+
+        Ok(remote)
+    }
+
+    /// Overrides the version used for one API prefix.
+    pub fn set_version(&mut self, prefix: &str, version: Version) -> anyhow::Result<()> {
+        let (prefix, major, _) = SUPPORTED
+            .iter()
+            .find(|(p, _, _)| *p == prefix)
+            .ok_or(anyhow::anyhow!("unsupported api prefix {}", prefix))?;
+
+        let requested = match version {
+            Version::Major(m) | Version::Pinned(m, _) => m,
+        };
+
+        if requested != *major {
+            anyhow::bail!("{} is only supported at major version {}", prefix, major);
+        }
+
+        self.versions.insert(prefix, version);
+
+        Ok(())
+    }
+
+    pub fn version(&self, prefix: &str) -> Option<Version> {
+        self.versions.get(prefix).copied()
+    }
+
+    pub async fn discovery(&self) -> Result<Discovery> {
+        let res = self
+            .http
+            .get(format!("{}{}", self.base, DISCOVERY_PATH))
+            .send()
+            .await
+            .map_err(transport)?;
+
+        json(check(res).await?).await
+    }
+
+    fn url(&self, prefix: &'static str, path: &str) -> String {
+        let version = self.versions[prefix];
+
+        format!("{}/{}/{}{}", self.base, prefix, version.segment(), path)
+    }
+
+    async fn get<R: DeserializeOwned>(&self, prefix: &'static str, path: &str) -> Result<R> {
+        let res = self
+            .http
+            .get(self.url(prefix, path))
+            .send()
+            .await
+            .map_err(transport)?;
+
+        json(check(res).await?).await
+    }
+
+    /// Like [`Remote::get`], but maps `404 NOT_FOUND` to `None`.
+    async fn get_opt<R: DeserializeOwned>(
+        &self,
+        prefix: &'static str,
+        path: &str,
+    ) -> Result<Option<R>> {
+        let res = self
+            .http
+            .get(self.url(prefix, path))
+            .send()
+            .await
+            .map_err(transport)?;
+
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        json(check(res).await?).await.map(Some)
+    }
+
+    /// Like [`Remote::get_opt`], also returns the `ETag` of the resource.
+    async fn get_tagged<R: DeserializeOwned>(
+        &self,
+        prefix: &'static str,
+        path: &str,
+    ) -> Result<Option<Tagged<R>>> {
+        let res = self
+            .http
+            .get(self.url(prefix, path))
+            .send()
+            .await
+            .map_err(transport)?;
+
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        tagged(check(res).await?).await.map(Some)
+    }
+
+    async fn post<R: DeserializeOwned>(
+        &self,
+        prefix: &'static str,
+        path: &str,
+        body: Option<&(impl Serialize + Sync)>,
+    ) -> Result<R> {
+        json(self.send(reqwest::Method::POST, prefix, path, body).await?).await
+    }
+
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        prefix: &'static str,
+        path: &str,
+        body: Option<&(impl Serialize + Sync)>,
+    ) -> Result<reqwest::Response> {
+        self.send_if(method, prefix, path, &IfMatch::None, body)
+            .await
+    }
+
+    /// Like [`Remote::send`], with an `If-Match` precondition.
+    async fn send_if(
+        &self,
+        method: reqwest::Method,
+        prefix: &'static str,
+        path: &str,
+        if_match: &IfMatch,
+        body: Option<&(impl Serialize + Sync)>,
+    ) -> Result<reqwest::Response> {
+        let mut req = self.http.request(method, self.url(prefix, path));
+
+        if let Some(tags) = if_match.header() {
+            req = req.header(reqwest::header::IF_MATCH, tags);
+        }
+
+        if let Some(body) = body {
+            req = req.json(body);
+        }
+
+        check(req.send().await.map_err(transport)?).await
+    }
+
+    /// Subscribes to a server-sent event route, the stream ends when the connection drops.
+    ///
+    /// Events that fail to decode are skipped.
+    async fn subscribe<T>(&self, prefix: &'static str, path: &str) -> Result<EventStream<T>>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let res = self
+            .http
+            .get(self.url(prefix, path))
+            .send()
+            .await
+            .map_err(transport)?;
+
+        let body = check(res).await?.bytes_stream();
+
+        let stream =
+            futures_util::stream::unfold((body, vec![]), |(mut body, mut buf)| async move {
+                loop {
+                    while let Some(data) = next_data(&mut buf) {
+                        if let Ok(event) = serde_json::from_str(&data) {
+                            return Some((event, (body, buf)));
+                        }
+                    }
+
+                    match body.next().await {
+                        Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+                        _ => return None,
+                    }
+                }
+            });
+
+        Ok(Box::pin(stream))
+    }
+}
+
+/// Takes the `data` of the next complete event out of `buf`.
+///
+/// Events without data (such as keep-alive comments) are dropped.
+fn next_data(buf: &mut Vec<u8>) -> Option<String> {
+    loop {
+        let end = buf.windows(2).position(|w| w == b"\n\n")?;
+
+        let frame: Vec<u8> = buf.drain(..end + 2).collect();
+        let frame = String::from_utf8_lossy(&frame);
+
+        let data: Vec<&str> = frame
+            .lines()
+            .filter_map(|l| l.strip_prefix("data:"))
+            .map(|d| d.strip_prefix(' ').unwrap_or(d))
+            .collect();
+
+        if !data.is_empty() {
+            return Some(data.join("\n"));
+        }
+    }
+}
+
+fn transport(e: reqwest::Error) -> Error {
+    Error::backend(format!("request to component failed: {}", e))
+}
+
+async fn json<R: DeserializeOwned>(res: reqwest::Response) -> Result<R> {
+    res.json()
+        .await
+        .map_err(|e| Error::backend(format!("invalid response from component: {}", e)))
+}
+
+async fn tagged<R: DeserializeOwned>(res: reqwest::Response) -> Result<Tagged<R>> {
+    let etag = res
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .and_then(ETag::parse)
+        .ok_or_else(|| Error::backend("response from component has no valid etag"))?;
+
+    json(res).await.map(|value| Tagged::new(value, etag))
+}
+
+/// Turns non-success responses into errors, decoding the error body where possible.
+async fn check(res: reqwest::Response) -> Result<reqwest::Response> {
+    let status = res.status();
+
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    let text = res.text().await.unwrap_or_default();
+
+    Err(serde_json::from_str::<Error>(&text)
+        .unwrap_or_else(|_| Error::backend(format!("{}: {}", status, text))))
+}
+
+/// Negotiates with the component at `base` and runs the conformance checks against it.
+#[cfg(feature = "conformance")]
+pub async fn conformance<I: Into<String>>(
+    base: I,
+) -> anyhow::Result<istruct_common::conformance::Report> {
+    let remote = Remote::negotiate(base).await?;
+
+    Ok(istruct_common::conformance::run(&remote, &remote).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::Mutex,
+    };
+
+    use async_trait::async_trait;
+    use istruct_common::{
+        api::{
+            self,
+            compute::machine::v1::{
+                Adopt, AttrValue, MachineAction, MachineApi, MachineEvent, MachineSpec,
+                MachineStatus,
+            },
+            operation::v1::Pending,
+            storage::device::v1::{BlockDevice, CdromDevice, StorageDevApi},
+            ApiBase,
+        },
+        error::{Error, ErrorKind, Result},
+        etag::{ETag, IfMatch, Tagged},
+        event::EventStream,
+        id::{DeviceId, MachineId, OperationId},
+        router::{CompositeRouter, VersionedRouter},
+    };
+
+    use super::{next_data, Remote, Version};
+
+    /// Serves `router` on a free port, and negotiates a remote for it.
+    async fn serve(router: VersionedRouter) -> Remote {
+        let router = CompositeRouter::new_with([router]).unwrap().assemble();
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(router.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        Remote::negotiate(format!("http://{}", addr)).await.unwrap()
+    }
+
+    struct Fixed;
+
+    impl ApiBase for Fixed {}
+
+    #[async_trait]
+    impl StorageDevApi for Fixed {
+        async fn get_block(&self, device: DeviceId) -> Result<Option<Tagged<BlockDevice>>> {
+            Ok(device
+                .is_nil()
+                .then(|| Tagged::new(BlockDevice { bytes: 1 }, ETag::new(1))))
+        }
+
+        async fn create_block(&self, block: BlockDevice) -> Result<Pending<DeviceId>> {
+            Ok(Pending {
+                operation: OperationId::nil(),
+                target: DeviceId::from_u128(block.bytes as u128),
+            })
+        }
+
+        async fn delete_block(&self, _device: DeviceId, if_match: IfMatch) -> Result<()> {
+            if_match.check(&ETag::new(1))?;
+
+            Err(Error::already_attached("device is attached"))
+        }
+
+        async fn get_cdrom(&self, _device: DeviceId) -> Result<Option<Tagged<CdromDevice>>> {
+            Err(Error::backend("cdroms are offline"))
+        }
+
+        async fn create_cdrom(&self, _cdrom: CdromDevice) -> Result<DeviceId> {
+            Err(Error::invalid_argument("no cdroms here"))
+        }
+
+        async fn delete_cdrom(&self, _device: DeviceId, _if_match: IfMatch) -> Result<()> {
+            Err(Error::not_found("could not find device"))
+        }
+
+        async fn insert_media(
+            &self,
+            _device: DeviceId,
+            _if_match: IfMatch,
+            _media: String,
+        ) -> Result<()> {
+            Err(Error::not_found("could not find device"))
+        }
+
+        async fn eject_media(&self, _device: DeviceId, _if_match: IfMatch) -> Result<()> {
+            Err(Error::not_found("could not find device"))
+        }
+    }
+
+    #[tokio::test]
+    async fn storage_round_trip() {
+        let remote = serve(api::storage::device::v1::convert(Fixed)).await;
+
+        assert_eq!(
+            remote.version("is.storage.device"),
+            Some(Version::Pinned(0, 1))
+        );
+
+        let block = remote.get_block(DeviceId::nil()).await.unwrap().unwrap();
+        assert_eq!(block.value.bytes, 1);
+        assert_eq!(block.etag, ETag::new(1));
+
+        assert!(remote
+            .get_block(DeviceId::from_u128(1))
+            .await
+            .unwrap()
+            .is_none());
+
+        let err = remote.get_cdrom(DeviceId::nil()).await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BackendFailure);
+        assert_eq!(
+            err.message,
+            "failed to get cdrom device: cdroms are offline"
+        );
+        assert_eq!(
+            remote
+                .create_block(BlockDevice { bytes: 5 })
+                .await
+                .unwrap()
+                .target,
+            DeviceId::from_u128(5)
+        );
+
+        let err = remote
+            .delete_block(DeviceId::nil(), IfMatch::tag(ETag::new(2)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::PreconditionFailed);
+
+        let err = remote
+            .delete_block(DeviceId::nil(), IfMatch::tag(block.etag))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::AlreadyAttached);
+        assert_eq!(
+            err.message,
+            "failed to delete block device: device is attached"
+        );
+    }
+
+    /// Attributes of a single machine, and creates that return the cores asked for as the id.
+    ///
+    /// Everything else fails.
+    #[derive(Default)]
+    struct Attrs(Mutex<BTreeMap<String, AttrValue>>);
+
+    impl ApiBase for Attrs {}
+
+    #[async_trait]
+    impl MachineApi for Attrs {
+        async fn act(&self, _machine: MachineId, _action: MachineAction) -> Result<OperationId> {
+            Err(Error::not_found("could not find machine"))
+        }
+
+        async fn status(&self, _machine: MachineId) -> Result<Option<MachineStatus>> {
+            Ok(None)
+        }
+
+        async fn get_attr(&self, _machine: MachineId, attr: String) -> Result<Tagged<AttrValue>> {
+            self.0
+                .lock()
+                .unwrap()
+                .get(&attr)
+                .map(|v| Tagged::new(v.clone(), ETag::new(1)))
+                .ok_or_else(|| Error::not_found(format!("could not find attribute {}", attr)))
+        }
+
+        async fn set_attr(
+            &self,
+            _machine: MachineId,
+            attr: String,
+            _if_match: IfMatch,
+            value: AttrValue,
+        ) -> Result<()> {
+            self.0.lock().unwrap().insert(attr, value);
+
+            Ok(())
+        }
+
+        async fn delete_attr(
+            &self,
+            _machine: MachineId,
+            attr: String,
+            _if_match: IfMatch,
+        ) -> Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .remove(&attr)
+                .map(drop)
+                .ok_or_else(|| Error::not_found(format!("could not find attribute {}", attr)))
+        }
+
+        async fn get_attrs(
+            &self,
+            _machine: MachineId,
+        ) -> Result<Tagged<BTreeMap<String, AttrValue>>> {
+            Ok(Tagged::new(self.0.lock().unwrap().clone(), ETag::new(1)))
+        }
+
+        async fn patch_attrs(
+            &self,
+            _machine: MachineId,
+            _if_match: IfMatch,
+            _attrs: HashMap<String, Option<AttrValue>>,
+        ) -> Result<()> {
+            Err(Error::invalid_argument("no patches here"))
+        }
+
+        async fn list_attrs(&self, _machine: MachineId) -> Result<Vec<String>> {
+            Ok(self.0.lock().unwrap().keys().cloned().collect())
+        }
+
+        async fn dev_list(
+            &self,
+            _machine: MachineId,
+        ) -> Result<Option<Tagged<HashMap<DeviceId, String>>>> {
+            Ok(None)
+        }
+
+        async fn dev_attach(
+            &self,
+            _machine: MachineId,
+            _device: DeviceId,
+            _if_match: IfMatch,
+        ) -> Result<()> {
+            Err(Error::not_found("could not find device"))
+        }
+
+        async fn dev_detach(
+            &self,
+            _machine: MachineId,
+            _device: DeviceId,
+            _if_match: IfMatch,
+        ) -> Result<()> {
+            Err(Error::not_found("could not find device"))
+        }
+
+        async fn create(&self, spec: MachineSpec) -> Result<MachineId> {
+            Ok(MachineId::from_u128(spec.cores as u128))
+        }
+
+        async fn destroy(&self, _machine: MachineId, _if_match: IfMatch) -> Result<()> {
+            Err(Error::not_found("could not find machine"))
+        }
+
+        async fn list(&self) -> Result<Vec<MachineId>> {
+            Ok(vec![])
+        }
+
+        async fn adoptable(&self) -> Result<Vec<MachineId>> {
+            Ok(vec![])
+        }
+
+        async fn adopt(
+            &self,
+            _machine: MachineId,
+            _adopt: Adopt,
+        ) -> Result<HashMap<DeviceId, String>> {
+            Err(Error::not_found("could not find domain"))
+        }
+
+        async fn events(&self) -> Result<EventStream<MachineEvent>> {
+            Err(Error::backend("no events here"))
+        }
+    }
+
+    #[tokio::test]
+    async fn attr_keys_stay_one_segment() {
+        let remote = serve(api::compute::machine::v1::convert(Attrs::default())).await;
+
+        let machine = MachineId::nil();
+        let key = "a b?c=d#e%20f&g";
+        let value = AttrValue::String("x".into());
+
+        remote
+            .set_attr(machine, key.into(), IfMatch::None, value.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(remote.list_attrs(machine).await.unwrap(), [key]);
+        assert_eq!(
+            remote.get_attr(machine, key.into()).await.unwrap().value,
+            value
+        );
+
+        remote
+            .delete_attr(machine, key.into(), IfMatch::None)
+            .await
+            .unwrap();
+
+        let err = remote.get_attr(machine, key.into()).await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn create_bodies_default() {
+        let remote = serve(api::compute::machine::v1::convert(Attrs::default())).await;
+
+        let spec = MachineSpec {
+            cores: 3,
+            ..MachineSpec::default()
+        };
+        assert_eq!(remote.create(spec).await.unwrap(), MachineId::from_u128(3));
+
+        let post = |body: &'static str| {
+            remote
+                .http
+                .post(remote.url("is.compute.machine", "/m"))
+                .body(body)
+                .send()
+        };
+
+        for body in ["", "{}"] {
+            let res = post(body).await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+            let id: MachineId = res.json().await.unwrap();
+            assert_eq!(
+                id,
+                MachineId::from_u128(MachineSpec::default().cores as u128)
+            );
+        }
+
+        let res = post("{").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn sse_frames() {
+        let mut buf = b": keep-alive\n\ndata: {\"a\":\ndata: 1}\n\ndata: 2".to_vec();
+
+        assert_eq!(next_data(&mut buf).as_deref(), Some("{\"a\":\n1}"));
+        assert_eq!(next_data(&mut buf), None);
+
+        buf.extend_from_slice(b"\n\n");
+        assert_eq!(next_data(&mut buf).as_deref(), Some("2"));
+        assert!(buf.is_empty());
+    }
+}
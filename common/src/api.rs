@@ -1,5 +1,212 @@
+pub mod backup;
 pub mod compute;
 pub mod network;
+pub mod operation;
+pub mod reconcile;
 pub mod storage;
 
+use async_trait::async_trait;
+use axum::{
+    body::{Bytes, HttpBody},
+    extract::{FromRequest, RequestParts},
+    BoxError,
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::Error;
+
 pub trait ApiBase: Send + Sync + 'static {}
+
+/// JSON request body, a missing one is taken as `T::default()`.
+///
+/// Used by routes marked with `default_body`.
+pub struct JsonOrDefault<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for JsonOrDefault<T>
+where
+    T: DeserializeOwned + Default,
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let body = Bytes::from_request(req)
+            .await
+            .map_err(|e| Error::invalid_argument(format!("failed to read body: {}", e)))?;
+
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Self(T::default()));
+        }
+
+        serde_json::from_slice(&body)
+            .map(Self)
+            .map_err(|e| Error::invalid_argument(format!("invalid body: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use axum::{
+        body::Body,
+        http::{header, Request, Response, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use super::{
+        operation::v1::Pending,
+        storage::device::v1::{convert, BlockDevice, CdromDevice, StorageDevApi},
+        ApiBase,
+    };
+    use crate::{
+        error::{Error, Result},
+        etag::{ETag, IfMatch, Tagged},
+        id::{DeviceId, OperationId},
+        router::CompositeRouter,
+    };
+
+    struct Fixed;
+
+    impl ApiBase for Fixed {}
+
+    #[async_trait]
+    impl StorageDevApi for Fixed {
+        async fn get_block(&self, device: DeviceId) -> Result<Option<Tagged<BlockDevice>>> {
+            Ok(device
+                .is_nil()
+                .then(|| Tagged::new(BlockDevice { bytes: 1 }, ETag::new(1))))
+        }
+
+        async fn create_block(&self, _block: BlockDevice) -> Result<Pending<DeviceId>> {
+            Ok(Pending {
+                operation: OperationId::nil(),
+                target: DeviceId::nil(),
+            })
+        }
+
+        async fn delete_block(&self, _device: DeviceId, if_match: IfMatch) -> Result<()> {
+            if_match.check(&ETag::new(1))?;
+
+            Err(Error::already_attached("device is attached"))
+        }
+
+        async fn get_cdrom(&self, _device: DeviceId) -> Result<Option<Tagged<CdromDevice>>> {
+            Err(Error::backend("cdroms are offline"))
+        }
+
+        async fn create_cdrom(&self, _cdrom: CdromDevice) -> Result<DeviceId> {
+            Err(Error::invalid_argument("no cdroms here"))
+        }
+
+        async fn delete_cdrom(&self, _device: DeviceId, _if_match: IfMatch) -> Result<()> {
+            Err(Error::not_found("could not find device"))
+        }
+
+        async fn insert_media(
+            &self,
+            _device: DeviceId,
+            _if_match: IfMatch,
+            _media: String,
+        ) -> Result<()> {
+            Err(Error::not_found("could not find device"))
+        }
+
+        async fn eject_media(&self, _device: DeviceId, _if_match: IfMatch) -> Result<()> {
+            Err(Error::not_found("could not find device"))
+        }
+    }
+
+    async fn send(req: Request<Body>) -> Response<axum::body::BoxBody> {
+        let router = CompositeRouter::new_with([convert(Fixed)])
+            .unwrap()
+            .assemble();
+
+        router.oneshot(req).await.unwrap()
+    }
+
+    async fn call(method: &str, uri: &str, body: &'static str) -> (StatusCode, String) {
+        let res = send(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await;
+
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn generated_routes() {
+        let nil = DeviceId::nil();
+        let other = DeviceId::from_u128(1);
+
+        assert_eq!(
+            call("GET", &format!("/is.storage.device/v0/block/{}", nil), "").await,
+            (StatusCode::OK, r#"{"bytes":1}"#.into())
+        );
+        assert_eq!(
+            call(
+                "GET",
+                &format!("/is.storage.device/v0.1/block/{}", other),
+                ""
+            )
+            .await
+            .0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            call("GET", &format!("/is.storage.device/v0/cdrom/{}", nil), "").await,
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                r#"{"code":"backend_failure","message":"failed to get cdrom device: cdroms are offline"}"#
+                    .into()
+            )
+        );
+        assert_eq!(
+            call("POST", "/is.storage.device/v0/block", r#"{"bytes":1}"#).await,
+            (
+                StatusCode::OK,
+                format!(r#"{{"operation":"{}","target":"{}"}}"#, nil, nil)
+            )
+        );
+        assert_eq!(
+            call("DELETE", &format!("/is.storage.device/v0/block/{}", nil), "").await,
+            (
+                StatusCode::CONFLICT,
+                r#"{"code":"already_attached","message":"failed to delete block device: device is attached"}"#
+                    .into()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn etag_routes() {
+        let uri = format!("/is.storage.device/v0/block/{}", DeviceId::nil());
+
+        let res = send(Request::get(&uri).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()[header::ETAG], r#""1""#);
+
+        let delete = |tag: &'static str| {
+            Request::delete(&uri)
+                .header(header::IF_MATCH, tag)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(
+            send(delete(r#""2""#)).await.status(),
+            StatusCode::PRECONDITION_FAILED
+        );
+        assert_eq!(send(delete(r#""1""#)).await.status(), StatusCode::CONFLICT);
+        assert_eq!(send(delete("1")).await.status(), StatusCode::BAD_REQUEST);
+    }
+}
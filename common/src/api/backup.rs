@@ -0,0 +1,55 @@
+pub mod v1 {
+    use async_trait::async_trait;
+    use istruct_macros::api;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        api::ApiBase,
+        error::Result,
+        id::{DeviceId, MachineId, OperationId},
+    };
+
+    /// Portable copies of a component's state, to recover from losing a host or to replace it.
+    ///
+    /// Archives are directories on the component's host, their layout is up to the component.
+    #[api(prefix = "is.backup", major = 0, minor = 1)]
+    #[async_trait]
+    pub trait BackupApi: ApiBase {
+        /// Writes every machine and device to a new archive.
+        ///
+        /// The operation's result is the [`Contents`] of the archive.
+        #[route(post, "/export", error = "failed to export")]
+        async fn export(&self, export: Export) -> Result<OperationId>;
+
+        /// Restores an archive, none of its machines or devices can exist yet.
+        ///
+        /// The operation's result is the [`Contents`] that were restored.
+        #[route(post, "/import", error = "failed to import")]
+        async fn import(&self, import: Import) -> Result<OperationId>;
+    }
+
+    /// Request body of `POST /export`.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Export {
+        /// Absolute path of the archive, which can't exist yet.
+        pub path: String,
+        /// Copy disk images into the archive, otherwise only their records are kept.
+        ///
+        /// The operation fails with `invalid_state` if a machine with images is not off.
+        #[serde(default)]
+        pub images: bool,
+    }
+
+    /// Request body of `POST /import`.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Import {
+        /// Absolute path of an archive written by `POST /export`.
+        pub path: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Contents {
+        pub machines: Vec<MachineId>,
+        pub devices: Vec<DeviceId>,
+    }
+}
@@ -1,197 +1,424 @@
 pub mod device;
 
 pub mod v1 {
-    use std::{collections::HashMap, sync::Arc};
+    use std::collections::{BTreeMap, HashMap};
 
     use async_trait::async_trait;
-    use axum::{
-        extract::{Extension, Path},
-        http::StatusCode,
-        routing::{delete, get, post, put},
-        AddExtensionLayer, Json,
-    };
+    use istruct_macros::api;
     use serde::{Deserialize, Serialize};
 
     use crate::{
         api::ApiBase,
-        id::{DeviceId, MachineId},
-        router::VersionedRouter,
+        error::{Error, Result},
+        etag::{IfMatch, Tagged},
+        event::EventStream,
+        id::{DeviceId, MachineId, OperationId},
     };
 
+    #[api(prefix = "is.compute.machine", major = 0, minor = 1)]
     #[async_trait]
     pub trait MachineApi: ApiBase {
         // todo check return types
 
-        async fn act(&self, machine: MachineId, action: MachineAction);
-
-        async fn status(&self, machine: MachineId) -> Option<MachineState>;
-
-        // async fn get_attr(&self, machine: MachineId, attr: String) -> Option<Object>;
-
-        // async fn set_attr(&self, machine: MachineId, attr: String, value: Object);
-
-        // async fn delete_attr(&self, machine: MachineId, attr: String);
-
-        // async fn patch_attrs(&self, machine: MachineId, attrs: HashMap<String, Object>);
-
-        // async fn list_attrs(&self, machine: MachineId) -> Vec<String>;
-
-        async fn dev_list(&self, machine: MachineId) -> Option<HashMap<DeviceId, String>>;
-
-        async fn dev_attach(&self, machine: MachineId, device: DeviceId) -> anyhow::Result<()>;
-
-        async fn dev_detach(&self, machine: MachineId, device: DeviceId) -> anyhow::Result<()>;
-
-        async fn create(&self) -> MachineId;
-
-        async fn destroy(&self, machine: MachineId) -> anyhow::Result<()>;
-
-        async fn list(&self) -> Vec<MachineId>;
-
-        // todo: temporary methods, change to devices, possibly storage ones
-        // async fn get_cd(&self, machine: MachineId) -> Option<String>;
-
-        // async fn set_cd(&self, machine: MachineId, cd: String) -> anyhow::Result<()>;
-
-        // async fn rm_cd(&self, machine: MachineId) -> anyhow::Result<()>;
-    }
-
-    // todo: properly convert return values (fromrequest and all)
-    // todo: make proc(?) macro for this, so manual mistakes arent made
-    pub fn convert<A: MachineApi>(api: A) -> VersionedRouter {
-        let router = axum::Router::new()
-            .route(
-                "/act/:mid/:action",
-                post(|Extension::<Arc<A>>(api), Path((mid, action))| async move {
-                    api.act(mid, action).await
-                }),
-            )
-            .route(
-                "/status/:mid",
-                get(|Extension::<Arc<A>>(api), Path(mid)| async move {
-                    api.status(mid).await.map(Json).ok_or(StatusCode::NOT_FOUND)
-                }),
-            )
-            // todo: reimagine
-            // .route(
-            //     "/attr/:mid/io/:attr",
-            //     get(|Extension::<Arc<A>>(api), Path((mid, attr))| async move {
-            //         Json(api.get_attr(mid, attr).await)
-            //     })
-            //     .put(
-            //         |Extension::<Arc<A>>(api), Path((mid, attr)), Json(value)| async move {
-            //             api.set_attr(mid, attr, value).await;
-            //         },
-            //     )
-            //     .delete(
-            //         |Extension::<Arc<A>>(api), Path((mid, attr))| async move {
-            //             api.delete_attr(mid, attr).await;
-            //         },
-            //     ),
-            // )
-            // .route(
-            //     "/attr/:mid/io",
-            //     patch(
-            //         |Extension::<Arc<A>>(api), Path(mid), Json(attrs)| async move {
-            //             api.patch_attrs(mid, attrs).await;
-            //         },
-            //     ),
-            // )
-            // .route(
-            //     "/attr/:mid/ls",
-            //     get(|Extension::<Arc<A>>(api), Path(mid)| async move {
-            //         Json(api.list_attrs(mid).await)
-            //     }),
-            // )
-            .route(
-                "/dev/:mid",
-                get(|Extension::<Arc<A>>(api), Path(mid)| async move {
-                    api.dev_list(mid)
-                        .await
-                        .map(Json)
-                        .ok_or(StatusCode::NOT_FOUND)
-                }),
-            )
-            .route(
-                "/dev/:mid/plug/:did",
-                put(|Extension::<Arc<A>>(api), Path((mid, did))| async move {
-                    api.dev_attach(mid, did).await.map_err(|e| {
-                        (
-                            axum::http::StatusCode::CONFLICT,
-                            format!("failed to attach device: {}", e),
-                        )
-                    })
-                })
-                .delete(|Extension::<Arc<A>>(api), Path((mid, did))| async move {
-                    api.dev_detach(mid, did).await.map_err(|e| {
-                        (
-                            axum::http::StatusCode::CONFLICT,
-                            format!("failed to detach block device: {}", e),
-                        )
-                    })
-                }),
-            )
-            .route(
-                "/m",
-                get(|Extension::<Arc<A>>(api)| async move { Json(api.list().await) })
-                    .post(|Extension::<Arc<A>>(api)| async move { Json(api.create().await) }),
-            )
-            .route(
-                "/m/:mid",
-                delete(|Extension::<Arc<A>>(api), Path(mid)| async move {
-                    api.destroy(mid).await.map_err(|e| {
-                        (
-                            axum::http::StatusCode::CONFLICT,
-                            format!("failed to destroy device: {}", e),
-                        )
-                    })
-                }),
-            )
-            // todo: refactor
-            // .route(
-            //     "/temp/:mid/cd",
-            //     get(|Extension::<Arc<A>>(api), Path(mid)| async move {
-            //         api.get_cd(mid).await.map(Json).ok_or(StatusCode::NOT_FOUND)
-            //     })
-            //     .post(|Extension::<Arc<A>>(api), Path(mid), Json(cd)| async move {
-            //         api.set_cd(mid, cd).await.map_err(|e| {
-            //             (
-            //                 axum::http::StatusCode::CONFLICT,
-            //                 format!("failed to set cdrom: {}", e),
-            //             )
-            //         })
-            //     })
-            //     .delete(|Extension::<Arc<A>>(api), Path(mid)| async move {
-            //         api.rm_cd(mid).await.map_err(|e| {
-            //             (
-            //                 axum::http::StatusCode::CONFLICT,
-            //                 format!("failed to set cdrom: {}", e),
-            //             )
-            //         })
-            //     }),
-            // )
-            .layer(AddExtensionLayer::new(Arc::new(api)));
-
-        VersionedRouter::new(router, "is.compute.machine", 0, 1)
-    }
-
-    #[derive(Debug, Deserialize)]
+        // The ETag of a machine covers its attributes and attached devices,
+        // writes to either fail with `precondition_failed` if `If-Match` doesn't match.
+
+        /// Fails with `invalid_state` if the action isn't allowed from the current state.
+        ///
+        /// The operation succeeds once the machine reaches [`MachineAction::target`].
+        #[route(post, "/act/:mid/:action", error = "failed to act on machine")]
+        async fn act(&self, machine: MachineId, action: MachineAction) -> Result<OperationId>;
+
+        #[route(get, "/status/:mid", error = "failed to get machine status")]
+        async fn status(&self, machine: MachineId) -> Result<Option<MachineStatus>>;
+
+        #[route(get, "/attr/:mid/io/:attr", error = "failed to get attribute")]
+        async fn get_attr(&self, machine: MachineId, attr: String) -> Result<Tagged<AttrValue>>;
+
+        #[route(put, "/attr/:mid/io/:attr", error = "failed to set attribute")]
+        async fn set_attr(
+            &self,
+            machine: MachineId,
+            attr: String,
+            if_match: IfMatch,
+            value: AttrValue,
+        ) -> Result<()>;
+
+        #[route(delete, "/attr/:mid/io/:attr", error = "failed to delete attribute")]
+        async fn delete_attr(
+            &self,
+            machine: MachineId,
+            attr: String,
+            if_match: IfMatch,
+        ) -> Result<()>;
+
+        /// Returns all attributes of a machine.
+        #[route(get, "/attr/:mid/io", error = "failed to get attributes")]
+        async fn get_attrs(
+            &self,
+            machine: MachineId,
+        ) -> Result<Tagged<BTreeMap<String, AttrValue>>>;
+
+        /// Sets every given attribute, `null` deletes it.
+        #[route(patch, "/attr/:mid/io", error = "failed to patch attributes")]
+        async fn patch_attrs(
+            &self,
+            machine: MachineId,
+            if_match: IfMatch,
+            attrs: HashMap<String, Option<AttrValue>>,
+        ) -> Result<()>;
+
+        #[route(get, "/attr/:mid/ls", error = "failed to list attributes")]
+        async fn list_attrs(&self, machine: MachineId) -> Result<Vec<String>>;
+
+        #[route(get, "/dev/:mid", error = "failed to list devices")]
+        async fn dev_list(
+            &self,
+            machine: MachineId,
+        ) -> Result<Option<Tagged<HashMap<DeviceId, String>>>>;
+
+        #[route(put, "/dev/:mid/plug/:did", error = "failed to attach device")]
+        async fn dev_attach(
+            &self,
+            machine: MachineId,
+            device: DeviceId,
+            if_match: IfMatch,
+        ) -> Result<()>;
+
+        #[route(delete, "/dev/:mid/plug/:did", error = "failed to detach device")]
+        async fn dev_detach(
+            &self,
+            machine: MachineId,
+            device: DeviceId,
+            if_match: IfMatch,
+        ) -> Result<()>;
+
+        /// Without a body, a machine of the default [`MachineSpec`] is created.
+        #[route(post, "/m", default_body, error = "failed to create machine")]
+        async fn create(&self, spec: MachineSpec) -> Result<MachineId>;
+
+        #[route(delete, "/m/:mid", error = "failed to destroy machine")]
+        async fn destroy(&self, machine: MachineId, if_match: IfMatch) -> Result<()>;
+
+        #[route(get, "/m", error = "failed to list machines")]
+        async fn list(&self) -> Result<Vec<MachineId>>;
+
+        /// Domains of the backend that aren't machines (yet).
+        #[route(get, "/adopt", error = "failed to list adoptable domains")]
+        async fn adoptable(&self) -> Result<Vec<MachineId>>;
+
+        /// Turns an existing domain into a machine, registering what it has as devices.
+        ///
+        /// The domain has to be off. Returns the devices like `GET /dev/:mid`, anything
+        /// that can't be represented as a device is left in the domain as it is.
+        #[route(post, "/adopt/:mid", error = "failed to adopt machine")]
+        async fn adopt(
+            &self,
+            machine: MachineId,
+            adopt: Adopt,
+        ) -> Result<HashMap<DeviceId, String>>;
+
+        /// Streams every [`MachineEvent`] from the moment of the request on.
+        #[route(get, "/events", sse, error = "failed to subscribe to events")]
+        async fn events(&self) -> Result<EventStream<MachineEvent>>;
+    }
+
+    /// Free-form machine attribute value, maps directly onto JSON (sans `null`).
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(untagged)]
+    pub enum AttrValue {
+        Bool(bool),
+        Int(i64),
+        Float(f64),
+        String(String),
+        List(Vec<AttrValue>),
+        Map(BTreeMap<String, AttrValue>),
+    }
+
+    pub const MAX_ATTR_KEY_LEN: usize = 128;
+
+    pub fn validate_attr_key(key: &str) -> Result<()> {
+        if key.is_empty() || key.len() > MAX_ATTR_KEY_LEN {
+            return Err(Error::invalid_argument(format!(
+                "attribute keys have to be between 1 and {} characters",
+                MAX_ATTR_KEY_LEN
+            )));
+        }
+
+        if key.contains('/') {
+            return Err(Error::invalid_argument(
+                "attribute keys cannot contain slashes",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Request body of `POST /m`, every field is optional, and so is the body.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct MachineSpec {
+        #[serde(default = "MachineSpec::default_cores")]
+        pub cores: u64,
+        #[serde(default = "MachineSpec::default_memory")]
+        pub memory_bytes: u64,
+
+        /// Defaults to the machine ID.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub name: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub title: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub firmware: Option<Firmware>,
+
+        /// Devices to attach right after creation.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub devices: Vec<DeviceId>,
+    }
+
+    impl MachineSpec {
+        pub const MAX_NAME_LEN: usize = 64;
+
+        fn default_cores() -> u64 {
+            1
+        }
+
+        fn default_memory() -> u64 {
+            128000000 // 128 MB
+        }
+
+        pub fn validate(&self) -> Result<()> {
+            if self.cores == 0 {
+                return Err(Error::invalid_argument("cores have to be above 0"));
+            }
+
+            if self.memory_bytes == 0 {
+                return Err(Error::invalid_argument("memory bytes have to be above 0"));
+            }
+
+            if let Some(name) = &self.name {
+                if name.is_empty() || name.len() > Self::MAX_NAME_LEN {
+                    return Err(Error::invalid_argument(format!(
+                        "name has to be between 1 and {} characters",
+                        Self::MAX_NAME_LEN
+                    )));
+                }
+
+                if !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                {
+                    return Err(Error::invalid_argument(
+                        "name can only contain ascii alphanumerics, '-', '_' and '.'",
+                    ));
+                }
+            }
+
+            for (i, device) in self.devices.iter().enumerate() {
+                if self.devices[..i].contains(device) {
+                    return Err(Error::invalid_argument(format!(
+                        "device {} is listed twice",
+                        device
+                    )));
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    impl Default for MachineSpec {
+        fn default() -> Self {
+            Self {
+                cores: Self::default_cores(),
+                memory_bytes: Self::default_memory(),
+                name: None,
+                title: None,
+                firmware: None,
+                devices: vec![],
+            }
+        }
+    }
+
+    /// Request body of `POST /adopt/:mid`.
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    pub struct Adopt {
+        #[serde(default)]
+        pub external_disks: ExternalDisks,
+    }
+
+    /// What to do with disk images outside of the backend's storage.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ExternalDisks {
+        /// Fail the adoption.
+        #[default]
+        Reject,
+        /// Register them where they are, deleting the device leaves the image alone.
+        Reference,
+    }
+
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Firmware {
+        Bios,
+        Efi,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
     #[serde(rename_all = "snake_case")]
     pub enum MachineAction {
         ForceShutdown,
         ForceReset,
         Shutdown,
+        /// Graceful reboot, asks the guest to restart.
+        Reboot,
         Suspend,
         Resume,
         Boot,
     }
 
-    #[derive(Debug, Serialize)]
+    impl MachineAction {
+        /// States this action can be taken from.
+        pub fn allowed_from(&self) -> &'static [MachineState] {
+            use MachineState::*;
+
+            match self {
+                MachineAction::ForceShutdown => &[
+                    Running,
+                    Suspended,
+                    Starting,
+                    ShuttingDown,
+                    PmSuspended,
+                    Blocked,
+                    Crashed,
+                ],
+                MachineAction::ForceReset => &[Running, ShuttingDown, Blocked],
+                MachineAction::Shutdown | MachineAction::Reboot => &[Running, Blocked],
+                MachineAction::Suspend => &[Running, Blocked],
+                MachineAction::Resume => &[Suspended],
+                MachineAction::Boot => &[Off],
+            }
+        }
+
+        /// The state the machine ends up in, `None` if it's indistinguishable from the start
+        /// (a rebooted machine is running before and after).
+        pub fn target(&self) -> Option<MachineState> {
+            match self {
+                MachineAction::ForceShutdown | MachineAction::Shutdown => Some(MachineState::Off),
+                MachineAction::Suspend => Some(MachineState::Suspended),
+                MachineAction::Resume | MachineAction::Boot => Some(MachineState::Running),
+                MachineAction::ForceReset | MachineAction::Reboot => None,
+            }
+        }
+
+        pub fn check(&self, state: MachineState) -> Result<()> {
+            if self.allowed_from().contains(&state) {
+                Ok(())
+            } else {
+                Err(Error::invalid_state(format!(
+                    "cannot {} a machine that is {}",
+                    snake_name(self),
+                    snake_name(state),
+                )))
+            }
+        }
+    }
+
+    /// The serialized name of a unit variant, e.g. `force_shutdown`.
+    fn snake_name<T: Serialize>(v: T) -> String {
+        match serde_json::to_value(v) {
+            Ok(serde_json::Value::String(s)) => s,
+            _ => unreachable!("unit variants serialize to strings"),
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
     #[serde(rename_all = "snake_case")]
     pub enum MachineState {
         Running,
         Suspended,
         Off,
+        /// Booting, not running yet.
+        Starting,
+        /// Shutting down, not off yet.
+        ShuttingDown,
+        /// Suspended by the guest itself (e.g. ACPI S3).
+        PmSuspended,
+        /// Running, but blocked on a resource.
+        Blocked,
+        Crashed,
+        /// The component can't tell what state the machine is in.
         Error,
+        /// The machine is known, but its backend is gone (e.g. removed out-of-band),
+        /// it can only be destroyed.
+        Lost,
+    }
+
+    /// State of a machine, with the reason it got there (if the backend knows it).
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct MachineStatus {
+        pub state: MachineState,
+        /// Free-form, e.g. `"booted"`, `"destroyed"`, `"ioerror"`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub reason: Option<String>,
+    }
+
+    /// Lifecycle event of a machine, as sent by `GET /events`.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(tag = "event", rename_all = "snake_case")]
+    pub enum MachineEvent {
+        Created {
+            machine: MachineId,
+        },
+        Destroyed {
+            machine: MachineId,
+        },
+        StateChanged {
+            machine: MachineId,
+            state: MachineState,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            reason: Option<String>,
+        },
+        DeviceAttached {
+            machine: MachineId,
+            device: DeviceId,
+        },
+        DeviceDetached {
+            machine: MachineId,
+            device: DeviceId,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::v1::{MachineAction, MachineSpec, MachineState};
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn machine_spec_defaults() {
+        let spec: MachineSpec = serde_json::from_str("{}").unwrap();
+
+        assert_eq!(spec.cores, 1);
+        assert_eq!(spec.memory_bytes, 128000000);
+        assert!(spec.validate().is_ok());
+
+        let spec: MachineSpec = serde_json::from_str(r#"{"cores": 0}"#).unwrap();
+        assert!(spec.validate().is_err());
+
+        let spec: MachineSpec = serde_json::from_str(r#"{"name": "no spaces"}"#).unwrap();
+        assert!(spec.validate().is_err());
+    }
+
+    #[test]
+    fn machine_action_transitions() {
+        assert!(MachineAction::Boot.check(MachineState::Off).is_ok());
+        assert!(MachineAction::Resume.check(MachineState::Suspended).is_ok());
+
+        let err = MachineAction::Resume
+            .check(MachineState::Running)
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidState);
+        assert_eq!(err.message, "cannot resume a machine that is running");
     }
 }
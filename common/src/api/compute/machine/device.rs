@@ -1,72 +1,35 @@
 pub mod v1 {
-    use std::sync::Arc;
-
-    use crate::{api::ApiBase, id::DeviceId, router::VersionedRouter};
-    use async_trait::async_trait;
-    use axum::{
-        extract::{Extension, Path},
-        http::StatusCode,
-        routing::get,
-        AddExtensionLayer, Json,
+    use crate::{
+        api::ApiBase,
+        error::Result,
+        etag::{IfMatch, Tagged},
+        id::DeviceId,
     };
+    use async_trait::async_trait;
+    use istruct_macros::api;
     use serde::{Deserialize, Serialize};
 
     // note: VCPU and Memory devices have same lifetime as machine
 
+    #[api(prefix = "is.compute.machine.device", major = 0, minor = 1)]
     #[async_trait]
     pub trait MachineDevApi: ApiBase {
-        async fn get_memory(&self, device: DeviceId) -> Option<MemoryDevice>;
-
-        async fn set_memory(&self, device: DeviceId, memory: MemoryDevice) -> anyhow::Result<()>;
-
-        async fn get_cpu(&self, device: DeviceId) -> Option<CpuDevice>;
-
-        async fn set_cpu(&self, device: DeviceId, cpu: CpuDevice) -> anyhow::Result<()>;
-    }
-
-    pub fn convert<A: MachineDevApi>(api: A) -> VersionedRouter {
-        let router = axum::Router::new()
-            .route(
-                "/mem/:did",
-                get(|Extension::<Arc<A>>(api), Path(did)| async move {
-                    api.get_memory(did)
-                        .await
-                        .map(Json)
-                        .ok_or(StatusCode::NOT_FOUND)
-                })
-                .patch(
-                    |Extension::<Arc<A>>(api), Path(did), Json(device)| async move {
-                        api.set_memory(did, device).await.map_err(|e| {
-                            (
-                                axum::http::StatusCode::CONFLICT,
-                                format!("failed to set memory: {}", e),
-                            )
-                        })
-                    },
-                ),
-            )
-            .route(
-                "/cpu/:did",
-                get(|Extension::<Arc<A>>(api), Path(did)| async move {
-                    api.get_cpu(did)
-                        .await
-                        .map(Json)
-                        .ok_or(StatusCode::NOT_FOUND)
-                })
-                .patch(
-                    |Extension::<Arc<A>>(api), Path(did), Json(device)| async move {
-                        api.set_cpu(did, device).await.map_err(|e| {
-                            (
-                                axum::http::StatusCode::CONFLICT,
-                                format!("failed to set cpu: {}", e),
-                            )
-                        })
-                    },
-                ),
-            )
-            .layer(AddExtensionLayer::new(Arc::new(api)));
-
-        return VersionedRouter::new(router, "is.compute.machine.device", 0, 1);
+        #[route(get, "/mem/:did", error = "failed to get memory")]
+        async fn get_memory(&self, device: DeviceId) -> Result<Option<Tagged<MemoryDevice>>>;
+
+        #[route(patch, "/mem/:did", error = "failed to set memory")]
+        async fn set_memory(
+            &self,
+            device: DeviceId,
+            if_match: IfMatch,
+            memory: MemoryDevice,
+        ) -> Result<()>;
+
+        #[route(get, "/cpu/:did", error = "failed to get cpu")]
+        async fn get_cpu(&self, device: DeviceId) -> Result<Option<Tagged<CpuDevice>>>;
+
+        #[route(patch, "/cpu/:did", error = "failed to set cpu")]
+        async fn set_cpu(&self, device: DeviceId, if_match: IfMatch, cpu: CpuDevice) -> Result<()>;
     }
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -0,0 +1,93 @@
+pub mod v1 {
+    use async_trait::async_trait;
+    use istruct_macros::api;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        api::ApiBase,
+        error::{Error, Result},
+        id::OperationId,
+    };
+
+    /// Long-running operations, started by calls that return an [`OperationId`] or [`Pending`].
+    #[api(prefix = "is.operation", major = 0, minor = 1)]
+    #[async_trait]
+    pub trait OperationApi: ApiBase {
+        #[route(get, "/op", error = "failed to list operations")]
+        async fn list_ops(&self) -> Result<Vec<OperationId>>;
+
+        #[route(get, "/op/:oid", error = "failed to get operation")]
+        async fn get_op(&self, op: OperationId) -> Result<Option<Operation>>;
+
+        /// Waits until the operation is done or the timeout passes, returns it either way.
+        #[route(post, "/op/:oid/wait", error = "failed to wait for operation")]
+        async fn wait_op(&self, op: OperationId, wait: Wait) -> Result<Operation>;
+
+        /// Requests cancellation, the operation is `cancelled` once it has stopped.
+        #[route(delete, "/op/:oid", error = "failed to cancel operation")]
+        async fn cancel_op(&self, op: OperationId) -> Result<()>;
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct Operation {
+        pub id: OperationId,
+        /// What started the operation, e.g. `create_block`.
+        pub kind: String,
+        pub state: OperationState,
+        /// Free-form description of what the operation is doing right now.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub progress: Option<String>,
+        /// Set once `succeeded`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub result: Option<serde_json::Value>,
+        /// Set once `failed`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub error: Option<Error>,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum OperationState {
+        Running,
+        Succeeded,
+        Failed,
+        Cancelled,
+    }
+
+    impl OperationState {
+        pub fn is_done(&self) -> bool {
+            !matches!(self, OperationState::Running)
+        }
+    }
+
+    /// Request body of `POST /op/:oid/wait`.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Wait {
+        #[serde(default = "Wait::default_timeout")]
+        pub timeout_ms: u64,
+    }
+
+    impl Wait {
+        pub const MAX_TIMEOUT_MS: u64 = 60_000;
+
+        fn default_timeout() -> u64 {
+            10_000
+        }
+    }
+
+    impl Default for Wait {
+        fn default() -> Self {
+            Self {
+                timeout_ms: Self::default_timeout(),
+            }
+        }
+    }
+
+    /// Returned by calls that continue in the background,
+    /// `target` is what the operation works on, and is usable once it succeeds.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Pending<T> {
+        pub operation: OperationId,
+        pub target: T,
+    }
+}
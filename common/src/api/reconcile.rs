@@ -0,0 +1,71 @@
+pub mod v1 {
+    use async_trait::async_trait;
+    use istruct_macros::api;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        api::ApiBase,
+        error::Result,
+        id::{DeviceId, MachineId},
+    };
+
+    /// Drift between what a component has on record and what its backend actually has.
+    #[api(prefix = "is.reconcile", major = 0, minor = 1)]
+    #[async_trait]
+    pub trait ReconcileApi: ApiBase {
+        /// The report of the last pass, periodic or requested.
+        #[route(get, "/drift", error = "failed to get drift")]
+        async fn drift(&self) -> Result<Option<Report>>;
+
+        /// Runs a pass right away.
+        #[route(post, "/run", error = "failed to reconcile")]
+        async fn reconcile(&self, run: Reconcile) -> Result<Report>;
+    }
+
+    /// Request body of `POST /run`.
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    pub struct Reconcile {
+        /// Repair what can be repaired, instead of only reporting it.
+        #[serde(default)]
+        pub repair: bool,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct Report {
+        /// Unix timestamp (seconds) of when the pass finished.
+        pub finished_at: u64,
+        pub findings: Vec<Finding>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Finding {
+        #[serde(flatten)]
+        pub drift: Drift,
+        pub repaired: bool,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(tag = "drift", rename_all = "snake_case")]
+    pub enum Drift {
+        /// The machine is known, but the backend doesn't have it anymore.
+        ///
+        /// Repaired by marking it `lost`, which later passes don't report again.
+        MachineLost { machine: MachineId },
+        /// The machine has no record of a device it always has (cpu or memory).
+        ///
+        /// Repaired by re-creating the record from the backend.
+        DeviceMissing {
+            machine: MachineId,
+            device_type: String,
+        },
+        /// The device is on record as attached, but the machine doesn't have it.
+        ///
+        /// Repaired by removing the attachment.
+        AttachmentDangling {
+            machine: MachineId,
+            device: DeviceId,
+        },
+        /// The storage backing a device is gone, this can't be repaired.
+        StorageMissing { device: DeviceId },
+    }
+}
@@ -1,58 +1,61 @@
 pub mod v1 {
-    use std::sync::Arc;
-
     use async_trait::async_trait;
-    use axum::{
-        extract::{Extension, Path},
-        http::StatusCode,
-        routing::{get, post},
-        AddExtensionLayer, Json,
-    };
+    use istruct_macros::api;
     use serde::{Deserialize, Serialize};
 
-    use crate::{api::ApiBase, id::DeviceId, router::VersionedRouter};
+    use crate::{
+        api::{operation::v1::Pending, ApiBase},
+        error::Result,
+        etag::{IfMatch, Tagged},
+        id::DeviceId,
+    };
 
+    #[api(prefix = "is.storage.device", major = 0, minor = 1)]
     #[async_trait]
     pub trait StorageDevApi: ApiBase {
-        async fn get_block(&self, device: DeviceId) -> Option<BlockDevice>;
-
-        async fn create_block(&self, block: BlockDevice) -> DeviceId;
-
-        async fn delete_block(&self, device: DeviceId) -> anyhow::Result<()>;
-    }
-
-    pub fn convert<A: StorageDevApi>(api: A) -> VersionedRouter {
-        let router = axum::Router::new()
-            .route(
-                "/block",
-                post(|Extension::<Arc<A>>(api), Json(block)| async move {
-                    Json(api.create_block(block).await)
-                }),
-            )
-            .route(
-                "/block/:did",
-                get(|Extension::<Arc<A>>(api), Path(did)| async move {
-                    api.get_block(did)
-                        .await
-                        .map(Json)
-                        .ok_or(StatusCode::NOT_FOUND)
-                })
-                .delete(|Extension::<Arc<A>>(api), Path(did)| async move {
-                    api.delete_block(did).await.map_err(|e| {
-                        (
-                            StatusCode::CONFLICT,
-                            format!("failed to delete block device: {}", e),
-                        )
-                    })
-                }),
-            )
-            .layer(AddExtensionLayer::new(Arc::new(api)));
-
-        VersionedRouter::new(router, "is.storage.device", 0, 1)
+        #[route(get, "/block/:did", error = "failed to get block device")]
+        async fn get_block(&self, device: DeviceId) -> Result<Option<Tagged<BlockDevice>>>;
+
+        /// The device exists once the operation succeeds.
+        #[route(post, "/block", error = "failed to create block device")]
+        async fn create_block(&self, block: BlockDevice) -> Result<Pending<DeviceId>>;
+
+        #[route(delete, "/block/:did", error = "failed to delete block device")]
+        async fn delete_block(&self, device: DeviceId, if_match: IfMatch) -> Result<()>;
+
+        #[route(get, "/cdrom/:did", error = "failed to get cdrom device")]
+        async fn get_cdrom(&self, device: DeviceId) -> Result<Option<Tagged<CdromDevice>>>;
+
+        #[route(post, "/cdrom", error = "failed to create cdrom device")]
+        async fn create_cdrom(&self, cdrom: CdromDevice) -> Result<DeviceId>;
+
+        #[route(delete, "/cdrom/:did", error = "failed to delete cdrom device")]
+        async fn delete_cdrom(&self, device: DeviceId, if_match: IfMatch) -> Result<()>;
+
+        /// Inserts media, replacing any that is already inserted.
+        ///
+        /// Takes effect immediately when the cdrom is attached to a running machine.
+        #[route(put, "/cdrom/:did/media", error = "failed to insert media")]
+        async fn insert_media(
+            &self,
+            device: DeviceId,
+            if_match: IfMatch,
+            media: String,
+        ) -> Result<()>;
+
+        #[route(delete, "/cdrom/:did/media", error = "failed to eject media")]
+        async fn eject_media(&self, device: DeviceId, if_match: IfMatch) -> Result<()>;
     }
 
     #[derive(Debug, Serialize, Deserialize)]
     pub struct BlockDevice {
         pub bytes: u64,
     }
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct CdromDevice {
+        /// Path of the inserted image (e.g. an ISO) on the host, if any.
+        #[serde(default)]
+        pub media: Option<String>,
+    }
 }
@@ -0,0 +1,625 @@
+//! Checks an implementation of the API traits against their contract.
+//!
+//! Works on anything implementing the traits, so a component in-process as well as
+//! `istruct_client::Remote` for one behind a URL. Checks create and destroy machines of their
+//! own and clean up after themselves, but don't point them at a component you care about.
+
+use std::{collections::HashMap, fmt};
+
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        compute::{
+            devadm::v1::DevAdmApi,
+            machine::{
+                device::v1::{CpuDevice, MachineDevApi, MemoryDevice},
+                v1::{MachineAction, MachineApi, MachineSpec, MachineState},
+            },
+        },
+        network::device::v1::NetworkDevApi,
+        operation::v1::{OperationApi, OperationState, Wait},
+        storage::device::v1::{BlockDevice, StorageDevApi},
+    },
+    error::{ErrorKind, Result},
+    etag::IfMatch,
+    id::{DeviceId, MachineId, OperationId},
+};
+
+const MIB: u64 = 1024 * 1024;
+
+/// The APIs a component has to serve to be checked.
+pub trait Component:
+    MachineApi + MachineDevApi + DevAdmApi + StorageDevApi + NetworkDevApi
+{
+}
+
+impl<T> Component for T where
+    T: MachineApi + MachineDevApi + DevAdmApi + StorageDevApi + NetworkDevApi
+{
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Checks without violations.
+    pub passed: Vec<&'static str>,
+    pub violations: Vec<Violation>,
+}
+
+/// The first thing a check found wrong, the rest of the check is skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub check: &'static str,
+    pub message: String,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    fn record(&mut self, check: &'static str, outcome: Outcome) {
+        match outcome {
+            Ok(()) => self.passed.push(check),
+            Err(message) => self.violations.push(Violation { check, message }),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.passed {
+            writeln!(f, "ok   {}", check)?;
+        }
+
+        for violation in &self.violations {
+            writeln!(f, "FAIL {}: {}", violation.check, violation.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+type Outcome<T = ()> = std::result::Result<T, String>;
+
+/// Runs every check against `component`, waiting for its operations through `ops`.
+pub async fn run<C: Component, O: OperationApi>(component: &C, ops: &O) -> Report {
+    let mut report = Report::default();
+
+    macro_rules! check {
+        ($check:ident) => {{
+            let mut scratch = Scratch::default();
+            let outcome = $check(component, ops, &mut scratch).await;
+
+            scratch.clean(component, ops).await;
+            report.record(stringify!($check), outcome);
+        }};
+    }
+
+    check!(machine_lifecycle);
+    check!(attach_detach);
+    check!(compute_updates);
+    check!(block_lifecycle);
+    check!(nat_lifecycle);
+
+    report
+}
+
+async fn machine_lifecycle<C: Component, O: OperationApi>(
+    c: &C,
+    ops: &O,
+    scratch: &mut Scratch,
+) -> Outcome {
+    let machine = scratch
+        .machine(
+            c,
+            MachineSpec {
+                cores: 2,
+                memory_bytes: 256 * MIB,
+                ..MachineSpec::default()
+            },
+        )
+        .await?;
+
+    ensure(
+        ok("listing machines", c.list().await)?.contains(&machine),
+        "created machine is not listed",
+    )?;
+    expect_state(c, machine, MachineState::Off).await?;
+
+    let devices = compute_devices(c, machine).await?;
+    let all = ok("listing devices", c.all().await)?;
+
+    for (device, typ) in &devices {
+        ensure(
+            all.get(device) == Some(typ),
+            format!("device {} of the machine is not listed as {}", device, typ),
+        )?;
+    }
+
+    act(c, ops, machine, MachineAction::Boot).await?;
+    expect_state(c, machine, MachineState::Running).await?;
+
+    fails_with(
+        "destroying a running machine",
+        c.destroy(machine, IfMatch::None).await,
+        ErrorKind::InvalidState,
+    )?;
+
+    act(c, ops, machine, MachineAction::ForceShutdown).await?;
+    expect_state(c, machine, MachineState::Off).await?;
+
+    ok(
+        "destroying the machine",
+        c.destroy(machine, IfMatch::None).await,
+    )?;
+
+    ensure(
+        !ok("listing machines", c.list().await)?.contains(&machine),
+        "destroyed machine is still listed",
+    )?;
+    ensure(
+        ok("getting the status", c.status(machine).await)?.is_none(),
+        "destroyed machine still has a status",
+    )?;
+
+    for device in devices.keys() {
+        ensure(
+            ok("getting the device type", c.get_type(*device).await)?.is_none(),
+            format!("compute device {} outlived its machine", device),
+        )?;
+    }
+
+    fails_with(
+        "destroying the machine again",
+        c.destroy(machine, IfMatch::None).await,
+        ErrorKind::NotFound,
+    )
+}
+
+async fn attach_detach<C: Component, O: OperationApi>(
+    c: &C,
+    _: &O,
+    scratch: &mut Scratch,
+) -> Outcome {
+    let nat = scratch.nat(c).await?;
+    let a = scratch.machine(c, MachineSpec::default()).await?;
+    let b = scratch.machine(c, MachineSpec::default()).await?;
+
+    fails_with(
+        "attaching an unknown device",
+        c.dev_attach(a, Uuid::new_v4(), IfMatch::None).await,
+        ErrorKind::NotFound,
+    )?;
+    fails_with(
+        "attaching to an unknown machine",
+        c.dev_attach(Uuid::new_v4(), nat, IfMatch::None).await,
+        ErrorKind::NotFound,
+    )?;
+
+    ok(
+        "attaching a nat interface",
+        c.dev_attach(a, nat, IfMatch::None).await,
+    )?;
+
+    let listed =
+        ok("listing devices", c.dev_list(a).await)?.and_then(|l| l.value.get(&nat).cloned());
+
+    ensure(
+        listed.as_deref() == Some("is.network.nat"),
+        format!("attached nat interface is listed as {:?}", listed),
+    )?;
+
+    fails_with(
+        "attaching it to the same machine again",
+        c.dev_attach(a, nat, IfMatch::None).await,
+        ErrorKind::AlreadyAttached,
+    )?;
+    fails_with(
+        "attaching it to another machine",
+        c.dev_attach(b, nat, IfMatch::None).await,
+        ErrorKind::AlreadyAttached,
+    )?;
+    fails_with(
+        "detaching it from another machine",
+        c.dev_detach(b, nat, IfMatch::None).await,
+        ErrorKind::InvalidState,
+    )?;
+    fails_with(
+        "deleting it while attached",
+        c.delete_nat(nat).await,
+        ErrorKind::AlreadyAttached,
+    )?;
+
+    let cpu = compute_device(c, a, "is.compute.cpu").await?;
+
+    fails_with(
+        "detaching a cpu",
+        c.dev_detach(a, cpu, IfMatch::None).await,
+        ErrorKind::InvalidArgument,
+    )?;
+    fails_with(
+        "attaching a cpu to another machine",
+        c.dev_attach(b, cpu, IfMatch::None).await,
+        ErrorKind::InvalidArgument,
+    )?;
+
+    ok(
+        "detaching the nat interface",
+        c.dev_detach(a, nat, IfMatch::None).await,
+    )?;
+
+    fails_with(
+        "detaching it again",
+        c.dev_detach(a, nat, IfMatch::None).await,
+        ErrorKind::InvalidState,
+    )?;
+
+    ok(
+        "attaching it to another machine once detached",
+        c.dev_attach(b, nat, IfMatch::None).await,
+    )
+}
+
+async fn compute_updates<C: Component, O: OperationApi>(
+    c: &C,
+    _: &O,
+    scratch: &mut Scratch,
+) -> Outcome {
+    let machine = scratch
+        .machine(
+            c,
+            MachineSpec {
+                cores: 1,
+                memory_bytes: 256 * MIB,
+                ..MachineSpec::default()
+            },
+        )
+        .await?;
+
+    let cpu = compute_device(c, machine, "is.compute.cpu").await?;
+    let mem = compute_device(c, machine, "is.compute.mem").await?;
+
+    let before = ok("getting the cpu", c.get_cpu(cpu).await)?.ok_or("cpu can't be read")?;
+
+    ensure(
+        before.value.cores == 1,
+        format!("cpu has {} cores, created with 1", before.value.cores),
+    )?;
+    ensure(
+        ok("getting a cpu as memory", c.get_memory(cpu).await)?.is_none(),
+        "cpu can be read as memory",
+    )?;
+
+    ok(
+        "setting cores",
+        c.set_cpu(
+            cpu,
+            IfMatch::tag(before.etag.clone()),
+            CpuDevice { cores: 2 },
+        )
+        .await,
+    )?;
+
+    let after = ok("getting the cpu", c.get_cpu(cpu).await)?.ok_or("cpu can't be read")?;
+
+    ensure(
+        after.value.cores == 2,
+        format!("cpu has {} cores, set to 2", after.value.cores),
+    )?;
+    ensure(
+        after.etag != before.etag,
+        "setting cores didn't change the cpu's etag",
+    )?;
+
+    fails_with(
+        "setting cores with a stale etag",
+        c.set_cpu(cpu, IfMatch::tag(before.etag), CpuDevice { cores: 3 })
+            .await,
+        ErrorKind::PreconditionFailed,
+    )?;
+    fails_with(
+        "setting 0 cores",
+        c.set_cpu(cpu, IfMatch::None, CpuDevice { cores: 0 }).await,
+        ErrorKind::InvalidArgument,
+    )?;
+    fails_with(
+        "setting cores of memory",
+        c.set_cpu(mem, IfMatch::None, CpuDevice { cores: 2 }).await,
+        ErrorKind::InvalidArgument,
+    )?;
+    fails_with(
+        "setting cores of an unknown device",
+        c.set_cpu(Uuid::new_v4(), IfMatch::None, CpuDevice { cores: 2 })
+            .await,
+        ErrorKind::NotFound,
+    )?;
+
+    ok(
+        "setting memory",
+        c.set_memory(mem, IfMatch::None, MemoryDevice { bytes: 512 * MIB })
+            .await,
+    )?;
+
+    let bytes = ok("getting the memory", c.get_memory(mem).await)?
+        .ok_or("memory can't be read")?
+        .value
+        .bytes;
+
+    ensure(
+        bytes == 512 * MIB,
+        format!("memory has {} bytes, set to {}", bytes, 512 * MIB),
+    )?;
+
+    fails_with(
+        "setting 0 bytes of memory",
+        c.set_memory(mem, IfMatch::None, MemoryDevice { bytes: 0 })
+            .await,
+        ErrorKind::InvalidArgument,
+    )?;
+    fails_with(
+        "setting memory of a cpu",
+        c.set_memory(cpu, IfMatch::None, MemoryDevice { bytes: 512 * MIB })
+            .await,
+        ErrorKind::InvalidArgument,
+    )
+}
+
+async fn block_lifecycle<C: Component, O: OperationApi>(
+    c: &C,
+    ops: &O,
+    scratch: &mut Scratch,
+) -> Outcome {
+    fails_with(
+        "creating an empty block device",
+        c.create_block(BlockDevice { bytes: 0 }).await,
+        ErrorKind::InvalidArgument,
+    )?;
+
+    let pending = ok(
+        "creating a block device",
+        c.create_block(BlockDevice { bytes: MIB }).await,
+    )?;
+    let block = pending.target;
+
+    scratch.devices.push(block);
+
+    wait(ops, "creating a block device", pending.operation).await?;
+
+    let bytes = ok("getting the block device", c.get_block(block).await)?
+        .ok_or("block device can't be read")?
+        .value
+        .bytes;
+
+    ensure(
+        bytes == MIB,
+        format!("block device has {} bytes, created with {}", bytes, MIB),
+    )?;
+    expect_type(c, block, Some("is.storage.block")).await?;
+
+    fails_with(
+        "deleting it as a nat interface",
+        c.delete_nat(block).await,
+        ErrorKind::InvalidArgument,
+    )?;
+
+    let machine = scratch.machine(c, MachineSpec::default()).await?;
+
+    ok(
+        "attaching the block device",
+        c.dev_attach(machine, block, IfMatch::None).await,
+    )?;
+
+    fails_with(
+        "deleting it while attached",
+        c.delete_block(block, IfMatch::None).await,
+        ErrorKind::AlreadyAttached,
+    )?;
+
+    ok(
+        "destroying the machine it is attached to",
+        c.destroy(machine, IfMatch::None).await,
+    )?;
+
+    expect_type(c, block, Some("is.storage.block")).await?;
+
+    ok(
+        "deleting the block device",
+        c.delete_block(block, IfMatch::None).await,
+    )?;
+
+    ensure(
+        ok("getting the block device", c.get_block(block).await)?.is_none(),
+        "deleted block device can still be read",
+    )?;
+    expect_type(c, block, None).await?;
+
+    fails_with(
+        "deleting it again",
+        c.delete_block(block, IfMatch::None).await,
+        ErrorKind::NotFound,
+    )
+}
+
+async fn nat_lifecycle<C: Component, O: OperationApi>(
+    c: &C,
+    _: &O,
+    scratch: &mut Scratch,
+) -> Outcome {
+    let nat = scratch.nat(c).await?;
+
+    expect_type(c, nat, Some("is.network.nat")).await?;
+
+    fails_with(
+        "deleting it as a block device",
+        c.delete_block(nat, IfMatch::None).await,
+        ErrorKind::InvalidArgument,
+    )?;
+
+    ok("deleting the nat interface", c.delete_nat(nat).await)?;
+
+    expect_type(c, nat, None).await?;
+    ensure(
+        !ok("listing devices", c.all().await)?.contains_key(&nat),
+        "deleted nat interface is still listed",
+    )?;
+
+    fails_with(
+        "deleting it again",
+        c.delete_nat(nat).await,
+        ErrorKind::NotFound,
+    )
+}
+
+/// What a check created, removed again whether it passed or not.
+#[derive(Default)]
+struct Scratch {
+    machines: Vec<MachineId>,
+    devices: Vec<DeviceId>,
+}
+
+impl Scratch {
+    async fn machine<C: Component>(&mut self, c: &C, spec: MachineSpec) -> Outcome<MachineId> {
+        let machine = ok("creating a machine", c.create(spec).await)?;
+
+        self.machines.push(machine);
+
+        Ok(machine)
+    }
+
+    async fn nat<C: Component>(&mut self, c: &C) -> Outcome<DeviceId> {
+        let nat = ok("creating a nat interface", c.create_nat().await)?;
+
+        self.devices.push(nat);
+
+        Ok(nat)
+    }
+
+    // errors are expected for whatever the check already removed itself
+    async fn clean<C: Component, O: OperationApi>(self, c: &C, ops: &O) {
+        for machine in self.machines {
+            if let Ok(op) = c.act(machine, MachineAction::ForceShutdown).await {
+                let _ = ops.wait_op(op, Wait::default()).await;
+            }
+
+            let _ = c.destroy(machine, IfMatch::None).await;
+        }
+
+        for device in self.devices {
+            let _ = match c.get_type(device).await.ok().flatten().as_deref() {
+                Some("is.network.nat") => c.delete_nat(device).await,
+                Some("is.storage.block") => c.delete_block(device, IfMatch::None).await,
+                _ => continue,
+            };
+        }
+    }
+}
+
+fn ensure(holds: bool, violation: impl Into<String>) -> Outcome {
+    if holds {
+        Ok(())
+    } else {
+        Err(violation.into())
+    }
+}
+
+fn ok<T>(what: &str, res: Result<T>) -> Outcome<T> {
+    res.map_err(|e| format!("{} failed with {:?}: {}", what, e.kind, e))
+}
+
+fn fails_with<T: fmt::Debug>(what: &str, res: Result<T>, kind: ErrorKind) -> Outcome {
+    match res {
+        Err(e) if e.kind == kind => Ok(()),
+        Err(e) => Err(format!(
+            "{} failed with {:?}, expected {:?}: {}",
+            what, e.kind, kind, e
+        )),
+        Ok(v) => Err(format!(
+            "{} succeeded with {:?}, expected {:?}",
+            what, v, kind
+        )),
+    }
+}
+
+async fn wait<O: OperationApi>(ops: &O, what: &str, op: OperationId) -> Outcome {
+    let op = ok(
+        "waiting for an operation",
+        ops.wait_op(op, Wait::default()).await,
+    )?;
+
+    match op.state {
+        OperationState::Succeeded => Ok(()),
+        OperationState::Running => Err(format!(
+            "{} is still running after {}ms",
+            what,
+            Wait::default().timeout_ms
+        )),
+        state => Err(format!("{} ended {:?}: {:?}", what, state, op.error)),
+    }
+}
+
+async fn act<C: Component, O: OperationApi>(
+    c: &C,
+    ops: &O,
+    machine: MachineId,
+    action: MachineAction,
+) -> Outcome {
+    let what = format!("{:?}", action);
+    let op = ok(&what, c.act(machine, action).await)?;
+
+    wait(ops, &what, op).await
+}
+
+async fn expect_state<C: Component>(c: &C, machine: MachineId, state: MachineState) -> Outcome {
+    let status = ok("getting the status", c.status(machine).await)?;
+
+    ensure(
+        status.as_ref().map(|s| s.state) == Some(state),
+        format!("machine should be {:?}, status is {:?}", state, status),
+    )
+}
+
+async fn expect_type<C: Component>(c: &C, device: DeviceId, typ: Option<&str>) -> Outcome {
+    let actual = ok("getting the device type", c.get_type(device).await)?;
+
+    ensure(
+        actual.as_deref() == typ,
+        format!(
+            "device {} should have type {:?}, has {:?}",
+            device, typ, actual
+        ),
+    )
+}
+
+/// Checks a machine has exactly one cpu and one memory device, and returns them.
+async fn compute_devices<C: Component>(
+    c: &C,
+    machine: MachineId,
+) -> Outcome<HashMap<DeviceId, String>> {
+    let devices = ok("listing devices", c.dev_list(machine).await)?
+        .ok_or("machine has no device list")?
+        .value;
+
+    let mut types: Vec<_> = devices.values().map(String::as_str).collect();
+    types.sort_unstable();
+
+    ensure(
+        types == ["is.compute.cpu", "is.compute.mem"],
+        format!(
+            "machine should have a cpu and memory device, has {:?}",
+            types
+        ),
+    )?;
+
+    Ok(devices)
+}
+
+async fn compute_device<C: Component>(c: &C, machine: MachineId, typ: &str) -> Outcome<DeviceId> {
+    ok("listing devices", c.dev_list(machine).await)?
+        .ok_or("machine has no device list")?
+        .value
+        .into_iter()
+        .find(|(_, t)| t == typ)
+        .map(|(d, _)| d)
+        .ok_or_else(|| format!("machine has no {} device", typ))
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Machine-readable error kind, serialized as the `code` of an error body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The machine or device doesn't exist.
+    NotFound,
+    /// The request itself is invalid, e.g. `0` cores.
+    InvalidArgument,
+    /// The request is valid, but not in the current state, e.g. destroying a running machine.
+    InvalidState,
+    /// The device is already attached to a machine.
+    AlreadyAttached,
+    /// The resource changed since the client last read it, see `If-Match`.
+    PreconditionFailed,
+    /// Something went wrong in the component itself, or in what it's managing.
+    BackendFailure,
+}
+
+impl ErrorKind {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::InvalidArgument => StatusCode::BAD_REQUEST,
+            ErrorKind::InvalidState => StatusCode::CONFLICT,
+            ErrorKind::AlreadyAttached => StatusCode::CONFLICT,
+            ErrorKind::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorKind::BackendFailure => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Error returned by API methods, serializes to `{"code": ..., "message": ...}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Error {
+    #[serde(rename = "code")]
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new<M: Into<String>>(kind: ErrorKind, message: M) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorKind::NotFound, message)
+    }
+
+    pub fn invalid_argument<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorKind::InvalidArgument, message)
+    }
+
+    pub fn invalid_state<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorKind::InvalidState, message)
+    }
+
+    pub fn already_attached<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorKind::AlreadyAttached, message)
+    }
+
+    pub fn precondition_failed<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorKind::PreconditionFailed, message)
+    }
+
+    pub fn backend<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorKind::BackendFailure, message)
+    }
+
+    /// Prefixes the message with `context`, keeps the kind.
+    pub fn context<C: fmt::Display>(self, context: C) -> Self {
+        Self {
+            kind: self.kind,
+            message: format!("{}: {}", context, self.message),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+// anything not explicitly mapped is a failure of the backend
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Self::backend(format!("{:#}", e)),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        (self.kind.status(), Json(self)).into_response()
+    }
+}
@@ -0,0 +1,192 @@
+//! `ETag`/`If-Match` support for machine and device resources.
+//!
+//! Reads return [`Tagged`] values, which set the `ETag` header,
+//! writes take an [`IfMatch`] argument, taken from the `If-Match` header.
+//! A write whose `If-Match` doesn't match the current tag fails with `412 PRECONDITION_FAILED`.
+
+use std::fmt;
+
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequest, RequestParts},
+    http::{
+        header::{ETAG, IF_MATCH},
+        HeaderValue,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+/// Opaque version of a resource, changes whenever the resource does.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ETag(String);
+
+impl ETag {
+    pub fn new<T: fmt::Display>(tag: T) -> Self {
+        Self(tag.to_string())
+    }
+
+    /// Parses a single header value, e.g. `"5"`, weak tags are compared as if strong.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let value = value.strip_prefix("W/").unwrap_or(value);
+
+        value
+            .strip_prefix('"')?
+            .strip_suffix('"')
+            .filter(|v| !v.contains('"'))
+            .map(Self::new)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Formats as a header value, quoted.
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.0)
+    }
+}
+
+/// A value along with the tag of the resource it was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tagged<T> {
+    pub etag: ETag,
+    pub value: T,
+}
+
+impl<T> Tagged<T> {
+    pub fn new(value: T, etag: ETag) -> Self {
+        Self { etag, value }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Tagged<U> {
+        Tagged {
+            etag: self.etag,
+            value: f(self.value),
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Tagged<T> {
+    fn into_response(self) -> Response {
+        let mut res = Json(self.value).into_response();
+
+        let etag = HeaderValue::from_str(&self.etag.to_string())
+            .expect("etags are valid header values");
+        res.headers_mut().insert(ETAG, etag);
+
+        res
+    }
+}
+
+/// The `If-Match` precondition of a write.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum IfMatch {
+    /// No header, the write always goes through.
+    #[default]
+    None,
+    /// `*`, the resource has to exist.
+    Any,
+    /// The current tag has to be one of these.
+    Tags(Vec<ETag>),
+}
+
+impl IfMatch {
+    pub fn tag(tag: ETag) -> Self {
+        IfMatch::Tags(vec![tag])
+    }
+
+    pub fn check(&self, current: &ETag) -> Result<()> {
+        match self {
+            IfMatch::None | IfMatch::Any => Ok(()),
+            IfMatch::Tags(tags) if tags.contains(current) => Ok(()),
+            IfMatch::Tags(_) => Err(Error::precondition_failed(format!(
+                "resource has changed, it is now at {}",
+                current
+            ))),
+        }
+    }
+
+    /// Parses the `If-Match` header value.
+    pub fn parse(value: &str) -> Result<Self> {
+        if value.trim() == "*" {
+            return Ok(IfMatch::Any);
+        }
+
+        value
+            .split(',')
+            .map(|t| {
+                ETag::parse(t)
+                    .ok_or_else(|| Error::invalid_argument(format!("invalid entity tag {}", t)))
+            })
+            .collect::<Result<_>>()
+            .map(IfMatch::Tags)
+    }
+
+    /// The `If-Match` header value, if any.
+    pub fn header(&self) -> Option<String> {
+        match self {
+            IfMatch::None => None,
+            IfMatch::Any => Some("*".to_string()),
+            IfMatch::Tags(tags) => Some(
+                tags.iter()
+                    .map(ETag::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for IfMatch {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let value = match req.headers().and_then(|h| h.get(IF_MATCH)) {
+            Some(v) => v,
+            None => return Ok(IfMatch::None),
+        };
+
+        let value = value
+            .to_str()
+            .map_err(|_| Error::invalid_argument("If-Match is not valid ascii"))?;
+
+        IfMatch::parse(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ETag, IfMatch};
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn if_match() {
+        assert_eq!(IfMatch::parse("*").unwrap(), IfMatch::Any);
+        assert_eq!(
+            IfMatch::parse(r#""1", W/"2""#).unwrap(),
+            IfMatch::Tags(vec![ETag::new(1), ETag::new(2)])
+        );
+        assert!(IfMatch::parse("1").is_err());
+
+        let current = ETag::new(2);
+        assert!(IfMatch::parse(r#""1", "2""#)
+            .unwrap()
+            .check(&current)
+            .is_ok());
+        assert_eq!(
+            IfMatch::tag(ETag::new(1))
+                .check(&current)
+                .unwrap_err()
+                .kind,
+            ErrorKind::PreconditionFailed
+        );
+    }
+}
@@ -0,0 +1,20 @@
+use std::pin::Pin;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+
+/// Stream of events returned by `sse` routes.
+pub type EventStream<T> = Pin<Box<dyn Stream<Item = T> + Send + 'static>>;
+
+/// Turns a stream of events into a server-sent event response, one JSON `data` line per event.
+///
+/// Used by routes marked with `sse`.
+pub fn sse<T>(
+    stream: EventStream<T>,
+) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>> + Send + 'static>
+where
+    T: Serialize + 'static,
+{
+    Sse::new(stream.map(|e| Event::default().json_data(e))).keep_alive(KeepAlive::default())
+}
@@ -1,8 +1,18 @@
+// lets istruct-macros refer to `::istruct_common` from within this crate
+extern crate self as istruct_common;
+
 pub mod api;
+#[cfg(feature = "conformance")]
+pub mod conformance;
+pub mod error;
+pub mod etag;
+pub mod event;
+pub mod operation;
 pub mod router;
 pub mod id {
     pub type MachineId = uuid::Uuid;
     pub type DeviceId = uuid::Uuid;
+    pub type OperationId = uuid::Uuid;
 }
 
 #[cfg(test)]
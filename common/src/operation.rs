@@ -0,0 +1,269 @@
+//! In-memory bookkeeping of long-running operations.
+//!
+//! Components start an operation with [`Operations::start`], do the work in the background,
+//! and finish it through the returned [`OperationHandle`].
+//! [`Operations`] implements [`OperationApi`] itself, so it can be served as-is.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        operation::v1::{Operation, OperationApi, OperationState, Wait},
+        ApiBase,
+    },
+    error::{Error, Result},
+    id::OperationId,
+};
+
+/// How long finished operations can still be looked up.
+const RETAIN_FINISHED: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug)]
+struct Entry {
+    op: Operation,
+    cancelled: Arc<AtomicBool>,
+    done: watch::Receiver<bool>,
+    finished_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Operations {
+    entries: Arc<Mutex<HashMap<OperationId, Entry>>>,
+}
+
+impl Operations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new running operation.
+    pub fn start<K: Into<String>>(&self, kind: K) -> OperationHandle {
+        let id = Uuid::new_v4();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (done_tx, done_rx) = watch::channel(false);
+
+        let mut entries = self.entries.lock().unwrap();
+
+        entries
+            .retain(|_, e| !matches!(e.finished_at, Some(at) if at.elapsed() >= RETAIN_FINISHED));
+
+        entries.insert(
+            id,
+            Entry {
+                op: Operation {
+                    id,
+                    kind: kind.into(),
+                    state: OperationState::Running,
+                    progress: None,
+                    result: None,
+                    error: None,
+                },
+                cancelled: cancelled.clone(),
+                done: done_rx,
+                finished_at: None,
+            },
+        );
+
+        OperationHandle {
+            id,
+            ops: self.clone(),
+            cancelled,
+            done: done_tx,
+            finished: false,
+        }
+    }
+
+    pub fn get(&self, id: OperationId) -> Option<Operation> {
+        self.entries.lock().unwrap().get(&id).map(|e| e.op.clone())
+    }
+
+    pub fn list(&self) -> Vec<OperationId> {
+        self.entries.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Waits for the operation to finish, returns it as it is after `timeout`.
+    pub async fn wait(&self, id: OperationId, timeout: Duration) -> Option<Operation> {
+        let mut done = self.entries.lock().unwrap().get(&id)?.done.clone();
+
+        let _ = tokio::time::timeout(timeout, async {
+            while !*done.borrow() {
+                if done.changed().await.is_err() {
+                    break;
+                }
+            }
+        })
+        .await;
+
+        self.get(id)
+    }
+
+    /// Requests cancellation, it's up to the work itself to stop.
+    pub fn cancel(&self, id: OperationId) -> Result<()> {
+        let entries = self.entries.lock().unwrap();
+
+        let entry = entries
+            .get(&id)
+            .ok_or_else(|| Error::not_found("operation does not exist"))?;
+
+        if entry.op.state.is_done() {
+            return Err(Error::invalid_state("operation already finished"));
+        }
+
+        entry.cancelled.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn update(&self, id: OperationId, f: impl FnOnce(&mut Operation)) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            f(&mut entry.op);
+
+            if entry.op.state.is_done() {
+                entry.finished_at = Some(Instant::now());
+            }
+        }
+    }
+}
+
+/// Finishes an operation, an operation whose handle is dropped without finishing has failed.
+pub struct OperationHandle {
+    id: OperationId,
+    ops: Operations,
+    cancelled: Arc<AtomicBool>,
+    done: watch::Sender<bool>,
+    finished: bool,
+}
+
+impl OperationHandle {
+    pub fn id(&self) -> OperationId {
+        self.id
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn progress<P: Into<String>>(&self, progress: P) {
+        let progress = progress.into();
+        self.ops.update(self.id, |op| op.progress = Some(progress));
+    }
+
+    pub fn succeed<T: Serialize>(self, result: T) {
+        let result = serde_json::to_value(result).expect("operation result serializes");
+
+        self.finish(OperationState::Succeeded, |op| op.result = Some(result));
+    }
+
+    pub fn fail(self, error: Error) {
+        self.finish(OperationState::Failed, |op| op.error = Some(error));
+    }
+
+    /// Finishes as cancelled, after the work has stopped.
+    pub fn cancelled(self) {
+        self.finish(OperationState::Cancelled, |_| {});
+    }
+
+    pub fn complete<T: Serialize>(self, result: Result<T>) {
+        match result {
+            Ok(r) => self.succeed(r),
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn finish(mut self, state: OperationState, f: impl FnOnce(&mut Operation)) {
+        self.close(state, f);
+    }
+
+    fn close(&mut self, state: OperationState, f: impl FnOnce(&mut Operation)) {
+        self.finished = true;
+
+        self.ops.update(self.id, |op| {
+            op.state = state;
+            op.progress = None;
+            f(op);
+        });
+
+        let _ = self.done.send(true);
+    }
+}
+
+impl Drop for OperationHandle {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.close(OperationState::Failed, |op| {
+                op.error = Some(Error::backend("operation was abandoned"))
+            });
+        }
+    }
+}
+
+impl ApiBase for Operations {}
+
+#[async_trait]
+impl OperationApi for Operations {
+    async fn list_ops(&self) -> Result<Vec<OperationId>> {
+        Ok(self.list())
+    }
+
+    async fn get_op(&self, op: OperationId) -> Result<Option<Operation>> {
+        Ok(self.get(op))
+    }
+
+    async fn wait_op(&self, op: OperationId, wait: Wait) -> Result<Operation> {
+        let timeout = Duration::from_millis(wait.timeout_ms.min(Wait::MAX_TIMEOUT_MS));
+
+        self.wait(op, timeout)
+            .await
+            .ok_or_else(|| Error::not_found("operation does not exist"))
+    }
+
+    async fn cancel_op(&self, op: OperationId) -> Result<()> {
+        self.cancel(op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Operations;
+    use crate::{api::operation::v1::OperationState, error::ErrorKind};
+
+    #[tokio::test]
+    async fn lifecycle() {
+        let ops = Operations::new();
+
+        let handle = ops.start("test");
+        let id = handle.id();
+
+        let op = ops.wait(id, Duration::from_millis(1)).await.unwrap();
+        assert_eq!(op.state, OperationState::Running);
+
+        ops.cancel(id).unwrap();
+        assert!(handle.is_cancelled());
+
+        tokio::spawn(async move { handle.succeed(5) });
+
+        let op = ops.wait(id, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(op.state, OperationState::Succeeded);
+        assert_eq!(op.result, Some(5.into()));
+
+        assert_eq!(ops.cancel(id).unwrap_err().kind, ErrorKind::InvalidState);
+
+        drop(ops.start("dropped"));
+        let dropped = ops.list().into_iter().find(|&o| o != id).unwrap();
+        assert_eq!(ops.get(dropped).unwrap().state, OperationState::Failed);
+    }
+}
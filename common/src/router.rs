@@ -1,6 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use axum;
+use axum::{self, routing::get, Json};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Path under which [`CompositeRouter::assemble`] mounts the discovery document.
+pub const DISCOVERY_PATH: &str = "/.well-known/istruct";
 
 pub struct VersionedRouter(axum::Router, String, usize, usize);
 
@@ -15,17 +20,66 @@ impl VersionedRouter {
     }
 }
 
+/// Identifies the component serving a [`CompositeRouter`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComponentIdentity {
+    pub name: String,
+    pub version: String,
+    /// Component ID, as assigned by the cluster manager.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub id: Option<Uuid>,
+}
+
+impl ComponentIdentity {
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, version: V) -> Self {
+        Self {
+            name: name.into(),
+            version: version.into(),
+            id: None,
+        }
+    }
+}
+
+/// Discovery document, lists every API a component serves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Discovery {
+    pub component: Option<ComponentIdentity>,
+    /// API prefix (`is.compute.machine`, ...) to its available major versions.
+    pub apis: BTreeMap<String, Vec<DiscoveredVersion>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscoveredVersion {
+    pub major: usize,
+    /// All minor versions available under `/vMAJOR.MINOR`, ascending.
+    pub minors: Vec<usize>,
+    /// The minor version the bare `/vMAJOR` alias resolves to.
+    pub default_minor: usize,
+}
+
 pub struct CompositeRouter {
     routers: HashMap<(String, usize, usize), axum::Router>,
+    identity: Option<ComponentIdentity>,
+}
+
+impl Default for CompositeRouter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CompositeRouter {
     pub fn new() -> Self {
         Self {
             routers: HashMap::new(),
+            identity: None,
         }
     }
 
+    pub fn set_identity(&mut self, identity: ComponentIdentity) {
+        self.identity = Some(identity);
+    }
+
     pub fn new_with<I>(i: I) -> anyhow::Result<Self>
     where
         I: IntoIterator<Item = VersionedRouter>,
@@ -47,6 +101,10 @@ impl CompositeRouter {
             anyhow::bail!("routing prefixes cannot contain slashes")
         }
 
+        if prefix.starts_with('.') {
+            anyhow::bail!("routing prefixes cannot start with a dot")
+        }
+
         let key = (prefix, major, minor);
 
         if self.routers.contains_key(&key) {
@@ -58,8 +116,49 @@ impl CompositeRouter {
         Ok(())
     }
 
-    // todo: also generate discovery information alongside this
+    pub fn discovery(&self) -> Discovery {
+        let mut apis: BTreeMap<String, BTreeMap<usize, Vec<usize>>> = BTreeMap::new();
+
+        for (prefix, major, minor) in self.routers.keys() {
+            apis.entry(prefix.clone())
+                .or_default()
+                .entry(*major)
+                .or_default()
+                .push(*minor);
+        }
+
+        let apis = apis
+            .into_iter()
+            .map(|(prefix, versions)| {
+                let versions = versions
+                    .into_iter()
+                    .map(|(major, mut minors)| {
+                        minors.sort_unstable();
+
+                        DiscoveredVersion {
+                            major,
+                            default_minor: *minors
+                                .last()
+                                .expect("default-then-pushed vec isnt empty"),
+                            minors,
+                        }
+                    })
+                    .collect();
+
+                (prefix, versions)
+            })
+            .collect();
+
+        Discovery {
+            component: self.identity.clone(),
+            apis,
+        }
+    }
+
+    /// Assembles all added routers, alongside a discovery document at [`DISCOVERY_PATH`].
     pub fn assemble(self) -> axum::Router {
+        let discovery = self.discovery();
+
         let mut rearranged: HashMap<String, HashMap<usize, Vec<(usize, axum::Router)>>> =
             HashMap::new();
 
@@ -103,6 +202,61 @@ impl CompositeRouter {
             }
         }
 
-        amalgamation
+        amalgamation.route(
+            DISCOVERY_PATH,
+            get(move || async move { Json(discovery.clone()) }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery_lists_versions() {
+        let mut comp = CompositeRouter::new_with([
+            VersionedRouter::new(axum::Router::new(), "is.compute.machine", 0, 1),
+            VersionedRouter::new(axum::Router::new(), "is.compute.machine", 0, 3),
+            VersionedRouter::new(axum::Router::new(), "is.compute.machine", 1, 0),
+            VersionedRouter::new(axum::Router::new(), "is.storage.device", 0, 1),
+        ])
+        .unwrap();
+
+        comp.set_identity(ComponentIdentity::new("test", "0.0.0"));
+
+        let discovery = comp.discovery();
+
+        assert_eq!(discovery.component.unwrap().name, "test");
+        assert_eq!(
+            discovery.apis["is.compute.machine"],
+            vec![
+                DiscoveredVersion {
+                    major: 0,
+                    minors: vec![1, 3],
+                    default_minor: 3,
+                },
+                DiscoveredVersion {
+                    major: 1,
+                    minors: vec![0],
+                    default_minor: 0,
+                },
+            ]
+        );
+        assert_eq!(discovery.apis["is.storage.device"].len(), 1);
+    }
+
+    #[test]
+    fn rejects_dotted_prefix() {
+        let mut comp = CompositeRouter::new();
+
+        assert!(comp
+            .add(VersionedRouter::new(
+                axum::Router::new(),
+                ".well-known",
+                0,
+                1
+            ))
+            .is_err());
     }
 }
@@ -1,87 +1,337 @@
 use std::{
     borrow::Borrow,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
     marker::PhantomData,
     ops::Index,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use diplomatic_bag::DiplomaticBag;
-
+use anyhow::Context;
 use istruct_common::{
-    api::compute::machine::v1::{MachineAction, MachineState},
-    id::{DeviceId, MachineId},
+    api::{
+        compute::machine::v1::{
+            validate_attr_key, AttrValue, Firmware, MachineAction, MachineEvent, MachineSpec,
+            MachineState, MachineStatus,
+        },
+        operation::v1::Pending,
+        reconcile::v1::Report,
+    },
+    error::{Error, Result},
+    etag::{ETag, IfMatch},
+    id::{DeviceId, MachineId, OperationId},
+    operation::Operations,
 };
-use persy::{ByteVec, IndexType, Persy};
+use persy::{ByteVec, IndexType, Persy, Transaction};
+use tokio::sync::broadcast;
 use uuid::Uuid;
 use virt::{
     connect::Connect,
     domain::{Domain, DomainState},
 };
 
+mod adopt;
 mod api;
-
-#[derive(Debug, Clone)]
-pub struct ClientPuck {
-    pub inner: Arc<DiplomaticBag<Client>>,
+mod backup;
+mod events;
+mod images;
+mod locks;
+mod reconcile;
+mod schema;
+#[cfg(test)]
+mod tests;
+mod workers;
+
+pub use images::{Images, QemuImg};
+use locks::{Guards, Locks};
+use workers::Workers;
+
+/// How many events a slow subscriber can fall behind before it starts missing them.
+const EVENT_BUFFER: usize = 64;
+
+/// How long a machine action waits for the machine to reach its state, unless set otherwise.
+const ACT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// What machines run on, and what makes their disk images.
+#[derive(Clone)]
+pub struct Backend {
+    /// Domain `type`, e.g. `kvm`, `qemu` without hardware acceleration, or `test`.
+    pub domain_type: String,
+    pub emulator: String,
+    pub images: Arc<dyn Images>,
 }
 
-impl ClientPuck {
-    #[inline]
-    fn with<R: Send>(&self, f: impl (FnOnce(&Client) -> R) + Send) -> R {
-        (*self.inner).as_ref().map(|_, c| f(c)).into_inner()
-    }
-
-    pub fn create(f: impl FnOnce() -> Client + Send) -> Self {
-        let bag = DiplomaticBag::new(move |_| f());
-
-        ClientPuck {
-            inner: Arc::new(bag),
+impl Default for Backend {
+    fn default() -> Self {
+        Self {
+            domain_type: "kvm".into(),
+            emulator: "/usr/bin/qemu-system-x86_64".into(),
+            images: Arc::new(QemuImg),
         }
     }
 }
 
-pub struct Client {
-    pub conn: Connect,
-    pub persy: Persy,
-    pub block_path_dir: PathBuf,
+#[derive(Debug, Clone)]
+pub struct ClientPuck {
+    workers: Workers,
+    locks: Locks,
+    pub ops: Operations,
+    events: broadcast::Sender<MachineEvent>,
+    last_report: Arc<Mutex<Option<Report>>>,
+    act_timeout: Duration,
 }
 
-impl Client {
+impl ClientPuck {
+    /// Opens the database, and spawns `workers` workers with a libvirt connection each.
     pub fn new(
         uri: &str,
         persy: impl AsRef<Path>,
         block_dir: impl AsRef<Path>,
+        workers: usize,
+        backend: Backend,
     ) -> anyhow::Result<Self> {
-        let conn = virt::connect::Connect::open(uri)?;
+        events::start_event_loop()?;
 
-        let block_path_dir = block_dir.as_ref().canonicalize()?;
+        let block_dir = block_dir.as_ref();
+        let block_path_dir = block_dir
+            .canonicalize()
+            .with_context(|| format!("failed to find block directory {}", block_dir.display()))?;
 
         if !block_path_dir.is_dir() {
-            anyhow::bail!("block_dir is not a directory");
+            anyhow::bail!("block directory {} is not a directory", block_dir.display());
         }
 
-        let persy = Persy::open_or_create_with(persy, persy::Config::new(), |_| Ok(()))?;
+        let persy = persy.as_ref();
+        let persy = Persy::open_or_create_with(persy, persy::Config::new(), |_| Ok(()))
+            .with_context(|| format!("failed to open database {}", persy.display()))?;
+
+        schema::migrate(&persy).context("failed to migrate database")?;
+
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+
+        let uri = uri.to_string();
+        let shared_events = events.clone();
 
-        Self::create_indexes(&persy);
+        let workers = Workers::spawn(workers, move |idx| {
+            let conn =
+                Connect::open(&uri).with_context(|| format!("failed to connect to {}", uri))?;
 
-        Ok(Self {
-            conn,
-            persy,
-            block_path_dir,
+            // one connection is enough to hear about every domain
+            if idx == 0 {
+                events::register_lifecycle(&conn, shared_events.clone(), persy.clone())?;
+            }
+
+            Ok(Client {
+                conn,
+                persy: persy.clone(),
+                block_path_dir: block_path_dir.clone(),
+                backend: backend.clone(),
+                events: shared_events.clone(),
+            })
+        })?;
+
+        Ok(ClientPuck {
+            workers,
+            locks: Locks::default(),
+            ops: Operations::new(),
+            events,
+            last_report: Arc::default(),
+            act_timeout: ACT_TIMEOUT,
         })
     }
 
-    fn create_indexes(persy: &Persy) {
-        test_or_create_index::<u128, u128>(&persy, DEV_MACHINE_ATTACHED);
-        test_or_create_index::<u128, ByteVec>(&persy, DEV_TYPE);
+    /// How long a machine action waits for the machine to reach its state before it fails.
+    pub fn set_act_timeout(&mut self, timeout: Duration) {
+        self.act_timeout = timeout;
+    }
+
+    /// Runs `f` on any worker.
+    #[inline]
+    async fn with<R, F>(&self, f: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&Client) -> R + Send + 'static,
+    {
+        self.workers.run(f).await
+    }
+
+    /// Runs `f` on any worker, while no other call holds any of `keys`.
+    ///
+    /// Every call that changes a machine or device has to hold it.
+    async fn with_locked<R, F>(&self, keys: impl IntoIterator<Item = Uuid>, f: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&Client) -> R + Send + 'static,
+    {
+        let guards = self.locks.lock(keys).await;
+
+        self.with(Self::holding(guards, f)).await
+    }
+
+    /// The job keeps the guards, a dropped call doesn't release them while `f` still runs.
+    fn holding<R>(guards: Guards, f: impl FnOnce(&Client) -> R) -> impl FnOnce(&Client) -> R {
+        move |c| {
+            let _guards = guards;
+
+            f(c)
+        }
+    }
+
+    /// Like [`ClientPuck::with_locked`], for a device and the machine it's attached to.
+    async fn with_device<R, F>(&self, device: DeviceId, f: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&Client) -> R + Send + 'static,
+    {
+        loop {
+            let machine = self.with(move |c| c.db().get_dev_attached(device)).await;
+
+            let guards = self.locks.lock(machine.into_iter().chain([device])).await;
+
+            // attaching takes the device lock, so this can't change anymore
+            if self.with(move |c| c.db().get_dev_attached(device)).await == machine {
+                return self.with(Self::holding(guards, f)).await;
+            }
+        }
+    }
+}
+
+// background operations
+impl ClientPuck {
+    async fn start_act(&self, machine: MachineId, action: MachineAction) -> Result<OperationId> {
+        // subscribe before acting, so the state change can't slip by
+        let mut rx = self.events.subscribe();
+
+        self.with_locked([machine], move |c| c.act_on(machine, action))
+            .await?;
+
+        let op = self.ops.start("act");
+        let id = op.id();
+
+        let target = match action.target() {
+            Some(t) => t,
+            None => {
+                op.succeed(());
+                return Ok(id);
+            }
+        };
+
+        op.progress("waiting for the machine to change state");
+
+        let puck = self.clone();
+        let deadline = tokio::time::Instant::now() + self.act_timeout;
+
+        tokio::spawn(async move {
+            loop {
+                if op.is_cancelled() {
+                    return op.cancelled();
+                }
+
+                if tokio::time::Instant::now() >= deadline {
+                    return op.fail(Error::invalid_state(format!(
+                        "machine didn't become {:?} within {} seconds",
+                        target,
+                        puck.act_timeout.as_secs()
+                    )));
+                }
+
+                match puck
+                    .with(move |c| c.get_status(machine))
+                    .await
+                    .map(|s| s.state)
+                {
+                    None => return op.fail(Error::not_found("machine no longer exists")),
+                    Some(state) if state == target => return op.succeed(()),
+                    Some(MachineState::Crashed) => {
+                        return op.fail(Error::invalid_state("machine crashed"))
+                    }
+                    Some(MachineState::Lost) => {
+                        return op.fail(Error::invalid_state("machine was lost"))
+                    }
+                    Some(_) => {}
+                }
+
+                // state changes wake this up early, the timeout is for cancellation
+                let _ = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+            }
+        });
+
+        Ok(id)
+    }
+
+    fn start_create_block(&self, bytes: u64) -> Result<Pending<DeviceId>> {
+        if bytes == 0 {
+            return Err(Error::invalid_argument(
+                "block devices need at least 1 byte",
+            ));
+        }
+
+        let device = Uuid::new_v4();
+
+        let op = self.ops.start("create_block");
+
+        let pending = Pending {
+            operation: op.id(),
+            target: device,
+        };
+
+        let puck = self.clone();
+
+        tokio::spawn(async move {
+            if op.is_cancelled() {
+                return op.cancelled();
+            }
+
+            op.progress("creating block file");
+
+            // qemu-img can't be interrupted, a cancelled creation is undone afterwards
+            let res = puck
+                .with_locked([device], move |c| c.create_block(device, bytes))
+                .await;
+
+            if op.is_cancelled() {
+                if res.is_ok() {
+                    let _ = puck
+                        .with_locked([device], move |c| c.delete_block(device))
+                        .await;
+                }
+
+                return op.cancelled();
+            }
+
+            op.complete(res.map(|()| device));
+        });
+
+        Ok(pending)
+    }
+}
+
+pub struct Client {
+    pub conn: Connect,
+    pub persy: Persy,
+    pub block_path_dir: PathBuf,
+    backend: Backend,
+    events: broadcast::Sender<MachineEvent>,
+}
 
-        test_or_create_index::<u128, u64>(&persy, DEV_CPU);
-        test_or_create_index::<u128, u64>(&persy, DEV_MEM);
+impl Client {
+    fn emit(&self, event: MachineEvent) {
+        // no subscribers is fine
+        let _ = self.events.send(event);
+    }
 
-        test_or_create_index::<u128, u64>(&persy, DEV_BLOCK_CAPACITY);
+    /// Tag of a machine or device, see [`ClientDB::bump_generation`].
+    fn etag(&self, resource: impl Borrow<Uuid>) -> ETag {
+        ETag::new(self.db().get_generation(resource))
+    }
 
-        test_or_create_index::<u128, u8>(&persy, KNOWN_MACHINES);
+    /// Fails with `precondition_failed` if `if_match` doesn't match the current tag.
+    ///
+    /// Only meaningful while holding the lock of `resource`.
+    fn check_etag(&self, resource: impl Borrow<Uuid>, if_match: &IfMatch) -> Result<()> {
+        if_match.check(&self.etag(resource))
     }
 
     pub fn get_domain(&self, machine: impl Borrow<Uuid>) -> Option<Domain> {
@@ -108,53 +358,101 @@ impl Client {
             .collect()
     }
 
-    pub fn get_status(&self, uuid: impl Borrow<MachineId>) -> Option<MachineState> {
-        self.get_domain(uuid)
-            .map(|d| {
-                d.get_state()
-                    .ok()
-                    .map(|(s, _)| virt_domainstate_to_istruct(s))
-            })
-            .flatten()
+    pub fn get_status(&self, uuid: impl Borrow<MachineId>) -> Option<MachineStatus> {
+        let uuid = uuid.borrow();
+
+        match self.get_domain(uuid) {
+            Some(d) => d
+                .get_state()
+                .ok()
+                .map(|(s, r)| virt_domainstate_to_istruct(s, r)),
+            None if self.db().is_lost_machine(uuid) => Some(MachineStatus {
+                state: MachineState::Lost,
+                reason: Some("domain is gone".into()),
+            }),
+            None => None,
+        }
     }
 
     pub fn get_name(&self, uuid: Uuid) -> Option<String> {
         self.get_domain(uuid).map(|d| d.get_name().ok()).flatten()
     }
 
-    pub fn act_on(&self, uuid: Uuid, action: MachineAction) -> Option<()> {
-        let domain = self.get_domain(uuid)?;
+    pub fn act_on(&self, uuid: Uuid, action: MachineAction) -> Result<()> {
+        let status = self
+            .get_status(uuid)
+            .ok_or_else(|| Error::not_found("machine does not exist"))?;
+
+        action.check(status.state)?;
 
-        // todo verify starting state before running these
-        match action {
+        let domain = self
+            .get_domain(uuid)
+            .ok_or_else(|| Error::not_found("machine does not exist"))?;
+
+        let res = match action {
             MachineAction::ForceShutdown => domain
                 .destroy_flags(virt::domain::VIR_DOMAIN_DESTROY_GRACEFUL)
-                .unwrap(),
-            MachineAction::ForceReset => domain.reset().unwrap(),
-            MachineAction::Shutdown => domain.shutdown().unwrap(),
-            MachineAction::Suspend => domain.suspend().unwrap(),
-            MachineAction::Resume => domain.resume().unwrap(),
-            MachineAction::Boot => domain.create().unwrap(),
+                .map(drop),
+            MachineAction::ForceReset => domain.reset().map(drop),
+            MachineAction::Shutdown => domain.shutdown().map(drop),
+            MachineAction::Reboot => domain.reboot(),
+            MachineAction::Suspend => domain.suspend().map(drop),
+            MachineAction::Resume => domain.resume().map(drop),
+            MachineAction::Boot => domain.create().map(drop),
         };
 
-        Some(())
+        res.map_err(|e| Error::backend(e.to_string()))
     }
 
-    pub fn create(&self) -> Uuid {
+    pub fn create(&self, spec: MachineSpec) -> Result<Uuid> {
         use crate::xml;
 
+        spec.validate()?;
+
+        // check devices before anything is defined, so we don't have to roll back
+        for &device in &spec.devices {
+            match self.db().get_dev_type(device) {
+                None => {
+                    return Err(Error::not_found(format!(
+                        "could not find device {}",
+                        device
+                    )))
+                }
+                Some(DeviceType::Compute(_)) => {
+                    return Err(Error::invalid_argument("cannot reassign compute devices"))
+                }
+                Some(_) => {}
+            }
+
+            if let Some(m) = self.db().get_dev_attached(device) {
+                return Err(Error::already_attached(format!(
+                    "device {} is attached to {}",
+                    device, m
+                )));
+            }
+        }
+
         let uuid = Uuid::new_v4();
 
-        let cpu_cores = 1u64;
-        let mem_bytes = 128000000; // 128 MB
+        let name = spec.name.unwrap_or_else(|| uuid.to_string());
+
+        if Domain::lookup_by_name(&self.conn, &name).is_ok() {
+            return Err(Error::invalid_state(format!(
+                "a machine named {} already exists",
+                name
+            )));
+        }
 
-        self.define_domain(xml::Domain {
-            typ: "kvm".to_string(),
+        let cpu_cores = spec.cores;
+        let mem_bytes = spec.memory_bytes;
+
+        let domain = xml::Domain {
+            typ: self.backend.domain_type.clone(),
             id: None,
-            name: uuid.to_string(),
+            name,
             uuid: Some(uuid),
             genid: None,
-            title: None,
+            title: spec.title,
             description: None,
             memory: xml::Memory {
                 unit: xml::Unit::Bytes,
@@ -169,7 +467,10 @@ impl Client {
                 amount: cpu_cores as usize,
             },
             os: xml::OperatingSystem {
-                firmware: None,
+                firmware: spec.firmware.map(|f| match f {
+                    Firmware::Bios => xml::Firmware::BIOS,
+                    Firmware::Efi => xml::Firmware::EFI,
+                }),
                 typ: xml::OSType {
                     arch: None,
                     machine: None,
@@ -178,34 +479,9 @@ impl Client {
             },
             features: None,
             devices: xml::Devices {
-                emulator: "/usr/bin/qemu-system-x86_64".to_string(),
-                disks: vec![xml::Disk {
-                    r#type: xml::DiskType::File,
-                    driver: None,
-                    source: None,
-                    target: Some(xml::DiskTarget {
-                        dev: "sda".into(),
-                        bus: Some("sata".into()),
-
-                        tray: None,
-                        removable: None,
-                        rotation_rate: None,
-                    }),
-                    readonly: vec![xml::Empty::new()],
-                    boot: Some(xml::DiskBoot {
-                        order: 1,
-
-                        loadparm: None,
-                    }),
-
-                    device: Some(xml::DiskDevice::CDROM),
-                    shareable: vec![],
-                    serial: None,
-                    wwn: None,
-                    vendor: None,
-                    product: None,
-                    address: None,
-                }],
+                emulator: Some(self.backend.emulator.clone()),
+                // cdroms are attached as is.storage.cdrom devices
+                disks: vec![],
                 controllers: vec![],
                 interfaces: vec![],
                 parallels: vec![],
@@ -227,53 +503,86 @@ impl Client {
                     gl: None,
                 }],
             },
-        })
-        .unwrap();
+            unmodelled: Default::default(),
+        };
 
-        let db = self.db();
+        // the domain is defined last, so the records are rolled back if that fails
+        let res = self.transaction(|db| {
+            let mem = Uuid::new_v4();
 
-        let mem = Uuid::new_v4();
+            db.set_dev_type(mem, DeviceType::Compute(ComputeDeviceType::Mem));
+            db.set_dev_attached(mem, uuid);
+            db.set_dev_mem(mem, mem_bytes);
 
-        db.set_dev_type(mem.clone(), DeviceType::Compute(ComputeDeviceType::Mem));
-        db.set_dev_attached(mem.clone(), uuid.clone());
-        db.set_dev_mem(mem, mem_bytes);
+            let cpu = Uuid::new_v4();
 
-        let cpu = Uuid::new_v4();
+            db.set_dev_type(cpu, DeviceType::Compute(ComputeDeviceType::Cpu));
+            db.set_dev_attached(cpu, uuid);
+            db.set_dev_cpu(cpu, cpu_cores);
 
-        db.set_dev_type(cpu.clone(), DeviceType::Compute(ComputeDeviceType::Cpu));
-        db.set_dev_attached(cpu.clone(), uuid.clone());
-        db.set_dev_cpu(cpu, cpu_cores);
+            db.set_known_machine(uuid);
 
-        db.set_known_machine(uuid.clone());
+            self.define_domain(domain)?;
 
-        uuid
+            Ok(())
+        });
+
+        if let Err(e) = res {
+            // the domain was defined, but committing failed
+            if self.get_domain(uuid).is_some() {
+                let _ = self.undefine_domain(uuid);
+            }
+
+            return Err(e);
+        }
+
+        self.emit(MachineEvent::Created { machine: uuid });
+
+        for device in spec.devices {
+            if let Err(e) = self.attach_device(uuid, device) {
+                // the machine is left behind, but the attach failure is what the caller needs
+                if let Err(rollback) = self.destroy(uuid) {
+                    tracing::error!(
+                        "failed to remove machine {} after a failed create: {}",
+                        uuid,
+                        rollback
+                    );
+                }
+
+                return Err(e.context(format!("failed to attach device {}", device)));
+            }
+        }
+
+        Ok(uuid)
     }
 
     pub fn define_domain(&self, domain: crate::xml::Domain) -> anyhow::Result<Uuid> {
+        self.define_domain_xml(&domain.to_string()?)
+    }
+
+    /// Defines the XML as is, for XML that didn't go through [`crate::xml::Domain`].
+    pub fn define_domain_xml(&self, xml: &str) -> anyhow::Result<Uuid> {
         Ok(Uuid::parse_str(
-            &Domain::define_xml_flags(
-                &self.conn,
-                &domain.to_string()?,
-                virt::domain::VIR_DOMAIN_DEFINE_VALIDATE,
-            )?
-            .get_uuid_string()?,
+            &Domain::define_xml_flags(&self.conn, xml, virt::domain::VIR_DOMAIN_DEFINE_VALIDATE)?
+                .get_uuid_string()?,
         )?)
     }
 
     // todo: device destroy/detach flags
-    pub fn destroy(&self, uuid: Uuid) -> anyhow::Result<()> {
+    pub fn destroy(&self, uuid: Uuid) -> Result<()> {
         let status = self
             .get_status(&uuid)
-            .ok_or(anyhow::anyhow!("machine does not exist"))?;
+            .ok_or_else(|| Error::not_found("machine does not exist"))?;
 
-        if let MachineState::Off = status {
-            // fallthrough
-        } else {
-            anyhow::bail!("machine is not off");
-        }
+        // lost machines have no domain left to detach from or undefine
+        let lost = match status.state {
+            MachineState::Off => false,
+            MachineState::Lost => true,
+            _ => return Err(Error::invalid_state("machine is not off")),
+        };
 
-        let mut mem: Option<DeviceId> = None;
-        let mut cpu: Option<DeviceId> = None;
+        let mut compute = vec![];
+        let mut dangling = vec![];
 
         for (d, t) in self
             .db()
@@ -281,57 +590,81 @@ impl Client {
             .filter_map(|d| self.db().get_dev_type(d).map(|t| (d, t)))
         {
             if let DeviceType::Compute(c) = t {
-                match c {
-                    ComputeDeviceType::Cpu => cpu.insert(d),
-                    ComputeDeviceType::Mem => mem.insert(d),
-                };
+                compute.push((d, c));
+            } else if lost {
+                dangling.push(d);
             } else {
-                self.detach_device(uuid.clone(), d)
-                    .expect("machine is off, removing stuff should be fine")
+                self.detach_device(uuid, d)?
             }
         }
 
-        let cpu = cpu.expect("cpu device always exists");
-        let mem = mem.expect("memory device always exists");
+        let domain = self.get_domain_xml(uuid);
 
-        let db = self.db();
+        // the domain is undefined last, so the records are rolled back if that fails
+        let res = self.transaction(|db| {
+            for (d, c) in compute {
+                db.del_compute_device(d, c);
+            }
+
+            for d in dangling {
+                db.del_dev_attached(d);
+            }
+
+            for (attr, _) in db.get_machine_attrs(uuid) {
+                db.del_machine_attr(uuid, &attr);
+            }
 
-        db.del_dev_type(mem.clone());
-        db.del_dev_attached(mem.clone());
-        db.del_dev_mem(mem);
+            db.del_known_machine(uuid);
+            db.del_lost_machine(uuid);
+            db.del_generation(uuid);
 
-        db.del_dev_type(cpu.clone());
-        db.del_dev_attached(cpu.clone());
-        db.del_dev_cpu(cpu);
+            self.undefine_domain(uuid)
+        });
 
-        self.undefine_domain(uuid.clone());
+        if let Err(e) = res {
+            // the domain was undefined, but committing failed
+            if let Some(domain) = domain {
+                if self.get_domain(uuid).is_none() {
+                    let _ = self.define_domain(domain);
+                }
+            }
 
-        db.del_known_machine(uuid.clone());
+            return Err(e);
+        }
+
+        self.emit(MachineEvent::Destroyed { machine: uuid });
 
         Ok(())
     }
 
-    pub fn undefine_domain(&self, uuid: Uuid) {
+    pub fn undefine_domain(&self, uuid: Uuid) -> Result<()> {
         if let Some(d) = self.get_domain(uuid) {
-            d.undefine().unwrap();
+            d.undefine()
+                .map_err(|e| Error::backend(format!("failed to undefine domain: {}", e)))?;
         }
+
+        Ok(())
     }
 
-    pub fn attach_device(&self, machine: MachineId, device: DeviceId) -> anyhow::Result<()> {
+    pub fn attach_device(&self, machine: MachineId, device: DeviceId) -> Result<()> {
         let typ = self
             .db()
             .get_dev_type(&device)
-            .ok_or(anyhow::anyhow!("could not find device"))?;
+            .ok_or_else(|| Error::not_found("could not find device"))?;
 
         if let DeviceType::Compute(_) = typ {
-            anyhow::bail!("cannot reassign compute devices");
+            return Err(Error::invalid_argument("cannot reassign compute devices"));
         }
 
         if let Some(attached_machine) = self.db().get_dev_attached(&device) {
             if attached_machine == machine {
-                anyhow::bail!("device already attached to this machine")
+                return Err(Error::already_attached(
+                    "device already attached to this machine",
+                ));
             } else {
-                anyhow::bail!("device already attached to other machine")
+                return Err(Error::already_attached(
+                    "device already attached to other machine",
+                ));
             }
         }
 
@@ -339,61 +672,82 @@ impl Client {
 
         match typ {
             DeviceType::Storage(S::Block) => self.attach_block(machine, device),
+            DeviceType::Storage(S::Cdrom) => self.attach_cdrom(machine, device),
             DeviceType::Network(N::Nat) => self.attach_nat(machine, device),
 
             DeviceType::Compute(_) => unreachable!(),
-        }
+        }?;
+
+        self.db().bump_generation(machine);
+
+        self.emit(MachineEvent::DeviceAttached { machine, device });
+
+        Ok(())
     }
 
-    pub fn detach_device(&self, machine: MachineId, device: DeviceId) -> anyhow::Result<()> {
+    pub fn detach_device(&self, machine: MachineId, device: DeviceId) -> Result<()> {
         let typ = self
             .db()
             .get_dev_type(&device)
-            .ok_or(anyhow::anyhow!("could not find device"))?;
+            .ok_or_else(|| Error::not_found("could not find device"))?;
 
         if let DeviceType::Compute(_) = typ {
-            anyhow::bail!("cannot reassign compute devices");
+            return Err(Error::invalid_argument("cannot reassign compute devices"));
         }
 
         let attached_machine = self.db().get_dev_attached(&device);
 
         if let Some(attached_machine) = attached_machine {
             if attached_machine != machine {
-                anyhow::bail!("device is attached to different device");
+                return Err(Error::invalid_state(
+                    "device is attached to different machine",
+                ));
             }
         } else {
-            anyhow::bail!("device is not attached");
+            return Err(Error::invalid_state("device is not attached"));
         }
 
         use {NetworkDeviceType as N, StorageDeviceType as S};
 
         match typ {
             DeviceType::Storage(S::Block) => self.detach_block(machine, device),
+            DeviceType::Storage(S::Cdrom) => self.detach_cdrom(machine, device),
             DeviceType::Network(N::Nat) => self.detach_nat(machine, device),
 
             DeviceType::Compute(_) => unreachable!(),
-        }
+        }?;
+
+        self.db().bump_generation(machine);
+
+        self.emit(MachineEvent::DeviceDetached { machine, device });
+
+        Ok(())
     }
 
-    pub fn find_domains(&self) -> impl Iterator<Item = MachineId> + '_ {
-        let domains = self.conn.list_all_domains(0).unwrap();
+    pub fn find_domains(&self) -> Result<Vec<MachineId>> {
+        let domains = self
+            .conn
+            .list_all_domains(0)
+            .map_err(|e| Error::backend(format!("failed to list domains: {}", e)))?;
 
         let db = self.db();
 
-        domains
+        Ok(domains
             .into_iter()
-            .map(|d| Uuid::parse_str(&d.get_uuid_string().unwrap()).unwrap())
-            .filter(move |u| db.is_known_machine(u))
+            .filter_map(|d| d.get_uuid_string().ok())
+            .filter_map(|s| Uuid::parse_str(&s).ok())
+            .filter(|u| db.is_known_machine(u))
+            .collect())
     }
 
     fn edit(
         &self,
         machine: impl Borrow<MachineId>,
         f: impl FnOnce(&mut crate::xml::Domain),
-    ) -> anyhow::Result<()> {
+    ) -> Result<()> {
         let mut dom = self
             .get_domain_xml(machine)
-            .ok_or(anyhow::anyhow!("cannot fetch domain"))?;
+            .ok_or_else(|| Error::not_found("machine does not exist"))?;
 
         f(&mut dom);
 
@@ -403,21 +757,98 @@ impl Client {
     }
 }
 
+// machine attributes
+impl Client {
+    fn known_machine(&self, machine: impl Borrow<MachineId>) -> Result<()> {
+        if self.db().is_known_machine(machine) {
+            Ok(())
+        } else {
+            Err(Error::not_found("machine does not exist"))
+        }
+    }
+
+    fn get_attr(&self, machine: MachineId, attr: &str) -> Result<AttrValue> {
+        self.known_machine(machine)?;
+
+        self.db()
+            .get_machine_attr(machine, attr)
+            .ok_or_else(|| Error::not_found(format!("attribute {} is not set", attr)))
+    }
+
+    fn get_attrs(&self, machine: MachineId) -> Result<BTreeMap<String, AttrValue>> {
+        self.known_machine(machine)?;
+
+        Ok(self.db().get_machine_attrs(machine).collect())
+    }
+
+    fn set_attr(&self, machine: MachineId, attr: &str, value: AttrValue) -> Result<()> {
+        self.known_machine(machine)?;
+        validate_attr_key(attr)?;
+
+        let db = self.db();
+
+        db.set_machine_attr(machine, attr, &value);
+        db.bump_generation(machine);
+
+        Ok(())
+    }
+
+    fn delete_attr(&self, machine: MachineId, attr: &str) -> Result<()> {
+        self.known_machine(machine)?;
+
+        let db = self.db();
+
+        if db.get_machine_attr(machine, attr).is_none() {
+            return Err(Error::not_found(format!("attribute {} is not set", attr)));
+        }
+
+        db.del_machine_attr(machine, attr);
+        db.bump_generation(machine);
+
+        Ok(())
+    }
+
+    fn patch_attrs(
+        &self,
+        machine: MachineId,
+        attrs: HashMap<String, Option<AttrValue>>,
+    ) -> Result<()> {
+        self.known_machine(machine)?;
+
+        for attr in attrs.keys() {
+            validate_attr_key(attr)?;
+        }
+
+        let db = self.db();
+
+        for (attr, value) in attrs {
+            match value {
+                Some(value) => db.set_machine_attr(machine, &attr, &value),
+                None => db.del_machine_attr(machine, &attr),
+            }
+        }
+
+        db.bump_generation(machine);
+
+        Ok(())
+    }
+}
+
 // device functions specific to compute
 impl Client {
-    fn set_cpu_cores(&self, dev: DeviceId, cores: u64) -> anyhow::Result<()> {
+    fn set_cpu_cores(&self, dev: DeviceId, cores: u64) -> Result<()> {
         if cores == 0 {
-            anyhow::bail!("cores have to be above 0");
+            return Err(Error::invalid_argument("cores have to be above 0"));
         }
 
         self.db()
             .get_dev_type(&dev)
-            .ok_or(anyhow::anyhow!("cannot find device"))
+            .ok_or_else(|| Error::not_found("cannot find device"))
             .and_then(|t| {
                 if let DeviceType::Compute(ComputeDeviceType::Cpu) = t {
                     Ok(())
                 } else {
-                    Err(anyhow::anyhow!("device is not cpu"))
+                    Err(Error::invalid_argument("device is not cpu"))
                 }
             })?;
 
@@ -428,7 +859,10 @@ impl Client {
 
         self.edit(m, |d| d.vcpu.amount = cores as usize)?;
 
-        self.db().set_dev_cpu(dev, cores);
+        let db = self.db();
+
+        db.set_dev_cpu(dev, cores);
+        db.bump_generation(dev);
 
         Ok(())
     }
@@ -437,19 +871,19 @@ impl Client {
         self.db().get_dev_cpu(dev)
     }
 
-    fn set_mem_bytes(&self, dev: DeviceId, bytes: u64) -> anyhow::Result<()> {
+    fn set_mem_bytes(&self, dev: DeviceId, bytes: u64) -> Result<()> {
         if bytes == 0 {
-            anyhow::bail!("memory bytes have to be above 0");
+            return Err(Error::invalid_argument("memory bytes have to be above 0"));
         }
 
         self.db()
             .get_dev_type(&dev)
-            .ok_or(anyhow::anyhow!("cannot find device"))
+            .ok_or_else(|| Error::not_found("cannot find device"))
             .and_then(|t| {
                 if let DeviceType::Compute(ComputeDeviceType::Mem) = t {
                     Ok(())
                 } else {
-                    Err(anyhow::anyhow!("device is not memory"))
+                    Err(Error::invalid_argument("device is not memory"))
                 }
             })?;
 
@@ -465,7 +899,10 @@ impl Client {
             d.current_memory = None;
         })?;
 
-        self.db().set_dev_mem(dev, bytes);
+        let db = self.db();
+
+        db.set_dev_mem(dev, bytes);
+        db.bump_generation(dev);
 
         Ok(())
     }
@@ -477,33 +914,35 @@ impl Client {
 
 // device functions specific to storage
 impl Client {
-    fn create_block(&self, bytes: u64) -> DeviceId {
-        let uuid = Uuid::new_v4();
-
-        self.create_block_file(&uuid, bytes).unwrap();
+    fn create_block(&self, device: DeviceId, bytes: u64) -> Result<()> {
+        self.create_block_file(device, bytes)
+            .map_err(|e| Error::backend(format!("failed to create block file: {:#}", e)))?;
 
         let db = self.db();
 
-        db.set_dev_type(uuid.clone(), DeviceType::Storage(StorageDeviceType::Block));
-        db.set_dev_block_cap(uuid.clone(), bytes);
+        db.set_dev_type(device, DeviceType::Storage(StorageDeviceType::Block));
+        db.set_dev_block_cap(device, bytes);
 
-        uuid
+        Ok(())
     }
 
-    fn delete_block(&self, device: DeviceId) -> anyhow::Result<()> {
+    fn delete_block(&self, device: DeviceId) -> Result<()> {
         self.db()
             .get_dev_type(&device)
-            .ok_or(anyhow::anyhow!("could not find device"))
+            .ok_or_else(|| Error::not_found("could not find device"))
             .and_then(|t| {
                 if let DeviceType::Storage(StorageDeviceType::Block) = t {
                     Ok(())
                 } else {
-                    Err(anyhow::anyhow!("device was not block storage"))
+                    Err(Error::invalid_argument("device was not block storage"))
                 }
             })?;
 
         if let Some(machine) = self.db().get_dev_attached(&device) {
-            anyhow::bail!("device is attached to {}", machine);
+            return Err(Error::already_attached(format!(
+                "device is attached to {}",
+                machine
+            )));
         }
 
         self.delete_block_file(device)?;
@@ -512,6 +951,8 @@ impl Client {
 
         db.del_dev_type(device.clone());
         db.del_dev_block_cap(device.clone());
+        db.del_dev_block_path(device);
+        db.del_generation(device);
 
         Ok(())
     }
@@ -520,24 +961,27 @@ impl Client {
         self.db().get_dev_block_cap(device)
     }
 
-    fn attach_block(&self, machine: MachineId, device: DeviceId) -> anyhow::Result<()> {
+    fn attach_block(&self, machine: MachineId, device: DeviceId) -> Result<()> {
         self.db()
             .get_dev_type(&device)
-            .ok_or(anyhow::anyhow!("could not find device"))
+            .ok_or_else(|| Error::not_found("could not find device"))
             .and_then(|t| {
                 if let DeviceType::Storage(StorageDeviceType::Block) = t {
                     Ok(())
                 } else {
-                    Err(anyhow::anyhow!("device was not a storage block"))
+                    Err(Error::invalid_argument("device was not a storage block"))
                 }
             })?;
 
         if let Some(m) = self.db().get_dev_attached(&device) {
-            anyhow::bail!("device is attached to {}", m);
+            return Err(Error::already_attached(format!(
+                "device is attached to {}",
+                m
+            )));
         }
 
         self.edit(&machine, |d| {
-            use crate::xml::{Disk, DiskDriver, DiskTarget, DiskType, Source};
+            use crate::xml::{Alias, Disk, DiskDriver, DiskTarget, DiskType, Source};
 
             d.devices.disks.push(Disk {
                 r#type: DiskType::File,
@@ -563,7 +1007,7 @@ impl Client {
                     self.path_for_block_device(&device).to_str().unwrap(),
                 )),
                 target: Some(DiskTarget {
-                    dev: calculate_next_vd(&d.devices.disks),
+                    dev: calculate_next_dev(&d.devices.disks, "vd"),
                     bus: Some("ide".into()),
 
                     tray: None,
@@ -580,6 +1024,7 @@ impl Client {
                 vendor: None,
                 product: None,
                 address: None,
+                alias: Some(Alias::user(device)),
             })
         })?;
 
@@ -588,41 +1033,41 @@ impl Client {
         Ok(())
     }
 
-    fn detach_block(&self, machine: MachineId, device: DeviceId) -> anyhow::Result<()> {
+    fn detach_block(&self, machine: MachineId, device: DeviceId) -> Result<()> {
         self.db()
             .get_dev_type(&device)
-            .ok_or(anyhow::anyhow!("could not find device"))
+            .ok_or_else(|| Error::not_found("could not find device"))
             .and_then(|t| {
                 if let DeviceType::Storage(StorageDeviceType::Block) = t {
                     Ok(())
                 } else {
-                    Err(anyhow::anyhow!("device was not a storage block"))
+                    Err(Error::invalid_argument("device was not a storage block"))
                 }
             })?;
 
         if let Some(m) = self.db().get_dev_attached(&device) {
             if m != machine {
-                anyhow::bail!(
+                return Err(Error::invalid_state(format!(
                     "device is not attached to {}, it is attached to {}",
-                    machine,
-                    m
-                );
+                    machine, m
+                )));
             }
         } else {
-            anyhow::bail!("device is not attached");
+            return Err(Error::invalid_state("device is not attached"));
         }
 
+        let path = self.path_for_block_device(device);
+
         self.edit(machine, |d| {
             d.devices.disks.retain(|disk| {
-                if let Some(source) = &disk.source {
-                    if let Some(file) = &source.file {
-                        if file.contains(&device.to_string()) {
-                            return false;
-                        }
-                    }
+                if disk.alias.is_some() {
+                    return disk.alias != Some(crate::xml::Alias::user(device));
                 }
 
-                true
+                // disks attached before aliases were set, only match on the exact path
+                let file = disk.source.as_ref().and_then(|s| s.file.as_deref());
+
+                file.map(std::path::Path::new) != Some(path.as_path())
             })
         })?;
 
@@ -632,87 +1077,149 @@ impl Client {
     }
 }
 
-const NAT_NETWORK_XML: &str = r#"<network>
-<name>istruct_nat</name>
-<forward mode="nat"/>
-<domain name="network"/>
-<ip address="192.168.100.1" netmask="255.255.255.0">
-  <dhcp>
-    <range start="192.168.100.128" end="192.168.100.254"/>
-  </dhcp>
-</ip>
-</network>"#;
+const NAT_NETWORK_NAME: &str = "istruct_nat";
+
+/// Whether the interface is on the nat network, which every nat device is.
+fn is_nat_interface(interface: &crate::xml::NetworkInterface) -> bool {
+    interface.source.as_ref().and_then(|s| s.network.as_deref()) == Some(NAT_NETWORK_NAME)
+}
+
+/// The network nat interfaces attach to, guests get addresses from the upper half.
+fn nat_network() -> crate::xml::Network {
+    use crate::xml;
+
+    xml::Network {
+        name: NAT_NETWORK_NAME.into(),
+        uuid: None,
+        ipv6: None,
+        trust_guest_rx_filters: None,
+        forward: Some(xml::Forward {
+            mode: Some(xml::ForwardMode::NAT),
+            dev: None,
+            nat: None,
+            interfaces: vec![],
+        }),
+        bridge: None,
+        domain: Some(xml::NetworkDomain {
+            name: "network".into(),
+            local_only: None,
+        }),
+        dns: None,
+        ips: vec![xml::Ip {
+            address: Some("192.168.100.1".into()),
+            netmask: Some("255.255.255.0".into()),
+            prefix: None,
+            family: None,
+            local_ptr: None,
+            dhcp: Some(xml::Dhcp {
+                ranges: vec![xml::DhcpRange {
+                    start: "192.168.100.128".into(),
+                    end: "192.168.100.254".into(),
+                }],
+                hosts: vec![],
+            }),
+        }],
+        unmodelled: Default::default(),
+    }
+}
 
 // device functions specific to network
 impl Client {
-    fn create_nat(&self) -> DeviceId {
+    fn create_nat(&self) -> Result<DeviceId> {
         let uuid = Uuid::new_v4();
 
-        self.assure_nat_network();
+        self.assure_nat_network()?;
 
         self.db()
             .set_dev_type(uuid.clone(), DeviceType::Network(NetworkDeviceType::Nat));
 
-        uuid
+        Ok(uuid)
     }
 
-    fn assure_nat_network(&self) {
+    /// Defines the nat network if needed, and makes sure it runs now and after host reboots.
+    ///
+    /// An existing definition is kept as is.
+    fn assure_nat_network(&self) -> anyhow::Result<()> {
         use virt::network::Network;
 
-        if Network::lookup_by_name(&self.conn, "istruct_nat").is_err() {
-            Network::define_xml(&self.conn, NAT_NETWORK_XML).unwrap();
+        let network = match Network::lookup_by_name(&self.conn, NAT_NETWORK_NAME) {
+            Ok(network) => network,
+            Err(_) => Network::define_xml(&self.conn, &nat_network().to_string()?)
+                .context("failed to define the nat network")?,
+        };
+
+        if !network.is_active()? {
+            network
+                .create()
+                .context("failed to start the nat network")?;
         }
+
+        if !network.get_autostart()? {
+            network.set_autostart(true)?;
+        }
+
+        Ok(())
     }
 
-    fn delete_nat(&self, device: DeviceId) -> anyhow::Result<()> {
+    fn delete_nat(&self, device: DeviceId) -> Result<()> {
         self.db()
             .get_dev_type(&device)
-            .ok_or(anyhow::anyhow!("could not find device"))
+            .ok_or_else(|| Error::not_found("could not find device"))
             .and_then(|t| {
                 if let DeviceType::Network(NetworkDeviceType::Nat) = t {
                     Ok(())
                 } else {
-                    Err(anyhow::anyhow!("device was not a nat interface"))
+                    Err(Error::invalid_argument("device was not a nat interface"))
                 }
             })?;
 
         if let Some(machine) = self.db().get_dev_attached(&device) {
-            anyhow::bail!("device is attached to {}", machine);
+            return Err(Error::already_attached(format!(
+                "device is attached to {}",
+                machine
+            )));
         }
 
         // device is nat, is not attached
 
-        self.db().del_dev_type(device);
+        let db = self.db();
+
+        db.del_dev_type(device);
+        db.del_generation(device);
 
         Ok(())
     }
 
-    fn attach_nat(&self, machine: MachineId, device: DeviceId) -> anyhow::Result<()> {
+    fn attach_nat(&self, machine: MachineId, device: DeviceId) -> Result<()> {
         self.db()
             .get_dev_type(&device)
-            .ok_or(anyhow::anyhow!("could not find device"))
+            .ok_or_else(|| Error::not_found("could not find device"))
             .and_then(|t| {
                 if let DeviceType::Network(NetworkDeviceType::Nat) = t {
                     Ok(())
                 } else {
-                    Err(anyhow::anyhow!("device was not a nat network"))
+                    Err(Error::invalid_argument("device was not a nat network"))
                 }
             })?;
 
         if let Some(m) = self.db().get_dev_attached(&device) {
-            anyhow::bail!("device is attached to {}", m);
+            return Err(Error::already_attached(format!(
+                "device is attached to {}",
+                m
+            )));
         }
 
         self.edit(&machine, |d| {
-            use crate::xml::{NetworkInterface, NetworkSource};
+            use crate::xml::{Alias, NetworkInterface, NetworkSource};
 
             d.devices.interfaces.push(NetworkInterface {
                 typ: "network".to_string(),
                 source: Some(NetworkSource {
-                    network: Some("istruct_nat".into()),
+                    network: Some(NAT_NETWORK_NAME.into()),
 
                     bridge: None,
                 }),
+                alias: Some(Alias::user(device)),
 
                 mac: None,
                 model: None,
@@ -725,41 +1232,83 @@ impl Client {
         Ok(())
     }
 
-    fn detach_nat(&self, machine: MachineId, device: DeviceId) -> anyhow::Result<()> {
+    fn detach_nat(&self, machine: MachineId, device: DeviceId) -> Result<()> {
         self.db()
             .get_dev_type(&device)
-            .ok_or(anyhow::anyhow!("could not find device"))
+            .ok_or_else(|| Error::not_found("could not find device"))
             .and_then(|t| {
                 if let DeviceType::Network(NetworkDeviceType::Nat) = t {
                     Ok(())
                 } else {
-                    Err(anyhow::anyhow!("device was not a nat network"))
+                    Err(Error::invalid_argument("device was not a nat network"))
                 }
             })?;
 
         if let Some(m) = self.db().get_dev_attached(&device) {
             if m != machine {
-                anyhow::bail!(
+                return Err(Error::invalid_state(format!(
                     "device is not attached to {}, it is attached to {}",
-                    machine,
-                    m
-                );
+                    machine, m
+                )));
             }
         } else {
-            anyhow::bail!("device is not attached");
+            return Err(Error::invalid_state("device is not attached"));
         }
 
-        self.edit(machine, |d| {
-            d.devices.interfaces.retain(|i| {
-                if let Some(source) = &i.source {
-                    if let Some(network) = &source.network {
-                        return network.as_str() != "istruct_nat";
+        let mut domain = self
+            .get_domain_xml(machine)
+            .ok_or_else(|| Error::not_found("machine does not exist"))?;
+
+        let alias = crate::xml::Alias::user(device);
+        let interfaces = &mut domain.devices.interfaces;
+
+        let position = match interfaces
+            .iter()
+            .position(|i| i.alias.as_ref() == Some(&alias))
+        {
+            Some(position) => Some(position),
+            // interfaces attached before aliases were set, only told apart when there's one
+            None => {
+                let db = self.db();
+
+                let unaliased_devices = db
+                    .get_dev_attached_to(machine)
+                    .filter(|&d| {
+                        matches!(
+                            db.get_dev_type(d),
+                            Some(DeviceType::Network(NetworkDeviceType::Nat))
+                        )
+                    })
+                    .filter(|&d| {
+                        let alias = crate::xml::Alias::user(d);
+                        !interfaces.iter().any(|i| i.alias.as_ref() == Some(&alias))
+                    })
+                    .count();
+
+                let mut unaliased = interfaces
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, i)| i.alias.is_none() && is_nat_interface(i))
+                    .map(|(position, _)| position);
+
+                match (unaliased.next(), unaliased.next()) {
+                    (None, _) => None,
+                    (Some(position), None) if unaliased_devices == 1 => Some(position),
+                    _ => {
+                        return Err(Error::invalid_state(format!(
+                            "can't tell which interface of {} belongs to {}",
+                            machine, device
+                        )))
                     }
                 }
+            }
+        };
 
-                true
-            })
-        })?;
+        if let Some(position) = position {
+            interfaces.remove(position);
+        }
+
+        self.define_domain(domain)?;
 
         self.db().del_dev_attached(device);
 
@@ -770,95 +1319,334 @@ impl Client {
 // block device file functions
 impl Client {
     fn path_for_block_device(&self, dev: impl Borrow<DeviceId>) -> std::path::PathBuf {
-        self.block_path_dir
-            .join(format!("block_{}.qcow2", dev.borrow()))
+        // adopted images keep their paths
+        match self.db().get_dev_block_path(dev.borrow()) {
+            Some(path) => PathBuf::from(path),
+            None => self
+                .block_path_dir
+                .join(format!("block_{}.qcow2", dev.borrow())),
+        }
+    }
+
+    /// Images outside of the block directory are only referenced, and never deleted.
+    ///
+    /// The path is canonicalized first, `..` and symlinks can't lead out of the directory.
+    fn owns_block_file(&self, path: impl AsRef<Path>) -> bool {
+        path.as_ref()
+            .canonicalize()
+            .is_ok_and(|path| path.starts_with(&self.block_path_dir))
     }
 
     fn create_block_file(&self, dev: impl Borrow<DeviceId>, bytes: u64) -> anyhow::Result<()> {
-        use std::process::Command;
+        self.backend
+            .images
+            .create(&self.path_for_block_device(dev), bytes)
+    }
 
-        Command::new("qemu-img")
-            .arg("create")
-            .arg("-f")
-            .arg("qcow2")
-            .arg(self.path_for_block_device(dev))
-            .arg(bytes.to_string())
-            .output()?;
+    fn copy_block_file(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.backend.images.copy(from.as_ref(), to.as_ref())
+    }
 
-        Ok(())
+    fn block_file_bytes(&self, path: impl AsRef<Path>) -> anyhow::Result<u64> {
+        self.backend.images.bytes(path.as_ref())
     }
 
-    fn delete_block_file(&self, dev: impl Borrow<DeviceId>) -> anyhow::Result<()> {
-        std::fs::remove_file(self.path_for_block_device(dev)).map_err(Into::into)
+    fn delete_block_file(&self, dev: impl Borrow<DeviceId>) -> Result<()> {
+        let path = self.path_for_block_device(dev);
+
+        if !self.owns_block_file(&path) {
+            return Ok(());
+        }
+
+        std::fs::remove_file(path)
+            .map_err(|e| Error::backend(format!("failed to delete block file: {}", e)))
     }
 }
 
-// todo: temp CD methods
-// fixme: detach_block can remove a cd drive if it has the same UUID
-// impl Client {
-//     pub fn get_cd(&self, machine: MachineId) -> Option<String> {
-//         let d = self.get_domain_xml(machine)?;
-
-//         for disk in &d.devices.disks {
-//             if let Some(crate::xml::DiskDevice::CDROM) = disk.device {
-//                 return disk.source.as_ref().and_then(|s| s.file.to_owned());
-//             }
-//         }
-
-//         None
-//     }
-
-//     pub fn set_cd(&self, machine: MachineId, path: String) -> anyhow::Result<()> {
-//         self.edit(machine, |d| {
-//             for disk in &mut d.devices.disks {
-//                 if let Some(crate::xml::DiskDevice::CDROM) = disk.device {
-//                     disk.source = Some(crate::xml::Source::file(path));
-
-//                     break;
-//                 }
-//             }
-//         })
-//     }
-
-//     pub fn rm_cd(&self, machine: MachineId) -> anyhow::Result<()> {
-//         self.edit(machine, |d| {
-//             for disk in &mut d.devices.disks {
-//                 if let Some(crate::xml::DiskDevice::CDROM) = disk.device {
-//                     disk.source = None;
-
-//                     return;
-//                 }
-//             }
-//         })
-//     }
-// }
+// device functions specific to removable media
+impl Client {
+    fn check_cdrom(&self, device: &DeviceId) -> Result<()> {
+        self.db()
+            .get_dev_type(device)
+            .ok_or_else(|| Error::not_found("could not find device"))
+            .and_then(|t| {
+                if let DeviceType::Storage(StorageDeviceType::Cdrom) = t {
+                    Ok(())
+                } else {
+                    Err(Error::invalid_argument("device was not a cdrom"))
+                }
+            })
+    }
+
+    fn check_media(media: &str) -> Result<()> {
+        let path = Path::new(media);
+
+        if !path.is_absolute() {
+            return Err(Error::invalid_argument("media path has to be absolute"));
+        }
+
+        if !path.is_file() {
+            return Err(Error::invalid_argument(format!(
+                "media {} does not exist",
+                media
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn create_cdrom(&self, media: Option<String>) -> Result<DeviceId> {
+        if let Some(media) = &media {
+            Self::check_media(media)?;
+        }
+
+        let uuid = Uuid::new_v4();
+
+        let db = self.db();
+
+        db.set_dev_type(uuid, DeviceType::Storage(StorageDeviceType::Cdrom));
+
+        if let Some(media) = media {
+            db.set_dev_cdrom_media(uuid, media);
+        }
+
+        Ok(uuid)
+    }
+
+    fn delete_cdrom(&self, device: DeviceId) -> Result<()> {
+        self.check_cdrom(&device)?;
+
+        if let Some(machine) = self.db().get_dev_attached(device) {
+            return Err(Error::already_attached(format!(
+                "device is attached to {}",
+                machine
+            )));
+        }
+
+        let db = self.db();
+
+        db.del_dev_type(device);
+        db.del_dev_cdrom_media(device);
+        db.del_generation(device);
+
+        Ok(())
+    }
+
+    fn get_cdrom_media(&self, device: DeviceId) -> Option<Option<String>> {
+        self.check_cdrom(&device).ok()?;
+
+        Some(self.db().get_dev_cdrom_media(device))
+    }
+
+    fn cdrom_disk(&self, device: DeviceId, dev: String, boot: Option<usize>) -> crate::xml::Disk {
+        use crate::xml::{Alias, Disk, DiskBoot, DiskDevice, DiskTarget, DiskType, Empty, Source};
+
+        Disk {
+            r#type: DiskType::File,
+            device: Some(DiskDevice::CDROM),
+            driver: None,
+            source: self.db().get_dev_cdrom_media(device).map(Source::file),
+            target: Some(DiskTarget {
+                dev,
+                bus: Some("sata".into()),
+
+                tray: None,
+                removable: None,
+                rotation_rate: None,
+            }),
+            boot: boot.map(|order| DiskBoot {
+                order,
+
+                loadparm: None,
+            }),
+            readonly: vec![Empty::new()],
+            alias: Some(Alias::user(device)),
+
+            shareable: vec![],
+            serial: None,
+            wwn: None,
+            vendor: None,
+            product: None,
+            address: None,
+        }
+    }
+
+    fn set_cdrom_media(&self, device: DeviceId, media: Option<String>) -> Result<()> {
+        self.check_cdrom(&device)?;
+
+        if let Some(media) = &media {
+            Self::check_media(media)?;
+        }
+
+        let db = self.db();
+
+        let old = db.get_dev_cdrom_media(device);
+
+        match &media {
+            Some(media) => db.set_dev_cdrom_media(device, media.clone()),
+            None => db.del_dev_cdrom_media(device),
+        }
+
+        let res = match db.get_dev_attached(device) {
+            Some(machine) => self.swap_attached_media(machine, device),
+            None => Ok(()),
+        };
+
+        match res {
+            Ok(()) => db.bump_generation(device),
+            Err(_) => match old {
+                Some(old) => db.set_dev_cdrom_media(device, old),
+                None => db.del_dev_cdrom_media(device),
+            },
+        }
+
+        res
+    }
+
+    fn swap_attached_media(&self, machine: MachineId, device: DeviceId) -> Result<()> {
+        use crate::xml::Alias;
+
+        let alias = Alias::user(device);
+
+        let dom = self
+            .get_domain_xml(machine)
+            .ok_or_else(|| Error::not_found("machine does not exist"))?;
+
+        let (dev, boot) = dom
+            .devices
+            .disks
+            .iter()
+            .find(|d| d.alias.as_ref() == Some(&alias))
+            .map(|d| {
+                let dev = d.target.as_ref().map(|t| t.dev.clone()).unwrap_or_default();
+                (dev, d.boot.as_ref().map(|b| b.order))
+            })
+            .ok_or_else(|| {
+                Error::backend(format!(
+                    "cdrom {} is missing from machine {}",
+                    device, machine
+                ))
+            })?;
+
+        // live first, a failed live change then leaves the config as it was
+        if let Some(
+            MachineState::Running
+            | MachineState::Suspended
+            | MachineState::Starting
+            | MachineState::ShuttingDown
+            | MachineState::PmSuspended
+            | MachineState::Blocked,
+        ) = self.get_status(machine).map(|s| s.state)
+        {
+            let domain = self
+                .get_domain(machine)
+                .ok_or_else(|| Error::not_found("machine does not exist"))?;
+
+            domain
+                .update_device_flags(
+                    &self
+                        .cdrom_disk(device, dev.clone(), boot)
+                        .to_string()
+                        .map_err(anyhow::Error::from)?,
+                    virt::domain::VIR_DOMAIN_AFFECT_LIVE,
+                )
+                .map_err(anyhow::Error::from)?;
+        }
+
+        self.edit(machine, |d| {
+            for disk in &mut d.devices.disks {
+                if disk.alias.as_ref() == Some(&alias) {
+                    *disk = self.cdrom_disk(device, dev.clone(), boot);
+                }
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn attach_cdrom(&self, machine: MachineId, device: DeviceId) -> Result<()> {
+        self.check_cdrom(&device)?;
+
+        if let Some(m) = self.db().get_dev_attached(device) {
+            return Err(Error::already_attached(format!(
+                "device is attached to {}",
+                m
+            )));
+        }
+
+        self.edit(machine, |d| {
+            let dev = calculate_next_dev(&d.devices.disks, "sd");
+
+            // cdroms boot before anything else, in order of attachment
+            let boot = d
+                .devices
+                .disks
+                .iter()
+                .filter_map(|disk| disk.boot.as_ref().map(|b| b.order))
+                .max()
+                .unwrap_or(0)
+                + 1;
+
+            d.devices
+                .disks
+                .push(self.cdrom_disk(device, dev, Some(boot)));
+        })?;
+
+        self.db().set_dev_attached(device, machine);
+
+        Ok(())
+    }
+
+    fn detach_cdrom(&self, machine: MachineId, device: DeviceId) -> Result<()> {
+        self.check_cdrom(&device)?;
+
+        let alias = crate::xml::Alias::user(device);
+
+        self.edit(machine, |d| {
+            d.devices
+                .disks
+                .retain(|disk| disk.alias.as_ref() != Some(&alias))
+        })?;
+
+        self.db().del_dev_attached(device);
+
+        Ok(())
+    }
+}
 
 const KNOWN_MACHINES: &str = "known_machines";
 const DEV_MACHINE_ATTACHED: &str = "dev_machine_attached";
+const MACHINE_DEVICES: &str = "machine_devices";
 const DEV_TYPE: &str = "dev_type";
 const DEV_CPU: &str = "dev_cpu";
 const DEV_MEM: &str = "dev_mem";
 const DEV_BLOCK_CAPACITY: &str = "dev_block_capacity";
+const DEV_BLOCK_PATH: &str = "dev_block_path";
+const MACHINE_ATTRS: &str = "machine_attrs";
+const DEV_CDROM_MEDIA: &str = "dev_cdrom_media";
+const GENERATIONS: &str = "generations";
+const LOST_MACHINES: &str = "lost_machines";
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum DeviceType {
     Compute(ComputeDeviceType),
     Storage(StorageDeviceType),
     Network(NetworkDeviceType),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum ComputeDeviceType {
     Cpu,
     Mem,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum StorageDeviceType {
     Block,
+    Cdrom,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum NetworkDeviceType {
     Nat,
 }
@@ -871,11 +1659,13 @@ impl DeviceType {
             "is.compute.cpu" => Self::Compute(C::Cpu),
             "is.compute.mem" => Self::Compute(C::Mem),
             "is.storage.block" => Self::Storage(S::Block),
+            "is.storage.cdrom" => Self::Storage(S::Cdrom),
             "is.network.nat" => Self::Network(N::Nat),
             _ => return None,
         })
     }
 
+    /// These are stored in the database, renaming one takes a [`schema`] step.
     fn to_string(self) -> String {
         use {ComputeDeviceType as C, NetworkDeviceType as N, StorageDeviceType as S};
 
@@ -883,6 +1673,7 @@ impl DeviceType {
             DeviceType::Compute(C::Cpu) => "is.compute.cpu",
             DeviceType::Compute(C::Mem) => "is.compute.mem",
             DeviceType::Storage(S::Block) => "is.storage.block",
+            DeviceType::Storage(S::Cdrom) => "is.storage.cdrom",
             DeviceType::Network(N::Nat) => "is.network.nat",
         }
         .to_string()
@@ -891,16 +1682,56 @@ impl DeviceType {
 
 impl Client {
     fn db(&self) -> ClientDB<'_> {
-        return ClientDB(self);
+        ClientDB {
+            store: Store::Persy(&self.persy),
+        }
+    }
+
+    /// Runs `f` with a [`ClientDB`] whose changes are all committed at once if `f` succeeds,
+    /// and rolled back if it fails.
+    fn transaction<R>(&self, f: impl FnOnce(&ClientDB<'_>) -> Result<R>) -> Result<R> {
+        let tx = RefCell::new(self.persy.begin().map_err(db_error)?);
+
+        let res = f(&ClientDB {
+            store: Store::Tx(&tx),
+        });
+
+        let tx = tx.into_inner();
+
+        match res {
+            Ok(r) => {
+                tx.prepare().map_err(db_error)?.commit().map_err(db_error)?;
+
+                Ok(r)
+            }
+            Err(e) => {
+                tx.rollback().map_err(db_error)?;
+
+                Err(e)
+            }
+        }
     }
 }
 
+fn db_error(e: impl std::fmt::Display) -> Error {
+    Error::backend(format!("database failure: {}", e))
+}
+
+/// Where a [`ClientDB`] reads from and writes to.
+#[derive(Clone, Copy)]
+enum Store<'a> {
+    /// Every change is committed by itself.
+    Persy(&'a Persy),
+    /// Changes are collected in a transaction, reads see them.
+    Tx(&'a RefCell<Transaction>),
+}
+
 struct PersyInterface<'a, K, V>
 where
     K: IndexType,
     V: IndexType,
 {
-    persy: &'a Persy,
+    store: Store<'a>,
     name: &'static str,
 
     key: PhantomData<K>,
@@ -916,40 +1747,89 @@ where
     where
         Q: Borrow<K>,
     {
-        self.persy
-            .get::<K, V>(self.name, key.borrow())
-            .unwrap()
-            .next()
+        match self.store {
+            Store::Persy(persy) => persy.one::<K, V>(self.name, key.borrow()).unwrap(),
+            Store::Tx(tx) => tx
+                .borrow_mut()
+                .one::<K, V>(self.name, key.borrow())
+                .unwrap(),
+        }
     }
 
-    fn range<R>(&self, range: R) -> impl Iterator<Item = (K, V)>
+    fn range<R>(&self, range: R) -> Box<dyn Iterator<Item = (K, V)>>
     where
+        K: 'static,
+        V: 'static,
         R: std::ops::RangeBounds<K>,
     {
-        self.persy
-            .range::<K, V, _>(&self.name, range)
-            .unwrap()
-            .filter_map(move |(k, mut v)| v.next().map(|v| (k, v)))
+        match self.store {
+            Store::Persy(persy) => Box::new(
+                persy
+                    .range::<K, V, _>(self.name, range)
+                    .unwrap()
+                    .filter_map(move |(k, mut v)| v.next().map(|v| (k, v))),
+            ),
+            // the iterator borrows the transaction, so this can't be lazy
+            Store::Tx(tx) => Box::new(
+                tx.borrow_mut()
+                    .range::<K, V, _>(self.name, range)
+                    .unwrap()
+                    .filter_map(move |(k, mut v)| v.next().map(|v| (k, v)))
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            ),
+        }
     }
 
-    fn set(&self, key: K, val: V) {
-        let mut tx = self.begin();
-        tx.put::<K, V>(&self.name, key, val).unwrap();
-        tx.prepare().unwrap().commit().unwrap();
+    /// Every value of `key`, for indexes with more than one value per key.
+    fn get_all<Q>(&self, key: Q) -> Vec<V>
+    where
+        Q: Borrow<K>,
+    {
+        match self.store {
+            Store::Persy(persy) => persy
+                .get::<K, V>(self.name, key.borrow())
+                .unwrap()
+                .collect(),
+            Store::Tx(tx) => tx
+                .borrow_mut()
+                .get::<K, V>(self.name, key.borrow())
+                .unwrap()
+                .collect(),
+        }
     }
 
-    fn begin(&self) -> persy::Transaction {
-        self.persy.begin().unwrap()
+    /// Replaces the value of `key`, or adds to its values for indexes with more than one.
+    fn set(&self, key: K, val: V) {
+        self.change(|tx| tx.put::<K, V>(self.name, key, val).unwrap())
     }
 
     fn del(&self, key: K) {
-        let mut tx = self.begin();
-        tx.remove::<K, V>(&self.name, key, None).unwrap();
-        tx.prepare().unwrap().commit().unwrap();
+        self.change(|tx| tx.remove::<K, V>(self.name, key, None).unwrap())
+    }
+
+    /// Removes a single value of `key`.
+    fn del_value(&self, key: K, val: V) {
+        self.change(|tx| tx.remove::<K, V>(self.name, key, Some(val)).unwrap())
+    }
+
+    fn change(&self, f: impl FnOnce(&mut Transaction)) {
+        match self.store {
+            Store::Persy(persy) => {
+                let mut tx = persy.begin().unwrap();
+                f(&mut tx);
+                tx.prepare().unwrap().commit().unwrap();
+            }
+            Store::Tx(tx) => f(&mut tx.borrow_mut()),
+        }
     }
 }
 
-pub struct ClientDB<'c>(&'c Client);
+/// Typed access to the database, either committing every change by itself ([`Client::db`]),
+/// or as part of a transaction ([`Client::transaction`]).
+pub struct ClientDB<'c> {
+    store: Store<'c>,
+}
 
 // Persy Interface
 impl ClientDB<'_> {
@@ -959,7 +1839,7 @@ impl ClientDB<'_> {
         V: IndexType,
     {
         PersyInterface {
-            persy: &self.0.persy,
+            store: self.store,
             name,
             key: PhantomData,
             value: PhantomData,
@@ -967,6 +1847,27 @@ impl ClientDB<'_> {
     }
 }
 
+// Batches
+impl ClientDB<'_> {
+    /// Runs `f` with every change committed at once, or as part of the running transaction.
+    fn atomically<R>(&self, f: impl FnOnce(&ClientDB<'_>) -> R) -> R {
+        match self.store {
+            Store::Persy(persy) => {
+                let tx = RefCell::new(persy.begin().unwrap());
+
+                let r = f(&ClientDB {
+                    store: Store::Tx(&tx),
+                });
+
+                tx.into_inner().prepare().unwrap().commit().unwrap();
+
+                r
+            }
+            Store::Tx(_) => f(self),
+        }
+    }
+}
+
 // Device Type
 impl ClientDB<'_> {
     fn dev_type(&self) -> PersyInterface<'_, u128, ByteVec> {
@@ -1018,23 +1919,37 @@ impl ClientDB<'_> {
         &self,
         machine: impl Borrow<MachineId>,
     ) -> impl Iterator<Item = DeviceId> {
-        let m = machine.borrow().as_u128();
-
-        self.dev_attach().range(..).filter_map(move |(k, v)| {
-            if v == m {
-                Some(Uuid::from_u128(k))
-            } else {
-                None
-            }
-        })
+        self.machine_devices()
+            .get_all(machine.borrow().as_u128())
+            .into_iter()
+            .map(Uuid::from_u128)
     }
 
     fn set_dev_attached(&self, dev: DeviceId, machine: MachineId) {
-        self.dev_attach().set(dev.as_u128(), machine.as_u128())
+        self.atomically(|db| {
+            db.del_dev_attached(dev);
+
+            db.dev_attach().set(dev.as_u128(), machine.as_u128());
+            db.machine_devices().set(machine.as_u128(), dev.as_u128());
+        })
     }
 
     fn del_dev_attached(&self, dev: DeviceId) {
-        self.dev_attach().del(dev.as_u128())
+        self.atomically(|db| {
+            if let Some(machine) = db.get_dev_attached(dev) {
+                db.machine_devices()
+                    .del_value(machine.as_u128(), dev.as_u128());
+            }
+
+            db.dev_attach().del(dev.as_u128())
+        })
+    }
+}
+
+// Machine Devices, the reverse of Device Attach
+impl ClientDB<'_> {
+    fn machine_devices(&self) -> PersyInterface<'_, u128, u128> {
+        self.interface(MACHINE_DEVICES)
     }
 }
 
@@ -1076,6 +1991,21 @@ impl ClientDB<'_> {
     }
 }
 
+// Compute devices
+impl ClientDB<'_> {
+    /// Deletes every record of a cpu or memory device.
+    fn del_compute_device(&self, dev: DeviceId, typ: ComputeDeviceType) {
+        self.del_dev_type(dev);
+        self.del_dev_attached(dev);
+        self.del_generation(dev);
+
+        match typ {
+            ComputeDeviceType::Cpu => self.del_dev_cpu(dev),
+            ComputeDeviceType::Mem => self.del_dev_mem(dev),
+        }
+    }
+}
+
 // Storage device
 impl ClientDB<'_> {
     fn dev_block_cap(&self) -> PersyInterface<'_, u128, u64> {
@@ -1095,7 +2025,45 @@ impl ClientDB<'_> {
     }
 }
 
-// Storage device
+// Block device path, only set for adopted images
+impl ClientDB<'_> {
+    fn dev_block_path(&self) -> PersyInterface<'_, u128, String> {
+        self.interface(DEV_BLOCK_PATH)
+    }
+
+    fn get_dev_block_path(&self, dev: impl Borrow<DeviceId>) -> Option<String> {
+        self.dev_block_path().get(dev.borrow().as_u128())
+    }
+
+    fn set_dev_block_path(&self, dev: DeviceId, path: String) {
+        self.dev_block_path().set(dev.as_u128(), path)
+    }
+
+    fn del_dev_block_path(&self, dev: DeviceId) {
+        self.dev_block_path().del(dev.as_u128())
+    }
+}
+
+// Cdrom device
+impl ClientDB<'_> {
+    fn dev_cdrom_media(&self) -> PersyInterface<'_, u128, String> {
+        self.interface(DEV_CDROM_MEDIA)
+    }
+
+    fn get_dev_cdrom_media(&self, dev: impl Borrow<DeviceId>) -> Option<String> {
+        self.dev_cdrom_media().get(dev.borrow().as_u128())
+    }
+
+    fn set_dev_cdrom_media(&self, dev: DeviceId, media: String) {
+        self.dev_cdrom_media().set(dev.as_u128(), media)
+    }
+
+    fn del_dev_cdrom_media(&self, dev: DeviceId) {
+        self.dev_cdrom_media().del(dev.as_u128())
+    }
+}
+
+// Known machines
 impl ClientDB<'_> {
     fn known_machines(&self) -> PersyInterface<'_, u128, u8> {
         self.interface(KNOWN_MACHINES)
@@ -1114,39 +2082,195 @@ impl ClientDB<'_> {
     fn del_known_machine(&self, machine: MachineId) {
         self.known_machines().del(machine.as_u128())
     }
+
+    fn all_known_machines(&self) -> impl Iterator<Item = MachineId> {
+        self.known_machines()
+            .range(..)
+            .map(|(k, _)| Uuid::from_u128(k))
+    }
 }
 
-fn virt_domainstate_to_istruct(state: DomainState) -> MachineState {
+// Lost machines
+impl ClientDB<'_> {
+    fn lost_machines(&self) -> PersyInterface<'_, u128, u8> {
+        self.interface(LOST_MACHINES)
+    }
+
+    fn is_lost_machine(&self, machine: impl Borrow<MachineId>) -> bool {
+        self.lost_machines()
+            .get(machine.borrow().as_u128())
+            .is_some()
+    }
+
+    fn set_lost_machine(&self, machine: MachineId) {
+        self.lost_machines().set(machine.as_u128(), 0)
+    }
+
+    fn del_lost_machine(&self, machine: MachineId) {
+        self.lost_machines().del(machine.as_u128())
+    }
+}
+
+// Machine attributes
+impl ClientDB<'_> {
+    fn machine_attrs(&self) -> PersyInterface<'_, String, ByteVec> {
+        self.interface(MACHINE_ATTRS)
+    }
+
+    fn attr_key(machine: &MachineId, attr: &str) -> String {
+        format!("{}/{}", machine, attr)
+    }
+
+    fn get_machine_attr(&self, machine: impl Borrow<MachineId>, attr: &str) -> Option<AttrValue> {
+        self.machine_attrs()
+            .get(Self::attr_key(machine.borrow(), attr))
+            .map(|v| {
+                serde_json::from_slice(&Vec::from(v)).expect("database has valid attribute value")
+            })
+    }
+
+    fn get_machine_attrs(
+        &self,
+        machine: impl Borrow<MachineId>,
+    ) -> impl Iterator<Item = (String, AttrValue)> {
+        let machine = machine.borrow();
+
+        // '0' sorts right after '/', so this ranges over every "{machine}/..." key
+        self.machine_attrs()
+            .range(format!("{}/", machine)..format!("{}0", machine))
+            .map(|(k, v)| {
+                let (_, attr) = k.split_once('/').expect("attribute key has a slash");
+
+                (
+                    attr.to_string(),
+                    serde_json::from_slice(&Vec::from(v))
+                        .expect("database has valid attribute value"),
+                )
+            })
+    }
+
+    fn set_machine_attr(&self, machine: impl Borrow<MachineId>, attr: &str, value: &AttrValue) {
+        self.machine_attrs().set(
+            Self::attr_key(machine.borrow(), attr),
+            serde_json::to_vec(value)
+                .expect("attribute values serialize")
+                .into(),
+        )
+    }
+
+    fn del_machine_attr(&self, machine: impl Borrow<MachineId>, attr: &str) {
+        self.machine_attrs()
+            .del(Self::attr_key(machine.borrow(), attr))
+    }
+}
+
+// Generations
+impl ClientDB<'_> {
+    fn generations(&self) -> PersyInterface<'_, u128, u64> {
+        self.interface(GENERATIONS)
+    }
+
+    /// Machines and devices start at generation 0.
+    fn get_generation(&self, resource: impl Borrow<Uuid>) -> u64 {
+        self.generations()
+            .get(resource.borrow().as_u128())
+            .unwrap_or(0)
+    }
+
+    /// Has to be called on every change of a machine or device, changing its etag.
+    fn bump_generation(&self, resource: impl Borrow<Uuid>) {
+        let resource = resource.borrow();
+        let next = self.get_generation(resource) + 1;
+
+        self.generations().set(resource.as_u128(), next)
+    }
+
+    fn del_generation(&self, resource: impl Borrow<Uuid>) {
+        self.generations().del(resource.borrow().as_u128())
+    }
+}
+
+fn virt_domainstate_to_istruct(state: DomainState, reason: i32) -> MachineStatus {
     use virt::domain::{
         VIR_DOMAIN_BLOCKED, VIR_DOMAIN_CRASHED, VIR_DOMAIN_NOSTATE, VIR_DOMAIN_PAUSED,
         VIR_DOMAIN_PMSUSPENDED, VIR_DOMAIN_RUNNING, VIR_DOMAIN_SHUTDOWN, VIR_DOMAIN_SHUTOFF,
     };
-    match state {
+
+    // virDomain*Reason, in order
+    let reasons: &[&str] = match state {
+        VIR_DOMAIN_RUNNING => &[
+            "unknown",
+            "booted",
+            "migrated",
+            "restored",
+            "from_snapshot",
+            "unpaused",
+            "migration_canceled",
+            "save_canceled",
+            "wakeup",
+            "crashed",
+            "postcopy",
+            "postcopy_failed",
+        ],
+        VIR_DOMAIN_PAUSED => &[
+            "unknown",
+            "user",
+            "migration",
+            "save",
+            "dump",
+            "ioerror",
+            "watchdog",
+            "from_snapshot",
+            "shutting_down",
+            "snapshot",
+            "api_error",
+            "starting_up",
+            "postcopy",
+            "postcopy_failed",
+        ],
+        VIR_DOMAIN_SHUTDOWN => &["unknown", "user"],
+        VIR_DOMAIN_SHUTOFF => &[
+            "unknown",
+            "shutdown",
+            "destroyed",
+            "crashed",
+            "migrated",
+            "saved",
+            "failed",
+            "from_snapshot",
+            "daemon",
+        ],
+        VIR_DOMAIN_CRASHED => &["unknown", "panicked"],
+        VIR_DOMAIN_PMSUSPENDED => &["unknown"],
+        _ => &[],
+    };
+
+    let reason_str = usize::try_from(reason)
+        .ok()
+        .and_then(|r| reasons.get(r))
+        .copied();
+
+    let state = match state {
         VIR_DOMAIN_NOSTATE => MachineState::Off,
         VIR_DOMAIN_RUNNING => MachineState::Running,
-        // todo technically not correct
-        VIR_DOMAIN_BLOCKED => MachineState::Error,
+        VIR_DOMAIN_BLOCKED => MachineState::Blocked,
+        VIR_DOMAIN_PAUSED if reason_str == Some("starting_up") => MachineState::Starting,
         VIR_DOMAIN_PAUSED => MachineState::Suspended,
-        // todo technically not correct, add "transitioning" state?
-        VIR_DOMAIN_SHUTDOWN => MachineState::Running,
+        VIR_DOMAIN_SHUTDOWN => MachineState::ShuttingDown,
         VIR_DOMAIN_SHUTOFF => MachineState::Off,
-        VIR_DOMAIN_CRASHED => MachineState::Error,
-        // todo watch this state when resuming
-        VIR_DOMAIN_PMSUSPENDED => MachineState::Suspended,
-        unknown => panic!("Unknown DomainState {}", unknown),
-    }
-}
+        VIR_DOMAIN_CRASHED => MachineState::Crashed,
+        VIR_DOMAIN_PMSUSPENDED => MachineState::PmSuspended,
+        unknown => {
+            return MachineStatus {
+                state: MachineState::Error,
+                reason: Some(format!("unknown libvirt state {}", unknown)),
+            }
+        }
+    };
 
-fn test_or_create_index<K, V>(persy: &Persy, name: &str)
-where
-    K: IndexType,
-    V: IndexType,
-{
-    if !persy.exists_index(name).unwrap() {
-        let mut tx = persy.begin().unwrap();
-        tx.create_index::<K, V>(name, persy::ValueMode::Replace)
-            .unwrap();
-        tx.prepare().unwrap().commit().unwrap();
+    MachineStatus {
+        state,
+        reason: reason_str.filter(|&r| r != "unknown").map(str::to_string),
     }
 }
 
@@ -1155,13 +2279,13 @@ static ASCII_LOWER: [char; 26] = [
     't', 'u', 'v', 'w', 'x', 'y', 'z',
 ];
 
-fn calculate_next_vd(disks: &[crate::xml::Disk]) -> String {
+fn calculate_next_dev(disks: &[crate::xml::Disk], prefix: &str) -> String {
     let mut seen = vec![];
 
     for disk in disks {
         if let Some(t) = &disk.target {
-            if t.dev.starts_with("vd") {
-                seen.push(t.dev[2..].to_string())
+            if let Some(suffix) = t.dev.strip_prefix(prefix) {
+                seen.push(suffix.to_string())
             }
         }
     }
@@ -1186,5 +2310,5 @@ fn calculate_next_vd(disks: &[crate::xml::Disk]) -> String {
         }
     }
 
-    format!("vd{}", current)
+    format!("{}{}", prefix, current)
 }
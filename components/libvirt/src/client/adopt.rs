@@ -0,0 +1,191 @@
+//! Turns domains that weren't created through istruct into machines.
+
+use std::{collections::HashMap, path::Path};
+
+use istruct_common::{
+    api::compute::machine::v1::{ExternalDisks, MachineEvent, MachineState},
+    error::{Error, Result},
+    id::{DeviceId, MachineId},
+};
+use uuid::Uuid;
+
+use super::{
+    is_nat_interface, Client, ComputeDeviceType, DeviceType, NetworkDeviceType, StorageDeviceType,
+};
+use crate::xml::{Alias, DiskDevice, DiskType};
+
+/// A device found in the domain, registered once everything was checked.
+enum Found {
+    Block { path: String, bytes: u64 },
+    Cdrom { media: Option<String> },
+    Nat,
+}
+
+impl Client {
+    pub fn adoptable(&self) -> Vec<MachineId> {
+        let db = self.db();
+
+        self.list()
+            .into_iter()
+            .filter(|m| !db.is_known_machine(m))
+            .collect()
+    }
+
+    pub fn adopt(
+        &self,
+        machine: MachineId,
+        external_disks: ExternalDisks,
+    ) -> Result<HashMap<DeviceId, String>> {
+        if self.db().is_known_machine(machine) {
+            return Err(Error::invalid_state("machine is already known"));
+        }
+
+        let status = self
+            .get_status(machine)
+            .ok_or_else(|| Error::not_found("machine does not exist"))?;
+
+        // disk aliases are only picked up on boot
+        if status.state != MachineState::Off {
+            return Err(Error::invalid_state("machine has to be off to be adopted"));
+        }
+
+        let mut domain = self
+            .get_domain_xml(machine)
+            .ok_or_else(|| Error::not_found("machine does not exist"))?;
+
+        let mut found = vec![];
+
+        for disk in &mut domain.devices.disks {
+            let file = disk.source.as_ref().and_then(|s| s.file.clone());
+
+            let device = match disk.device {
+                Some(DiskDevice::CDROM) => Found::Cdrom { media: file },
+                Some(DiskDevice::Disk) | None => {
+                    let qcow2 =
+                        disk.driver.as_ref().and_then(|d| d.r#type.as_deref()) == Some("qcow2");
+
+                    let path = match (&disk.r#type, file) {
+                        (DiskType::File, Some(path)) if qcow2 => path,
+                        // raw images, host devices, network storage, ...
+                        _ => continue,
+                    };
+
+                    let path = self.check_adopted_block(&path, external_disks)?;
+
+                    let bytes = self.block_file_bytes(&path).map_err(|e| {
+                        Error::backend(format!("failed to inspect {}: {:#}", path, e))
+                    })?;
+
+                    Found::Block { path, bytes }
+                }
+                Some(DiskDevice::LUN) => continue,
+            };
+
+            let id = Uuid::new_v4();
+
+            disk.alias = Some(Alias::user(id));
+            found.push((id, device));
+        }
+
+        // interfaces on other networks can't be represented as devices
+        for interface in &mut domain.devices.interfaces {
+            if is_nat_interface(interface) {
+                let id = Uuid::new_v4();
+
+                interface.alias = Some(Alias::user(id));
+                found.push((id, Found::Nat));
+            }
+        }
+
+        let cores = domain.vcpu.amount as u64;
+        let bytes = domain.memory.bytes();
+
+        // the domain is defined last, so the records are rolled back if that fails
+        let devices = self.transaction(|db| {
+            let mut devices = HashMap::new();
+
+            let mut register = |id: DeviceId, typ: DeviceType| {
+                db.set_dev_attached(id, machine);
+                devices.insert(id, typ.to_string());
+                db.set_dev_type(id, typ);
+            };
+
+            let cpu = Uuid::new_v4();
+            register(cpu, DeviceType::Compute(ComputeDeviceType::Cpu));
+            db.set_dev_cpu(cpu, cores);
+
+            let mem = Uuid::new_v4();
+            register(mem, DeviceType::Compute(ComputeDeviceType::Mem));
+            db.set_dev_mem(mem, bytes);
+
+            for (id, device) in found {
+                match device {
+                    Found::Block { path, bytes } => {
+                        register(id, DeviceType::Storage(StorageDeviceType::Block));
+                        db.set_dev_block_cap(id, bytes);
+                        db.set_dev_block_path(id, path);
+                    }
+                    Found::Cdrom { media } => {
+                        register(id, DeviceType::Storage(StorageDeviceType::Cdrom));
+
+                        if let Some(media) = media {
+                            db.set_dev_cdrom_media(id, media);
+                        }
+                    }
+                    Found::Nat => register(id, DeviceType::Network(NetworkDeviceType::Nat)),
+                }
+            }
+
+            db.set_known_machine(machine);
+
+            self.define_domain(domain)?;
+
+            Ok(devices)
+        })?;
+
+        self.emit(MachineEvent::Created { machine });
+
+        Ok(devices)
+    }
+
+    /// Returns the canonical path of the image, which is what gets registered.
+    fn check_adopted_block(&self, path: &str, external_disks: ExternalDisks) -> Result<String> {
+        if !Path::new(path).is_absolute() {
+            return Err(Error::invalid_argument(format!(
+                "disk {} does not have an absolute path",
+                path
+            )));
+        }
+
+        let canonical = Path::new(path)
+            .canonicalize()
+            .ok()
+            .and_then(|p| p.to_str().map(String::from))
+            .ok_or_else(|| Error::invalid_argument(format!("disk {} does not exist", path)))?;
+
+        if !self.owns_block_file(&canonical) && external_disks == ExternalDisks::Reject {
+            return Err(Error::invalid_argument(format!(
+                "disk {} is outside of {}",
+                path,
+                self.block_path_dir.display()
+            )));
+        }
+
+        // e.g. a domain pointing at the image of another machine
+        let db = self.db();
+
+        let owner = db
+            .all_dev_types()
+            .filter(|(_, t)| matches!(t, DeviceType::Storage(StorageDeviceType::Block)))
+            .find(|(d, _)| self.path_for_block_device(d) == Path::new(&canonical));
+
+        if let Some((device, _)) = owner {
+            return Err(Error::already_attached(format!(
+                "disk {} belongs to device {}",
+                path, device
+            )));
+        }
+
+        Ok(canonical)
+    }
+}
@@ -1,21 +1,31 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use async_trait::async_trait;
+use tokio::sync::broadcast::error::RecvError;
 
 use istruct_common::{
     api::{
+        backup::v1::{BackupApi, Export, Import},
         compute::{
             devadm::v1::{DevAdmApi, DeviceType},
             machine::{
                 device::v1::{CpuDevice, MachineDevApi, MemoryDevice},
-                v1::{MachineAction, MachineApi, MachineState},
+                v1::{
+                    Adopt, AttrValue, MachineAction, MachineApi, MachineEvent, MachineSpec,
+                    MachineStatus,
+                },
             },
         },
         network::device::v1::NetworkDevApi,
-        storage::device::v1::{BlockDevice, StorageDevApi},
+        operation::v1::Pending,
+        reconcile::v1::{Reconcile, ReconcileApi, Report},
+        storage::device::v1::{BlockDevice, CdromDevice, StorageDevApi},
         ApiBase,
     },
-    id::{DeviceId, MachineId},
+    error::Result,
+    etag::{IfMatch, Tagged},
+    event::EventStream,
+    id::{DeviceId, MachineId, OperationId},
 };
 
 use super::ClientPuck;
@@ -24,111 +34,319 @@ impl ApiBase for ClientPuck {}
 
 #[async_trait]
 impl MachineApi for ClientPuck {
-    async fn act(&self, machine: MachineId, action: MachineAction) {
-        self.with(|c| c.act_on(machine, action));
+    async fn act(&self, machine: MachineId, action: MachineAction) -> Result<OperationId> {
+        self.start_act(machine, action).await
     }
 
-    async fn status(&self, machine: MachineId) -> Option<MachineState> {
-        self.with(|c| c.get_status(machine))
+    async fn status(&self, machine: MachineId) -> Result<Option<MachineStatus>> {
+        Ok(self.with(move |c| c.get_status(machine)).await)
     }
 
-    async fn dev_attach(&self, machine: MachineId, device: DeviceId) -> anyhow::Result<()> {
-        self.with(|c| c.attach_device(machine, device))
+    async fn get_attr(&self, machine: MachineId, attr: String) -> Result<Tagged<AttrValue>> {
+        self.with(move |c| {
+            c.get_attr(machine, &attr)
+                .map(|v| Tagged::new(v, c.etag(machine)))
+        })
+        .await
     }
 
-    async fn dev_detach(&self, machine: MachineId, device: DeviceId) -> anyhow::Result<()> {
-        self.with(|c| c.detach_device(machine, device))
+    async fn set_attr(
+        &self,
+        machine: MachineId,
+        attr: String,
+        if_match: IfMatch,
+        value: AttrValue,
+    ) -> Result<()> {
+        self.with_locked([machine], move |c| {
+            c.check_etag(machine, &if_match)?;
+            c.set_attr(machine, &attr, value)
+        })
+        .await
     }
 
-    async fn dev_list(&self, machine: MachineId) -> Option<HashMap<DeviceId, String>> {
-        self.with(|c| {
-            let db = c.db();
-            if !db.is_known_machine(&machine) {
-                return None;
-            }
-            Some(
-                db.get_dev_attached_to(machine)
+    async fn delete_attr(&self, machine: MachineId, attr: String, if_match: IfMatch) -> Result<()> {
+        self.with_locked([machine], move |c| {
+            c.check_etag(machine, &if_match)?;
+            c.delete_attr(machine, &attr)
+        })
+        .await
+    }
+
+    async fn get_attrs(&self, machine: MachineId) -> Result<Tagged<BTreeMap<String, AttrValue>>> {
+        self.with(move |c| {
+            c.get_attrs(machine)
+                .map(|v| Tagged::new(v, c.etag(machine)))
+        })
+        .await
+    }
+
+    async fn patch_attrs(
+        &self,
+        machine: MachineId,
+        if_match: IfMatch,
+        attrs: HashMap<String, Option<AttrValue>>,
+    ) -> Result<()> {
+        self.with_locked([machine], move |c| {
+            c.check_etag(machine, &if_match)?;
+            c.patch_attrs(machine, attrs)
+        })
+        .await
+    }
+
+    async fn list_attrs(&self, machine: MachineId) -> Result<Vec<String>> {
+        self.with(move |c| c.get_attrs(machine).map(|a| a.into_keys().collect()))
+            .await
+    }
+
+    async fn dev_attach(
+        &self,
+        machine: MachineId,
+        device: DeviceId,
+        if_match: IfMatch,
+    ) -> Result<()> {
+        self.with_locked([machine, device], move |c| {
+            c.check_etag(machine, &if_match)?;
+            c.attach_device(machine, device)
+        })
+        .await
+    }
+
+    async fn dev_detach(
+        &self,
+        machine: MachineId,
+        device: DeviceId,
+        if_match: IfMatch,
+    ) -> Result<()> {
+        self.with_locked([machine, device], move |c| {
+            c.check_etag(machine, &if_match)?;
+            c.detach_device(machine, device)
+        })
+        .await
+    }
+
+    async fn dev_list(
+        &self,
+        machine: MachineId,
+    ) -> Result<Option<Tagged<HashMap<DeviceId, String>>>> {
+        Ok(self
+            .with(move |c| {
+                let db = c.db();
+                if !db.is_known_machine(machine) {
+                    return None;
+                }
+                let devices = db
+                    .get_dev_attached_to(machine)
                     .filter_map(|d| {
                         c.db()
                             .get_dev_type(d)
                             .map(super::DeviceType::to_string)
                             .map(|t| (d, t))
                     })
-                    .collect(),
-            )
+                    .collect();
+
+                Some(Tagged::new(devices, c.etag(machine)))
+            })
+            .await)
+    }
+
+    async fn create(&self, spec: MachineSpec) -> Result<MachineId> {
+        let devices = spec.devices.clone();
+
+        self.with_locked(devices, move |c| c.create(spec)).await
+    }
+
+    async fn destroy(&self, machine: MachineId, if_match: IfMatch) -> Result<()> {
+        self.with_locked([machine], move |c| {
+            c.check_etag(machine, &if_match)?;
+            c.destroy(machine)
         })
+        .await
     }
 
-    async fn create(&self) -> MachineId {
-        self.with(|c| c.create())
+    async fn list(&self) -> Result<Vec<MachineId>> {
+        self.with(move |c| c.find_domains()).await
     }
 
-    async fn destroy(&self, machine: MachineId) -> anyhow::Result<()> {
-        self.with(|c| c.destroy(machine))
+    async fn adoptable(&self) -> Result<Vec<MachineId>> {
+        Ok(self.with(move |c| c.adoptable()).await)
     }
 
-    async fn list(&self) -> Vec<MachineId> {
-        self.with(|c| c.find_domains().collect())
+    async fn adopt(&self, machine: MachineId, adopt: Adopt) -> Result<HashMap<DeviceId, String>> {
+        self.with_locked([machine], move |c| c.adopt(machine, adopt.external_disks))
+            .await
+    }
+
+    async fn events(&self) -> Result<EventStream<MachineEvent>> {
+        let rx = self.events.subscribe();
+
+        let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    // lagging subscribers skip ahead, they can catch up with /status
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(Box::pin(stream))
     }
 }
 
 #[async_trait]
 impl DevAdmApi for ClientPuck {
-    async fn all(&self) -> HashMap<DeviceId, DeviceType> {
-        self.with(|c| {
-            c.db()
-                .all_dev_types()
-                .map(|(u, t)| (u, t.to_string()))
-                .collect()
-        })
+    async fn all(&self) -> Result<HashMap<DeviceId, DeviceType>> {
+        Ok(self
+            .with(move |c| {
+                c.db()
+                    .all_dev_types()
+                    .map(|(u, t)| (u, t.to_string()))
+                    .collect()
+            })
+            .await)
     }
 
-    async fn get_type(&self, dev: DeviceId) -> Option<DeviceType> {
-        self.with(|c| c.db().get_dev_type(dev).map(|t| t.to_string()))
+    async fn get_type(&self, dev: DeviceId) -> Result<Option<DeviceType>> {
+        Ok(self
+            .with(move |c| c.db().get_dev_type(dev).map(|t| t.to_string()))
+            .await)
     }
 }
 
 #[async_trait]
 impl MachineDevApi for ClientPuck {
-    async fn get_memory(&self, device: DeviceId) -> Option<MemoryDevice> {
-        self.with(|c| c.get_mem_bytes(device).map(|bytes| MemoryDevice { bytes }))
+    async fn get_memory(&self, device: DeviceId) -> Result<Option<Tagged<MemoryDevice>>> {
+        Ok(self
+            .with(move |c| {
+                c.get_mem_bytes(device)
+                    .map(|bytes| Tagged::new(MemoryDevice { bytes }, c.etag(device)))
+            })
+            .await)
     }
 
-    async fn set_memory(&self, device: DeviceId, memory: MemoryDevice) -> anyhow::Result<()> {
-        self.with(|c| c.set_mem_bytes(device, memory.bytes))
+    async fn set_memory(
+        &self,
+        device: DeviceId,
+        if_match: IfMatch,
+        memory: MemoryDevice,
+    ) -> Result<()> {
+        self.with_device(device, move |c| {
+            c.check_etag(device, &if_match)?;
+            c.set_mem_bytes(device, memory.bytes)
+        })
+        .await
     }
 
-    async fn get_cpu(&self, device: DeviceId) -> Option<CpuDevice> {
-        self.with(|c| c.get_cpu_cores(device).map(|cores| CpuDevice { cores }))
+    async fn get_cpu(&self, device: DeviceId) -> Result<Option<Tagged<CpuDevice>>> {
+        Ok(self
+            .with(move |c| {
+                c.get_cpu_cores(device)
+                    .map(|cores| Tagged::new(CpuDevice { cores }, c.etag(device)))
+            })
+            .await)
     }
 
-    async fn set_cpu(&self, device: DeviceId, cpu: CpuDevice) -> anyhow::Result<()> {
-        self.with(|c| c.set_cpu_cores(device, cpu.cores))
+    async fn set_cpu(&self, device: DeviceId, if_match: IfMatch, cpu: CpuDevice) -> Result<()> {
+        self.with_device(device, move |c| {
+            c.check_etag(device, &if_match)?;
+            c.set_cpu_cores(device, cpu.cores)
+        })
+        .await
     }
 }
 
 #[async_trait]
 impl NetworkDevApi for ClientPuck {
-    async fn create_nat(&self) -> DeviceId {
-        self.with(|c| c.create_nat())
+    async fn create_nat(&self) -> Result<DeviceId> {
+        self.with(move |c| c.create_nat()).await
     }
 
-    async fn delete_nat(&self, device: DeviceId) -> anyhow::Result<()> {
-        self.with(|c| c.delete_nat(device))
+    async fn delete_nat(&self, device: DeviceId) -> Result<()> {
+        self.with_device(device, move |c| c.delete_nat(device))
+            .await
     }
 }
 
 #[async_trait]
 impl StorageDevApi for ClientPuck {
-    async fn get_block(&self, device: DeviceId) -> Option<BlockDevice> {
-        self.with(|c| c.get_block_bytes(device).map(|bytes| BlockDevice { bytes }))
+    async fn get_block(&self, device: DeviceId) -> Result<Option<Tagged<BlockDevice>>> {
+        Ok(self
+            .with(move |c| {
+                c.get_block_bytes(device)
+                    .map(|bytes| Tagged::new(BlockDevice { bytes }, c.etag(device)))
+            })
+            .await)
+    }
+
+    async fn create_block(&self, block: BlockDevice) -> Result<Pending<DeviceId>> {
+        self.start_create_block(block.bytes)
+    }
+
+    async fn delete_block(&self, device: DeviceId, if_match: IfMatch) -> Result<()> {
+        self.with_device(device, move |c| {
+            c.check_etag(device, &if_match)?;
+            c.delete_block(device)
+        })
+        .await
+    }
+
+    async fn get_cdrom(&self, device: DeviceId) -> Result<Option<Tagged<CdromDevice>>> {
+        Ok(self
+            .with(move |c| {
+                c.get_cdrom_media(device)
+                    .map(|media| Tagged::new(CdromDevice { media }, c.etag(device)))
+            })
+            .await)
+    }
+
+    async fn create_cdrom(&self, cdrom: CdromDevice) -> Result<DeviceId> {
+        self.with(move |c| c.create_cdrom(cdrom.media)).await
+    }
+
+    async fn delete_cdrom(&self, device: DeviceId, if_match: IfMatch) -> Result<()> {
+        self.with_device(device, move |c| {
+            c.check_etag(device, &if_match)?;
+            c.delete_cdrom(device)
+        })
+        .await
+    }
+
+    async fn insert_media(&self, device: DeviceId, if_match: IfMatch, media: String) -> Result<()> {
+        self.with_device(device, move |c| {
+            c.check_etag(device, &if_match)?;
+            c.set_cdrom_media(device, Some(media))
+        })
+        .await
     }
 
-    async fn create_block(&self, block: BlockDevice) -> DeviceId {
-        self.with(|c| c.create_block(block.bytes))
+    async fn eject_media(&self, device: DeviceId, if_match: IfMatch) -> Result<()> {
+        self.with_device(device, move |c| {
+            c.check_etag(device, &if_match)?;
+            c.set_cdrom_media(device, None)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ReconcileApi for ClientPuck {
+    async fn drift(&self) -> Result<Option<Report>> {
+        Ok(self.last_report())
+    }
+
+    async fn reconcile(&self, run: Reconcile) -> Result<Report> {
+        Ok(self.reconcile_pass(run.repair).await)
+    }
+}
+
+#[async_trait]
+impl BackupApi for ClientPuck {
+    async fn export(&self, export: Export) -> Result<OperationId> {
+        self.start_export(export)
     }
 
-    async fn delete_block(&self, device: DeviceId) -> anyhow::Result<()> {
-        self.with(|c| c.delete_block(device))
+    async fn import(&self, import: Import) -> Result<OperationId> {
+        self.start_import(import)
     }
 }
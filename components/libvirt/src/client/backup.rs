@@ -0,0 +1,511 @@
+//! Archives of everything the component has on record.
+//!
+//! An archive is a directory with
+//! - `domains/{machine}.xml`, the libvirt definition of every machine,
+//! - `images/{device}.qcow2`, block images, if they were exported,
+//! - `manifest.json`, the [`Manifest`], written last, so archives without one are incomplete.
+//!
+//! Images that weren't exported are expected where they were when importing.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use istruct_common::{
+    api::{
+        backup::v1::{Contents, Export, Import},
+        compute::machine::v1::{AttrValue, MachineEvent, MachineState},
+    },
+    error::{Error, Result},
+    id::{DeviceId, MachineId, OperationId},
+    operation::OperationHandle,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{Client, ClientPuck, DeviceType, NetworkDeviceType, StorageDeviceType};
+
+/// Bumped on incompatible changes to the archive layout.
+const FORMAT: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+const DOMAINS: &str = "domains";
+const IMAGES: &str = "images";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    format: u32,
+    machines: Vec<MachineRecord>,
+    devices: Vec<DeviceRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MachineRecord {
+    id: MachineId,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    attrs: BTreeMap<String, AttrValue>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeviceRecord {
+    id: DeviceId,
+    /// e.g. `is.storage.block`
+    #[serde(rename = "type")]
+    typ: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attached: Option<MachineId>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cores: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memory_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    capacity_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media: Option<String>,
+
+    /// Where the block image was on the exporting host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    /// The block image is in `images/`.
+    #[serde(default)]
+    image: bool,
+}
+
+fn archive_error(e: impl std::fmt::Display) -> Error {
+    Error::backend(format!("archive failure: {}", e))
+}
+
+fn archive_path(path: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(path);
+
+    if !path.is_absolute() {
+        return Err(Error::invalid_argument("archive path has to be absolute"));
+    }
+
+    Ok(path)
+}
+
+fn image_path(root: &Path, device: DeviceId) -> PathBuf {
+    root.join(IMAGES).join(format!("{}.qcow2", device))
+}
+
+impl ClientPuck {
+    pub fn start_export(&self, export: Export) -> Result<OperationId> {
+        let root = archive_path(&export.path)?;
+
+        if root.exists() {
+            return Err(Error::invalid_state(format!(
+                "{} already exists",
+                root.display()
+            )));
+        }
+
+        let op = self.ops.start("export");
+        let id = op.id();
+
+        let puck = self.clone();
+
+        tokio::spawn(async move {
+            let res = puck.export(&root, export.images, &op).await;
+
+            if op.is_cancelled() || res.is_err() {
+                // don't leave a partial archive behind
+                let _ = fs::remove_dir_all(&root);
+            }
+
+            if op.is_cancelled() {
+                return op.cancelled();
+            }
+
+            op.complete(res);
+        });
+
+        Ok(id)
+    }
+
+    async fn export(&self, root: &Path, images: bool, op: &OperationHandle) -> Result<Contents> {
+        op.progress("writing records");
+
+        let keys: Vec<_> = self
+            .with(|c| {
+                let db = c.db();
+
+                db.all_known_machines()
+                    .chain(db.all_dev_types().map(|(d, _)| d))
+                    .collect()
+            })
+            .await;
+
+        // records are written under lock, so they're consistent with each other
+        let dir = root.to_owned();
+        let manifest = self
+            .with_locked(keys, move |c| c.export_records(&dir, images))
+            .await?;
+
+        // copying takes a while, so each copy only locks its device and machine, which keeps
+        // the machine from booting while its image is read
+        let copies: Vec<_> = manifest.devices.iter().filter(|d| d.image).collect();
+
+        for (i, device) in copies.iter().enumerate() {
+            if op.is_cancelled() {
+                return Err(Error::invalid_state("export was cancelled"));
+            }
+
+            op.progress(format!("copying image {} of {}", i + 1, copies.len()));
+
+            let id = device.id;
+            let from = device.path.clone().map(PathBuf::from);
+            let to = image_path(root, id);
+
+            self.with_device(id, move |c| {
+                c.check_image_idle(id)?;
+
+                let from = from.unwrap_or_else(|| c.path_for_block_device(id));
+
+                c.copy_block_file(&from, &to)
+                    .map_err(|e| Error::backend(format!("failed to copy image of {}: {:#}", id, e)))
+            })
+            .await?;
+        }
+
+        let json = serde_json::to_vec_pretty(&manifest).map_err(archive_error)?;
+        fs::write(root.join(MANIFEST), json).map_err(archive_error)?;
+
+        Ok(manifest.contents())
+    }
+
+    pub fn start_import(&self, import: Import) -> Result<OperationId> {
+        let root = archive_path(&import.path)?;
+
+        let manifest: Manifest = fs::read(root.join(MANIFEST))
+            .map_err(|e| Error::invalid_argument(format!("failed to read manifest: {}", e)))
+            .and_then(|m| {
+                serde_json::from_slice(&m).map_err(|e| {
+                    Error::invalid_argument(format!("failed to parse manifest: {}", e))
+                })
+            })?;
+
+        if manifest.format != FORMAT {
+            return Err(Error::invalid_argument(format!(
+                "archive format {} is not supported, only {} is",
+                manifest.format, FORMAT
+            )));
+        }
+
+        let op = self.ops.start("import");
+        let id = op.id();
+
+        let puck = self.clone();
+
+        tokio::spawn(async move {
+            if op.is_cancelled() {
+                return op.cancelled();
+            }
+
+            // once started, importing runs to the end or is rolled back
+            op.progress("restoring machines and devices");
+
+            let keys: Vec<_> = manifest
+                .machines
+                .iter()
+                .map(|m| m.id)
+                .chain(manifest.devices.iter().map(|d| d.id))
+                .collect();
+
+            let res = puck
+                .with_locked(keys, move |c| c.import(&root, manifest))
+                .await;
+
+            op.complete(res);
+        });
+
+        Ok(id)
+    }
+}
+
+impl Manifest {
+    fn contents(&self) -> Contents {
+        Contents {
+            machines: self.machines.iter().map(|m| m.id).collect(),
+            devices: self.devices.iter().map(|d| d.id).collect(),
+        }
+    }
+}
+
+impl Client {
+    fn export_records(&self, root: &Path, images: bool) -> Result<Manifest> {
+        let db = self.db();
+
+        fs::create_dir_all(root.join(DOMAINS)).map_err(archive_error)?;
+
+        if images {
+            fs::create_dir_all(root.join(IMAGES)).map_err(archive_error)?;
+        }
+
+        let mut machines = vec![];
+
+        for machine in db.all_known_machines() {
+            // lost machines have nothing left to restore
+            let domain = match self.get_domain(machine) {
+                Some(d) => d,
+                None => continue,
+            };
+
+            let xml = domain
+                .get_xml_desc(virt::domain::VIR_DOMAIN_XML_INACTIVE)
+                .map_err(|e| Error::backend(e.to_string()))?;
+
+            fs::write(root.join(DOMAINS).join(format!("{}.xml", machine)), xml)
+                .map_err(archive_error)?;
+
+            machines.push(MachineRecord {
+                id: machine,
+                attrs: db.get_machine_attrs(machine).collect(),
+            });
+        }
+
+        let mut devices = vec![];
+
+        for (id, typ) in db.all_dev_types() {
+            let mut attached = db.get_dev_attached(id);
+
+            if let Some(machine) = attached {
+                if !machines.iter().any(|m| m.id == machine) {
+                    // these don't outlive their machine
+                    if let DeviceType::Compute(_) = typ {
+                        continue;
+                    }
+
+                    attached = None;
+                }
+            }
+
+            let block = matches!(typ, DeviceType::Storage(StorageDeviceType::Block));
+
+            if images && block {
+                self.check_image_idle(id)?;
+            }
+
+            devices.push(DeviceRecord {
+                id,
+                typ: typ.to_string(),
+                attached,
+                cores: db.get_dev_cpu(id),
+                memory_bytes: db.get_dev_mem(id),
+                capacity_bytes: db.get_dev_block_cap(id),
+                media: db.get_dev_cdrom_media(id),
+                path: block.then(|| self.path_for_block_device(id).display().to_string()),
+                image: images && block,
+            });
+        }
+
+        Ok(Manifest {
+            format: FORMAT,
+            machines,
+            devices,
+        })
+    }
+
+    /// A running machine writes to its images while they're copied, so it has to be off.
+    fn check_image_idle(&self, device: DeviceId) -> Result<()> {
+        let machine = match self.db().get_dev_attached(device) {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+
+        match self.get_status(machine).map(|s| s.state) {
+            None | Some(MachineState::Off) => Ok(()),
+            Some(_) => Err(Error::invalid_state(format!(
+                "machine {} has to be off to export its images",
+                machine
+            ))),
+        }
+    }
+
+    fn import(&self, root: &Path, manifest: Manifest) -> Result<Contents> {
+        let contents = manifest.contents();
+
+        // check everything before anything is changed
+        for machine in &manifest.machines {
+            if self.db().is_known_machine(machine.id) || self.get_domain(machine.id).is_some() {
+                return Err(Error::invalid_state(format!(
+                    "machine {} already exists",
+                    machine.id
+                )));
+            }
+        }
+
+        let mut types = HashMap::new();
+
+        for device in &manifest.devices {
+            if self.db().get_dev_type(device.id).is_some() {
+                return Err(Error::invalid_state(format!(
+                    "device {} already exists",
+                    device.id
+                )));
+            }
+
+            let typ = DeviceType::parse_str(device.typ.as_str()).ok_or_else(|| {
+                Error::invalid_argument(format!("unknown device type {}", device.typ))
+            })?;
+
+            types.insert(device.id, typ);
+        }
+
+        let mut domains = vec![];
+
+        for machine in &manifest.machines {
+            let xml = fs::read_to_string(root.join(DOMAINS).join(format!("{}.xml", machine.id)))
+                .map_err(archive_error)?;
+
+            domains.push((machine.id, xml));
+        }
+
+        // images are copied first, and removed again if anything fails
+        let mut copied = vec![];
+
+        let res = self.import_images(root, &manifest, &mut domains, &mut copied);
+        let res = res.and_then(|()| self.import_records(&manifest, &types, domains));
+
+        if let Err(e) = res {
+            for path in copied {
+                let _ = fs::remove_file(path);
+            }
+
+            return Err(e);
+        }
+
+        for machine in &contents.machines {
+            self.emit(MachineEvent::Created { machine: *machine });
+        }
+
+        Ok(contents)
+    }
+
+    /// Copies the images of the archive into the block directory, pointing disks at them.
+    fn import_images(
+        &self,
+        root: &Path,
+        manifest: &Manifest,
+        domains: &mut [(MachineId, String)],
+        copied: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        use crate::xml::{Alias, Source};
+
+        for device in manifest.devices.iter().filter(|d| d.image) {
+            let to = self.path_for_block_device(device.id);
+
+            self.copy_block_file(image_path(root, device.id), &to)
+                .map_err(|e| {
+                    Error::backend(format!("failed to copy image of {}: {:#}", device.id, e))
+                })?;
+
+            copied.push(to.clone());
+
+            let (_, xml) = match domains
+                .iter_mut()
+                .find(|(m, _)| Some(*m) == device.attached)
+            {
+                Some(d) => d,
+                None => continue,
+            };
+
+            let mut domain = crate::xml::Domain::from_str(xml).map_err(archive_error)?;
+
+            let alias = Alias::user(device.id);
+
+            for disk in &mut domain.devices.disks {
+                let file = disk.source.as_ref().and_then(|s| s.file.as_deref());
+
+                // disks attached before aliases were set only have their path
+                if disk.alias.as_ref() == Some(&alias)
+                    || (disk.alias.is_none() && file == device.path.as_deref())
+                {
+                    disk.source = Some(Source::file(to.to_str().unwrap()));
+                }
+            }
+
+            *xml = domain.to_string().map_err(archive_error)?;
+        }
+
+        Ok(())
+    }
+
+    fn import_records(
+        &self,
+        manifest: &Manifest,
+        types: &HashMap<DeviceId, DeviceType>,
+        domains: Vec<(MachineId, String)>,
+    ) -> Result<()> {
+        let nat = types
+            .values()
+            .any(|t| matches!(t, DeviceType::Network(NetworkDeviceType::Nat)));
+
+        if nat {
+            self.assure_nat_network()?;
+        }
+
+        let mut defined = vec![];
+
+        // the domains are defined last, so the records are rolled back if that fails
+        let res = self.transaction(|db| {
+            for device in &manifest.devices {
+                let id = device.id;
+
+                db.set_dev_type(id, types[&id]);
+
+                if let Some(machine) = device.attached {
+                    db.set_dev_attached(id, machine);
+                }
+
+                if let Some(cores) = device.cores {
+                    db.set_dev_cpu(id, cores);
+                }
+
+                if let Some(bytes) = device.memory_bytes {
+                    db.set_dev_mem(id, bytes);
+                }
+
+                if let Some(bytes) = device.capacity_bytes {
+                    db.set_dev_block_cap(id, bytes);
+                }
+
+                if let Some(media) = &device.media {
+                    db.set_dev_cdrom_media(id, media.clone());
+                }
+
+                // images that weren't exported stay where they were
+                if let Some(path) = &device.path {
+                    if !device.image && Path::new(path) != self.path_for_block_device(id) {
+                        db.set_dev_block_path(id, path.clone());
+                    }
+                }
+            }
+
+            for machine in &manifest.machines {
+                db.set_known_machine(machine.id);
+
+                for (attr, value) in &machine.attrs {
+                    db.set_machine_attr(machine.id, attr, value);
+                }
+            }
+
+            for (machine, xml) in domains {
+                self.define_domain_xml(&xml)?;
+                defined.push(machine);
+            }
+
+            Ok(())
+        });
+
+        if res.is_err() {
+            for machine in defined {
+                let _ = self.undefine_domain(machine);
+            }
+        }
+
+        res
+    }
+}
@@ -0,0 +1,176 @@
+//! Domain lifecycle events.
+//!
+//! virt 0.2 doesn't bind libvirt's event API, so the few functions we need are declared here.
+
+use std::{
+    ffi::CStr,
+    os::raw::{c_char, c_int, c_void},
+    ptr,
+    sync::OnceLock,
+};
+
+use istruct_common::api::compute::machine::v1::MachineEvent;
+use persy::Persy;
+use tokio::sync::broadcast::Sender;
+use uuid::Uuid;
+use virt::{connect::Connect, domain::DomainState};
+
+use super::{virt_domainstate_to_istruct, KNOWN_MACHINES};
+
+const VIR_DOMAIN_EVENT_ID_LIFECYCLE: c_int = 0;
+const VIR_UUID_STRING_BUFLEN: usize = 37;
+
+// virDomainEventType
+const VIR_DOMAIN_EVENT_DEFINED: c_int = 0;
+const VIR_DOMAIN_EVENT_UNDEFINED: c_int = 1;
+
+type GenericCallback = unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_void);
+type LifecycleCallback =
+    unsafe extern "C" fn(*mut c_void, *mut c_void, c_int, c_int, *mut c_void) -> c_int;
+type FreeCallback = unsafe extern "C" fn(*mut c_void);
+
+#[link(name = "virt")]
+extern "C" {
+    fn virEventRegisterDefaultImpl() -> c_int;
+    fn virEventRunDefaultImpl() -> c_int;
+    fn virConnectDomainEventRegisterAny(
+        conn: *mut c_void,
+        dom: *mut c_void,
+        event_id: c_int,
+        cb: GenericCallback,
+        opaque: *mut c_void,
+        freecb: Option<FreeCallback>,
+    ) -> c_int;
+    fn virDomainGetUUIDString(dom: *mut c_void, buf: *mut c_char) -> c_int;
+    fn virDomainGetState(
+        dom: *mut c_void,
+        state: *mut c_int,
+        reason: *mut c_int,
+        flags: u32,
+    ) -> c_int;
+}
+
+/// Whether the event loop started, a failure is kept for every later call.
+static EVENT_LOOP: OnceLock<Result<(), String>> = OnceLock::new();
+
+/// Runs libvirt's default event loop on its own thread.
+///
+/// Has to be called before any connection is opened, connections opened earlier never get events.
+pub fn start_event_loop() -> anyhow::Result<()> {
+    EVENT_LOOP
+        .get_or_init(|| {
+            if unsafe { virEventRegisterDefaultImpl() } < 0 {
+                return Err(format!(
+                    "failed to register libvirt event loop: {}",
+                    virt::error::Error::new()
+                ));
+            }
+
+            std::thread::Builder::new()
+                .name("libvirt-events".to_string())
+                .spawn(|| while unsafe { virEventRunDefaultImpl() } >= 0 {})
+                .map(drop)
+                .map_err(|e| format!("failed to spawn libvirt event thread: {}", e))
+        })
+        .clone()
+        .map_err(anyhow::Error::msg)
+}
+
+struct Context {
+    events: Sender<MachineEvent>,
+    persy: Persy,
+}
+
+/// Sends a [`MachineEvent::StateChanged`] on `events` for every lifecycle change of a known machine.
+pub fn register_lifecycle(
+    conn: &Connect,
+    events: Sender<MachineEvent>,
+    persy: Persy,
+) -> anyhow::Result<()> {
+    let opaque = Box::into_raw(Box::new(Context { events, persy })) as *mut c_void;
+
+    let ret = unsafe {
+        virConnectDomainEventRegisterAny(
+            conn.as_ptr() as *mut c_void,
+            ptr::null_mut(),
+            VIR_DOMAIN_EVENT_ID_LIFECYCLE,
+            std::mem::transmute::<LifecycleCallback, GenericCallback>(lifecycle),
+            opaque,
+            Some(free_context),
+        )
+    };
+
+    if ret < 0 {
+        drop(unsafe { Box::from_raw(opaque as *mut Context) });
+
+        anyhow::bail!(
+            "failed to register lifecycle callback: {}",
+            virt::error::Error::new()
+        );
+    }
+
+    Ok(())
+}
+
+unsafe extern "C" fn lifecycle(
+    _conn: *mut c_void,
+    dom: *mut c_void,
+    event: c_int,
+    _detail: c_int,
+    opaque: *mut c_void,
+) -> c_int {
+    let ctx = &*(opaque as *const Context);
+
+    // create and destroy are sent by the client itself
+    if let VIR_DOMAIN_EVENT_DEFINED | VIR_DOMAIN_EVENT_UNDEFINED = event {
+        return 0;
+    }
+
+    let mut buf = [0 as c_char; VIR_UUID_STRING_BUFLEN];
+
+    if virDomainGetUUIDString(dom, buf.as_mut_ptr()) < 0 {
+        return 0;
+    }
+
+    let machine = match CStr::from_ptr(buf.as_ptr())
+        .to_str()
+        .ok()
+        .and_then(|s| Uuid::parse_str(s).ok())
+    {
+        Some(m) => m,
+        None => return 0,
+    };
+
+    let known = ctx
+        .persy
+        .get::<u128, u8>(KNOWN_MACHINES, &machine.as_u128())
+        .ok()
+        .and_then(|mut v| v.next())
+        .is_some();
+
+    if !known {
+        return 0;
+    }
+
+    // the event detail is specific to the event, the state reason is what /status reports
+    let (mut state, mut reason) = (0, 0);
+
+    if virDomainGetState(dom, &mut state, &mut reason, 0) < 0 {
+        return 0;
+    }
+
+    let status = virt_domainstate_to_istruct(state as DomainState, reason);
+
+    // no subscribers is fine
+    let _ = ctx.events.send(MachineEvent::StateChanged {
+        machine,
+        state: status.state,
+        reason: status.reason,
+    });
+
+    0
+}
+
+unsafe extern "C" fn free_context(opaque: *mut c_void) {
+    drop(Box::from_raw(opaque as *mut Context));
+}
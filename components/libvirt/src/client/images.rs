@@ -0,0 +1,73 @@
+//! Disk images of block devices.
+
+use std::{path::Path, process::Command};
+
+/// Creates and inspects disk images, [`QemuImg`] unless a backend is configured otherwise.
+///
+/// Paths are absolute, and callers decide where images go.
+pub trait Images: Send + Sync {
+    /// Creates an empty qcow2 image of `bytes` virtual size.
+    fn create(&self, path: &Path, bytes: u64) -> anyhow::Result<()>;
+
+    /// Copies a qcow2 image, leaving out unused space.
+    fn copy(&self, from: &Path, to: &Path) -> anyhow::Result<()>;
+
+    /// Virtual size of a qcow2 image.
+    fn bytes(&self, path: &Path) -> anyhow::Result<u64>;
+}
+
+/// Runs `qemu-img` from `PATH`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QemuImg;
+
+impl QemuImg {
+    /// Returns the stdout of `qemu-img`.
+    fn run(qemu_img: &mut Command) -> anyhow::Result<Vec<u8>> {
+        let output = qemu_img.output()?;
+
+        if !output.status.success() {
+            anyhow::bail!(
+                "qemu-img failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+impl Images for QemuImg {
+    fn create(&self, path: &Path, bytes: u64) -> anyhow::Result<()> {
+        Self::run(
+            Command::new("qemu-img")
+                .args(["create", "-f", "qcow2"])
+                .arg(path)
+                .arg(bytes.to_string()),
+        )
+        .map(drop)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        Self::run(
+            Command::new("qemu-img")
+                .args(["convert", "-O", "qcow2"])
+                .arg(from)
+                .arg(to),
+        )
+        .map(drop)
+    }
+
+    fn bytes(&self, path: &Path) -> anyhow::Result<u64> {
+        let output = Self::run(
+            Command::new("qemu-img")
+                .args(["info", "--output=json"])
+                .arg(path),
+        )?;
+
+        let info: serde_json::Value = serde_json::from_slice(&output)?;
+
+        info["virtual-size"]
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("qemu-img did not report a virtual size"))
+    }
+}
@@ -0,0 +1,45 @@
+//! Locks per machine or device, so operations on the same one don't interleave.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Default)]
+pub struct Locks {
+    locks: Arc<Mutex<HashMap<Uuid, Arc<AsyncMutex<()>>>>>,
+}
+
+/// Held locks, released on drop.
+pub struct Guards {
+    _held: Vec<OwnedMutexGuard<()>>,
+}
+
+impl Locks {
+    /// Locks every key, always in the same order, so two callers can't deadlock each other.
+    pub async fn lock(&self, keys: impl IntoIterator<Item = Uuid>) -> Guards {
+        let mut keys: Vec<Uuid> = keys.into_iter().collect();
+        keys.sort();
+        keys.dedup();
+
+        let mut guards = Vec::with_capacity(keys.len());
+
+        for key in keys {
+            let lock = {
+                let mut locks = self.locks.lock().unwrap();
+
+                // nobody else holds or waits on these
+                locks.retain(|_, l| Arc::strong_count(l) > 1);
+
+                locks.entry(key).or_default().clone()
+            };
+
+            guards.push(lock.lock_owned().await);
+        }
+
+        Guards { _held: guards }
+    }
+}
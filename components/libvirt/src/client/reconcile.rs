@@ -0,0 +1,285 @@
+//! Finds drift between the database and libvirt, and optionally repairs it.
+//!
+//! Drift happens whenever something is changed out-of-band, e.g. a domain undefined
+//! or a disk removed through `virsh`, or a block file deleted by hand.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use istruct_common::{
+    api::reconcile::v1::{Drift, Finding, Report},
+    id::{DeviceId, MachineId},
+};
+use uuid::Uuid;
+
+use super::{
+    is_nat_interface, Client, ClientPuck, ComputeDeviceType, DeviceType, NetworkDeviceType,
+    StorageDeviceType,
+};
+
+impl ClientPuck {
+    /// Runs a reconciliation pass, checking one machine or device at a time, under its lock.
+    pub async fn reconcile_pass(&self, repair: bool) -> Report {
+        let mut findings = vec![];
+
+        let machines: Vec<MachineId> = self.with(|c| c.db().all_known_machines().collect()).await;
+
+        for machine in machines {
+            let devices: Vec<DeviceId> = self
+                .with(move |c| c.db().get_dev_attached_to(machine).collect())
+                .await;
+
+            let keys = devices.into_iter().chain([machine]);
+
+            findings.extend(
+                self.with_locked(keys, move |c| c.reconcile_machine(machine, repair))
+                    .await,
+            );
+        }
+
+        let devices: Vec<DeviceId> = self
+            .with(|c| c.db().all_dev_types().map(|(d, _)| d).collect())
+            .await;
+
+        for device in devices {
+            findings.extend(
+                self.with_device(device, move |c| c.reconcile_device(device, repair))
+                    .await,
+            );
+        }
+
+        let report = Report {
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            findings,
+        };
+
+        *self.last_report.lock().unwrap() = Some(report.clone());
+
+        report
+    }
+
+    /// Reconciles right away, and then every `interval`.
+    pub async fn reconcile_every(self, interval: Duration, repair: bool) {
+        loop {
+            self.reconcile_pass(repair).await;
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    pub fn last_report(&self) -> Option<Report> {
+        self.last_report.lock().unwrap().clone()
+    }
+}
+
+impl Client {
+    fn reconcile_machine(&self, machine: MachineId, repair: bool) -> Vec<Finding> {
+        let db = self.db();
+
+        // destroyed since the pass started
+        if !db.is_known_machine(machine) {
+            return vec![];
+        }
+
+        let domain = match self.get_domain_xml(machine) {
+            Some(d) => d,
+            // recorded by an earlier pass
+            None if db.is_lost_machine(machine) => return vec![],
+            None => {
+                let repaired = repair && {
+                    db.set_lost_machine(machine);
+                    db.is_lost_machine(machine)
+                };
+
+                // everything else is about the domain, which is gone
+                return vec![Finding {
+                    drift: Drift::MachineLost { machine },
+                    repaired,
+                }];
+            }
+        };
+
+        if repair {
+            // the domain is back
+            db.del_lost_machine(machine);
+        }
+
+        let mut findings = vec![];
+
+        let attached: Vec<(DeviceId, Option<DeviceType>)> = db
+            .get_dev_attached_to(machine)
+            .map(|d| (d, db.get_dev_type(d)))
+            .collect();
+
+        let has_cpu = attached
+            .iter()
+            .any(|(_, t)| matches!(t, Some(DeviceType::Compute(ComputeDeviceType::Cpu))));
+        let has_mem = attached
+            .iter()
+            .any(|(_, t)| matches!(t, Some(DeviceType::Compute(ComputeDeviceType::Mem))));
+
+        if !has_cpu {
+            let cores = domain.vcpu.amount as u64;
+
+            findings.push(Finding {
+                drift: Drift::DeviceMissing {
+                    machine,
+                    device_type: DeviceType::Compute(ComputeDeviceType::Cpu).to_string(),
+                },
+                repaired: repair
+                    && self
+                        .transaction(|db| {
+                            let cpu = Uuid::new_v4();
+
+                            db.set_dev_type(cpu, DeviceType::Compute(ComputeDeviceType::Cpu));
+                            db.set_dev_attached(cpu, machine);
+                            db.set_dev_cpu(cpu, cores);
+
+                            Ok(())
+                        })
+                        .is_ok(),
+            });
+        }
+
+        if !has_mem {
+            let bytes = domain.memory.bytes();
+
+            findings.push(Finding {
+                drift: Drift::DeviceMissing {
+                    machine,
+                    device_type: DeviceType::Compute(ComputeDeviceType::Mem).to_string(),
+                },
+                repaired: repair
+                    && self
+                        .transaction(|db| {
+                            let mem = Uuid::new_v4();
+
+                            db.set_dev_type(mem, DeviceType::Compute(ComputeDeviceType::Mem));
+                            db.set_dev_attached(mem, machine);
+                            db.set_dev_mem(mem, bytes);
+
+                            Ok(())
+                        })
+                        .is_ok(),
+            });
+        }
+
+        // interfaces attached before aliases were set can only be counted
+        let unaliased_nats = domain
+            .devices
+            .interfaces
+            .iter()
+            .filter(|i| i.alias.is_none() && is_nat_interface(i))
+            .count();
+        let mut nats = 0;
+
+        for (device, typ) in attached {
+            use {NetworkDeviceType as N, StorageDeviceType as S};
+
+            let present = match typ {
+                Some(DeviceType::Compute(_)) => true,
+                Some(DeviceType::Storage(S::Block)) => {
+                    let path = self.path_for_block_device(device);
+                    let alias = crate::xml::Alias::user(device);
+
+                    domain.devices.disks.iter().any(|disk| match &disk.alias {
+                        Some(a) => a == &alias,
+                        // disks attached before aliases were set
+                        None => {
+                            disk.source.as_ref().and_then(|s| s.file.as_deref()) == path.to_str()
+                        }
+                    })
+                }
+                Some(DeviceType::Storage(S::Cdrom)) => {
+                    let alias = crate::xml::Alias::user(device);
+
+                    domain
+                        .devices
+                        .disks
+                        .iter()
+                        .any(|disk| disk.alias.as_ref() == Some(&alias))
+                }
+                Some(DeviceType::Network(N::Nat)) => {
+                    let alias = crate::xml::Alias::user(device);
+
+                    if domain
+                        .devices
+                        .interfaces
+                        .iter()
+                        .any(|i| i.alias.as_ref() == Some(&alias))
+                    {
+                        true
+                    } else {
+                        nats += 1;
+                        nats <= unaliased_nats
+                    }
+                }
+                // attached, but the device itself is gone
+                None => false,
+            };
+
+            if !present {
+                if repair {
+                    db.del_dev_attached(device);
+                    db.bump_generation(machine);
+                }
+
+                findings.push(Finding {
+                    drift: Drift::AttachmentDangling { machine, device },
+                    repaired: repair,
+                });
+            }
+        }
+
+        findings
+    }
+
+    fn reconcile_device(&self, device: DeviceId, repair: bool) -> Vec<Finding> {
+        let db = self.db();
+
+        let typ = match db.get_dev_type(device) {
+            Some(t) => t,
+            // deleted since the pass started
+            None => return vec![],
+        };
+
+        let mut findings = vec![];
+
+        if let DeviceType::Storage(StorageDeviceType::Block) = typ {
+            if !self.path_for_block_device(device).is_file() {
+                findings.push(Finding {
+                    drift: Drift::StorageMissing { device },
+                    repaired: false,
+                });
+            }
+        }
+
+        if let Some(machine) = db.get_dev_attached(device) {
+            if !db.is_known_machine(machine) {
+                let repaired = repair
+                    && match typ {
+                        // these don't outlive their machine
+                        DeviceType::Compute(c) => self
+                            .transaction(|db| {
+                                db.del_compute_device(device, c);
+                                Ok(())
+                            })
+                            .is_ok(),
+                        _ => {
+                            db.del_dev_attached(device);
+                            true
+                        }
+                    };
+
+                findings.push(Finding {
+                    drift: Drift::AttachmentDangling { machine, device },
+                    repaired,
+                });
+            }
+        }
+
+        findings
+    }
+}
@@ -0,0 +1,282 @@
+//! Versioned database schema, migrated one step at a time when the database is opened.
+//!
+//! Databases from before versioning are version 0, which could have any of the indexes added
+//! before it, so the steps up to [`machine_devices`] only add what's missing.
+
+use anyhow::Context;
+use persy::{ByteVec, IndexType, Persy, Transaction, ValueMode};
+
+use super::{
+    DEV_BLOCK_CAPACITY, DEV_BLOCK_PATH, DEV_CDROM_MEDIA, DEV_CPU, DEV_MACHINE_ATTACHED, DEV_MEM,
+    DEV_TYPE, GENERATIONS, KNOWN_MACHINES, LOST_MACHINES, MACHINE_ATTRS, MACHINE_DEVICES,
+};
+
+const SCHEMA: &str = "schema";
+const VERSION_KEY: &str = "version";
+
+type Step = fn(&Persy, &mut Transaction) -> anyhow::Result<()>;
+
+/// Step `n` takes the database from version `n` to `n + 1`.
+///
+/// Step 0 is the first release, the others each add an index that came after it. None of those
+/// has been released as a schema version yet. Once a version is released, its steps can't change
+/// anymore, only new ones can be appended.
+const STEPS: &[Step] = &[
+    initial_indexes,
+    machine_attrs,
+    cdrom_media,
+    generations,
+    lost_machines,
+    block_paths,
+    machine_devices,
+];
+
+/// The version this build writes.
+pub const VERSION: u64 = STEPS.len() as u64;
+
+/// Brings the database up to [`VERSION`], refusing databases of newer builds.
+pub fn migrate(persy: &Persy) -> anyhow::Result<()> {
+    let current = version(persy)?;
+
+    if current > VERSION {
+        anyhow::bail!(
+            "database schema version {} is newer than the supported version {}",
+            current,
+            VERSION
+        );
+    }
+
+    for (from, step) in STEPS.iter().enumerate().skip(current as usize) {
+        let to = from as u64 + 1;
+
+        // every step commits together with its version
+        let mut tx = persy.begin()?;
+
+        step(persy, &mut tx)
+            .with_context(|| format!("failed to migrate database to schema version {}", to))?;
+
+        tx.put::<String, u64>(SCHEMA, VERSION_KEY.into(), to)?;
+        tx.prepare()?.commit()?;
+    }
+
+    Ok(())
+}
+
+fn version(persy: &Persy) -> anyhow::Result<u64> {
+    if !persy.exists_index(SCHEMA)? {
+        let mut tx = persy.begin()?;
+        tx.create_index::<String, u64>(SCHEMA, ValueMode::Replace)?;
+        tx.prepare()?.commit()?;
+    }
+
+    Ok(persy
+        .one::<String, u64>(SCHEMA, &VERSION_KEY.into())?
+        .unwrap_or(0))
+}
+
+fn create_index<K, V>(tx: &mut Transaction, name: &str, mode: ValueMode) -> anyhow::Result<()>
+where
+    K: IndexType,
+    V: IndexType,
+{
+    if !tx.exists_index(name)? {
+        tx.create_index::<K, V>(name, mode)?;
+    }
+
+    Ok(())
+}
+
+/// The indexes of the first release.
+fn initial_indexes(_: &Persy, tx: &mut Transaction) -> anyhow::Result<()> {
+    use ValueMode::Replace;
+
+    create_index::<u128, u128>(tx, DEV_MACHINE_ATTACHED, Replace)?;
+    create_index::<u128, ByteVec>(tx, DEV_TYPE, Replace)?;
+
+    create_index::<u128, u64>(tx, DEV_CPU, Replace)?;
+    create_index::<u128, u64>(tx, DEV_MEM, Replace)?;
+
+    create_index::<u128, u64>(tx, DEV_BLOCK_CAPACITY, Replace)?;
+
+    create_index::<u128, u8>(tx, KNOWN_MACHINES, Replace)?;
+
+    Ok(())
+}
+
+fn machine_attrs(_: &Persy, tx: &mut Transaction) -> anyhow::Result<()> {
+    create_index::<String, ByteVec>(tx, MACHINE_ATTRS, ValueMode::Replace)
+}
+
+fn cdrom_media(_: &Persy, tx: &mut Transaction) -> anyhow::Result<()> {
+    create_index::<u128, String>(tx, DEV_CDROM_MEDIA, ValueMode::Replace)
+}
+
+fn generations(_: &Persy, tx: &mut Transaction) -> anyhow::Result<()> {
+    create_index::<u128, u64>(tx, GENERATIONS, ValueMode::Replace)
+}
+
+fn lost_machines(_: &Persy, tx: &mut Transaction) -> anyhow::Result<()> {
+    create_index::<u128, u8>(tx, LOST_MACHINES, ValueMode::Replace)
+}
+
+/// Paths of adopted images.
+fn block_paths(_: &Persy, tx: &mut Transaction) -> anyhow::Result<()> {
+    create_index::<u128, String>(tx, DEV_BLOCK_PATH, ValueMode::Replace)
+}
+
+/// Reverse of `dev_machine_attached`, back-filled from it.
+fn machine_devices(persy: &Persy, tx: &mut Transaction) -> anyhow::Result<()> {
+    // unversioned builds already kept it up to date
+    if tx.exists_index(MACHINE_DEVICES)? {
+        return Ok(());
+    }
+
+    tx.create_index::<u128, u128>(MACHINE_DEVICES, ValueMode::Cluster)?;
+
+    for (dev, mut machine) in persy.range::<u128, u128, _>(DEV_MACHINE_ATTACHED, ..)? {
+        if let Some(machine) = machine.next() {
+            tx.put::<u128, u128>(MACHINE_DEVICES, machine, dev)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use persy::{ByteVec, Persy, ValueMode};
+    use tempfile::TempDir;
+    use uuid::Uuid;
+
+    use super::super::{ClientDB, Store};
+    use super::{
+        migrate, version, DEV_BLOCK_CAPACITY, DEV_BLOCK_PATH, DEV_CDROM_MEDIA, DEV_CPU,
+        DEV_MACHINE_ATTACHED, DEV_MEM, DEV_TYPE, GENERATIONS, KNOWN_MACHINES, LOST_MACHINES,
+        MACHINE_ATTRS, MACHINE_DEVICES, SCHEMA, VERSION, VERSION_KEY,
+    };
+
+    const INDEXES: [&str; 12] = [
+        DEV_MACHINE_ATTACHED,
+        DEV_TYPE,
+        DEV_CPU,
+        DEV_MEM,
+        DEV_BLOCK_CAPACITY,
+        DEV_BLOCK_PATH,
+        DEV_CDROM_MEDIA,
+        KNOWN_MACHINES,
+        MACHINE_ATTRS,
+        GENERATIONS,
+        LOST_MACHINES,
+        MACHINE_DEVICES,
+    ];
+
+    /// An empty database, removed on drop.
+    struct Db {
+        persy: Persy,
+        _dir: TempDir,
+    }
+
+    impl Db {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let persy = Persy::open_or_create_with(
+                dir.path().join("db.persy"),
+                persy::Config::new(),
+                |_| Ok(()),
+            )
+            .unwrap();
+
+            Self { persy, _dir: dir }
+        }
+    }
+
+    #[test]
+    fn unversioned_databases_migrate() {
+        let db = Db::new();
+
+        // an unversioned build that had attributes, but not cdroms
+        let mut tx = db.persy.begin().unwrap();
+        tx.create_index::<u128, u128>(DEV_MACHINE_ATTACHED, ValueMode::Replace)
+            .unwrap();
+        tx.create_index::<String, ByteVec>(MACHINE_ATTRS, ValueMode::Replace)
+            .unwrap();
+        tx.put::<String, ByteVec>(MACHINE_ATTRS, "m/a".into(), b"1".to_vec().into())
+            .unwrap();
+        tx.prepare().unwrap().commit().unwrap();
+
+        migrate(&db.persy).unwrap();
+
+        assert_eq!(version(&db.persy).unwrap(), VERSION);
+
+        for index in INDEXES {
+            assert!(
+                db.persy.exists_index(index).unwrap(),
+                "{} is missing",
+                index
+            );
+        }
+
+        let attr = db
+            .persy
+            .one::<String, ByteVec>(MACHINE_ATTRS, &"m/a".into())
+            .unwrap()
+            .unwrap();
+        assert_eq!(attr, b"1".to_vec().into());
+
+        // nothing left to do
+        migrate(&db.persy).unwrap();
+        assert_eq!(version(&db.persy).unwrap(), VERSION);
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let db = Db::new();
+
+        let mut tx = db.persy.begin().unwrap();
+        tx.create_index::<String, u64>(SCHEMA, ValueMode::Replace)
+            .unwrap();
+        tx.put::<String, u64>(SCHEMA, VERSION_KEY.into(), VERSION + 1)
+            .unwrap();
+        tx.prepare().unwrap().commit().unwrap();
+
+        let err = migrate(&db.persy).unwrap_err();
+        assert!(err.to_string().contains("is newer than"), "{}", err);
+
+        // left as it was
+        assert_eq!(version(&db.persy).unwrap(), VERSION + 1);
+        assert!(!db.persy.exists_index(DEV_TYPE).unwrap());
+    }
+
+    #[test]
+    fn machine_devices_are_back_filled() {
+        let db = Db::new();
+
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let (cpu, mem, nat) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        let mut tx = db.persy.begin().unwrap();
+        tx.create_index::<u128, u128>(DEV_MACHINE_ATTACHED, ValueMode::Replace)
+            .unwrap();
+        for (dev, machine) in [(cpu, a), (mem, a), (nat, b)] {
+            tx.put::<u128, u128>(DEV_MACHINE_ATTACHED, dev.as_u128(), machine.as_u128())
+                .unwrap();
+        }
+        tx.prepare().unwrap().commit().unwrap();
+
+        migrate(&db.persy).unwrap();
+
+        let client = ClientDB {
+            store: Store::Persy(&db.persy),
+        };
+
+        let mut attached: Vec<_> = client.get_dev_attached_to(a).collect();
+        attached.sort();
+
+        let mut expected = vec![cpu, mem];
+        expected.sort();
+
+        assert_eq!(attached, expected);
+        assert_eq!(client.get_dev_attached_to(b).collect::<Vec<_>>(), [nat]);
+        assert_eq!(client.get_dev_attached_to(Uuid::new_v4()).count(), 0);
+    }
+}
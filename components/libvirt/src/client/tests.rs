@@ -0,0 +1,565 @@
+//! Runs against libvirt's `test:///default` driver, which keeps domains in memory.
+//!
+//! Every test gets its own database and block directory, but the driver's state is shared by
+//! the whole process, so tests only touch machines they created.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use istruct_common::{
+    api::{
+        backup::v1::{Contents, Export, Import},
+        compute::machine::v1::{AttrValue, ExternalDisks, MachineSpec},
+        operation::v1::{Operation, OperationState},
+        reconcile::v1::{Drift, Finding},
+    },
+    conformance,
+    error::ErrorKind,
+    id::OperationId,
+};
+use tempfile::TempDir;
+use uuid::Uuid;
+
+use super::{
+    virt_domainstate_to_istruct, Backend, ClientPuck, ComputeDeviceType, DeviceType, Images,
+    StorageDeviceType, NAT_NETWORK_NAME,
+};
+use crate::xml::{self, Alias};
+
+const MIB: u64 = 1024 * 1024;
+
+/// Images are files of their virtual size, sparse where the filesystem allows it.
+struct Sparse;
+
+impl Images for Sparse {
+    fn create(&self, path: &Path, bytes: u64) -> anyhow::Result<()> {
+        std::fs::File::create(path)?.set_len(bytes)?;
+
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        std::fs::copy(from, to)?;
+
+        Ok(())
+    }
+
+    fn bytes(&self, path: &Path) -> anyhow::Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+}
+
+/// A component on the test driver, its files are removed on drop.
+struct TestComponent {
+    puck: ClientPuck,
+    dir: TempDir,
+}
+
+impl TestComponent {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+
+        std::fs::create_dir_all(dir.path().join("block")).unwrap();
+
+        let puck = ClientPuck::new(
+            "test:///default",
+            dir.path().join("db.persy"),
+            dir.path().join("block"),
+            2,
+            Backend {
+                domain_type: "test".into(),
+                images: Arc::new(Sparse),
+                ..Backend::default()
+            },
+        )
+        .unwrap();
+
+        Self { puck, dir }
+    }
+}
+
+/// Waits for the operation, which has to succeed.
+async fn succeeded(puck: &ClientPuck, id: OperationId) -> Operation {
+    let op = puck.ops.wait(id, Duration::from_secs(30)).await.unwrap();
+    assert_eq!(op.state, OperationState::Succeeded, "{:?}", op.error);
+
+    op
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn conforms() {
+    let test = TestComponent::new();
+
+    let report = conformance::run(&test.puck, &test.puck.ops).await;
+    assert!(report.is_ok(), "{}", report);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn create_and_destroy_keep_records() {
+    let test = TestComponent::new();
+
+    test.puck
+        .with(|c| {
+            let machine = c
+                .create(MachineSpec {
+                    cores: 3,
+                    memory_bytes: 128 * MIB,
+                    ..MachineSpec::default()
+                })
+                .unwrap();
+
+            let db = c.db();
+            assert!(db.is_known_machine(machine));
+
+            let devices: Vec<_> = db.get_dev_attached_to(machine).collect();
+            assert_eq!(devices.len(), 2);
+
+            for &device in &devices {
+                assert_eq!(db.get_dev_attached(device), Some(machine));
+
+                match db.get_dev_type(device) {
+                    Some(DeviceType::Compute(ComputeDeviceType::Cpu)) => {
+                        assert_eq!(db.get_dev_cpu(device), Some(3))
+                    }
+                    Some(DeviceType::Compute(ComputeDeviceType::Mem)) => {
+                        assert_eq!(db.get_dev_mem(device), Some(128 * MIB))
+                    }
+                    other => panic!("machine came with a {:?} device", other),
+                }
+            }
+
+            let domain = c.get_domain_xml(machine).unwrap();
+            assert_eq!(domain.vcpu.amount, 3);
+
+            c.destroy(machine).unwrap();
+
+            assert!(!db.is_known_machine(machine));
+            assert!(c.get_domain(machine).is_none());
+            assert_eq!(db.get_dev_attached_to(machine).count(), 0);
+
+            for device in devices {
+                assert!(db.get_dev_type(device).is_none());
+                assert!(db.get_dev_attached(device).is_none());
+            }
+
+            let err = c.destroy(machine).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::NotFound);
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn block_devices_attach_and_detach() {
+    let test = TestComponent::new();
+
+    test.puck
+        .with(|c| {
+            let block = Uuid::new_v4();
+            c.create_block(block, MIB).unwrap();
+
+            let path = c.path_for_block_device(block);
+            assert!(path.starts_with(&c.block_path_dir));
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), MIB);
+
+            let db = c.db();
+            assert!(matches!(
+                db.get_dev_type(block),
+                Some(DeviceType::Storage(StorageDeviceType::Block))
+            ));
+            assert_eq!(db.get_dev_block_cap(block), Some(MIB));
+
+            let machine = c.create(MachineSpec::default()).unwrap();
+
+            c.attach_device(machine, block).unwrap();
+
+            assert_eq!(db.get_dev_attached(block), Some(machine));
+            assert!(db.get_dev_attached_to(machine).any(|d| d == block));
+
+            let disks = c.get_domain_xml(machine).unwrap().devices.disks;
+            assert!(disks.iter().any(|d| d.alias == Some(Alias::user(block))));
+
+            let err = c.attach_device(machine, block).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::AlreadyAttached);
+
+            let err = c.delete_block(block).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::AlreadyAttached);
+
+            c.detach_device(machine, block).unwrap();
+
+            assert!(db.get_dev_attached(block).is_none());
+            assert!(!db.get_dev_attached_to(machine).any(|d| d == block));
+
+            let disks = c.get_domain_xml(machine).unwrap().devices.disks;
+            assert!(disks.is_empty());
+
+            c.destroy(machine).unwrap();
+            c.delete_block(block).unwrap();
+
+            assert!(!path.exists());
+            assert!(db.get_dev_type(block).is_none());
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn adopted_block_paths_are_canonical() {
+    let test = TestComponent::new();
+
+    let outside = test.dir.path().join("outside.qcow2");
+    std::fs::File::create(&outside)
+        .unwrap()
+        .set_len(MIB)
+        .unwrap();
+
+    test.puck
+        .with(move |c| {
+            // starts with the block directory, but isn't in it
+            let escaped = c.block_path_dir.join("..").join("outside.qcow2");
+            assert!(!c.owns_block_file(&escaped));
+
+            let machine = Uuid::new_v4();
+
+            c.define_domain_xml(&format!(
+                "<domain type='test'>
+                  <name>adopt-{machine}</name>
+                  <uuid>{machine}</uuid>
+                  <memory unit='KiB'>131072</memory>
+                  <vcpu>1</vcpu>
+                  <os><type>hvm</type></os>
+                  <devices>
+                    <disk type='file' device='disk'>
+                      <driver name='qemu' type='qcow2'/>
+                      <source file='{file}'/>
+                      <target dev='vda' bus='virtio'/>
+                    </disk>
+                  </devices>
+                </domain>",
+                machine = machine,
+                file = escaped.display(),
+            ))
+            .unwrap();
+
+            let err = c.adopt(machine, ExternalDisks::Reject).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::InvalidArgument);
+
+            let devices = c.adopt(machine, ExternalDisks::Reference).unwrap();
+
+            let db = c.db();
+            let block = devices
+                .into_keys()
+                .find(|&d| {
+                    matches!(
+                        db.get_dev_type(d),
+                        Some(DeviceType::Storage(StorageDeviceType::Block))
+                    )
+                })
+                .unwrap();
+
+            assert_eq!(
+                db.get_dev_block_path(block).map(PathBuf::from),
+                Some(outside.canonicalize().unwrap())
+            );
+
+            c.destroy(machine).unwrap();
+            c.delete_block(block).unwrap();
+
+            // only referenced, so it's left alone
+            assert!(outside.exists());
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nat_network_is_started() {
+    let test = TestComponent::new();
+
+    test.puck
+        .with(|c| {
+            let nat = c.create_nat().unwrap();
+
+            let network =
+                virt::network::Network::lookup_by_name(&c.conn, NAT_NETWORK_NAME).unwrap();
+            assert!(network.is_active().unwrap());
+            assert!(network.get_autostart().unwrap());
+
+            let defined = xml::Network::from_str(&network.get_xml_desc(0).unwrap()).unwrap();
+            assert!(matches!(
+                defined.forward.and_then(|f| f.mode),
+                Some(xml::ForwardMode::NAT)
+            ));
+            assert_eq!(defined.ips[0].address.as_deref(), Some("192.168.100.1"));
+
+            c.delete_nat(nat).unwrap();
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nat_interfaces_detach_by_alias() {
+    let test = TestComponent::new();
+
+    test.puck
+        .with(|c| {
+            let a = c.create_nat().unwrap();
+            let b = c.create_nat().unwrap();
+
+            let machine = c.create(MachineSpec::default()).unwrap();
+
+            c.attach_device(machine, a).unwrap();
+            c.attach_device(machine, b).unwrap();
+
+            c.detach_device(machine, a).unwrap();
+
+            let interfaces = c.get_domain_xml(machine).unwrap().devices.interfaces;
+            assert_eq!(interfaces.len(), 1);
+            assert_eq!(interfaces[0].alias, Some(Alias::user(b)));
+
+            c.attach_device(machine, a).unwrap();
+
+            // as if attached before aliases were set
+            c.edit(machine, |d| {
+                for interface in &mut d.devices.interfaces {
+                    interface.alias = None;
+                }
+            })
+            .unwrap();
+
+            let err = c.detach_device(machine, a).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::InvalidState);
+
+            c.db().del_dev_attached(b);
+            c.edit(machine, |d| {
+                d.devices.interfaces.pop();
+            })
+            .unwrap();
+
+            // the only one left
+            c.detach_device(machine, a).unwrap();
+            assert!(c
+                .get_domain_xml(machine)
+                .unwrap()
+                .devices
+                .interfaces
+                .is_empty());
+
+            c.destroy(machine).unwrap();
+            c.delete_nat(a).unwrap();
+            c.delete_nat(b).unwrap();
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exports_import_into_a_fresh_database() {
+    let from = TestComponent::new();
+    let archive = from.dir.path().join("archive").display().to_string();
+
+    let (machine, block) = from
+        .puck
+        .with(|c| {
+            let machine = c.create(MachineSpec::default()).unwrap();
+
+            let block = Uuid::new_v4();
+            c.create_block(block, MIB).unwrap();
+            c.attach_device(machine, block).unwrap();
+
+            c.set_attr(machine, "owner", AttrValue::String("ci".into()))
+                .unwrap();
+
+            (machine, block)
+        })
+        .await;
+
+    let export = from
+        .puck
+        .start_export(Export {
+            path: archive.clone(),
+            images: true,
+        })
+        .unwrap();
+    succeeded(&from.puck, export).await;
+
+    // the driver is shared, the machine has to be gone before it can be imported again
+    from.puck
+        .with(move |c| {
+            c.destroy(machine).unwrap();
+            c.delete_block(block).unwrap();
+        })
+        .await;
+
+    let to = TestComponent::new();
+
+    let import = to.puck.start_import(Import { path: archive }).unwrap();
+    let contents: Contents =
+        serde_json::from_value(succeeded(&to.puck, import).await.result.unwrap()).unwrap();
+
+    assert_eq!(contents.machines, [machine]);
+    assert_eq!(contents.devices.len(), 3);
+    assert!(contents.devices.contains(&block));
+
+    to.puck
+        .with(move |c| {
+            let db = c.db();
+            assert!(db.is_known_machine(machine));
+            assert_eq!(db.get_dev_attached_to(machine).count(), 3);
+            assert_eq!(db.get_dev_attached(block), Some(machine));
+            assert_eq!(db.get_dev_block_cap(block), Some(MIB));
+            assert_eq!(
+                db.get_machine_attrs(machine).collect::<Vec<_>>(),
+                [("owner".to_string(), AttrValue::String("ci".into()))]
+            );
+
+            // the image was copied into this component's block directory
+            let path = c.path_for_block_device(block);
+            assert!(path.starts_with(&c.block_path_dir));
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), MIB);
+
+            let disks = c.get_domain_xml(machine).unwrap().devices.disks;
+            let disk = disks
+                .iter()
+                .find(|d| d.alias == Some(Alias::user(block)))
+                .unwrap();
+            assert_eq!(
+                disk.source.as_ref().and_then(|s| s.file.as_deref()),
+                path.to_str()
+            );
+
+            c.destroy(machine).unwrap();
+            c.delete_block(block).unwrap();
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dry_runs_repair_nothing() {
+    let test = TestComponent::new();
+
+    let machine = test
+        .puck
+        .with(|c| {
+            let machine = c.create(MachineSpec::default()).unwrap();
+            c.undefine_domain(machine).unwrap();
+
+            machine
+        })
+        .await;
+
+    let lost = |repaired| Finding {
+        drift: Drift::MachineLost { machine },
+        repaired,
+    };
+
+    let report = test.puck.reconcile_pass(false).await;
+    assert_eq!(report.findings, [lost(false)]);
+    assert!(
+        !test
+            .puck
+            .with(move |c| c.db().is_lost_machine(machine))
+            .await
+    );
+
+    let report = test.puck.reconcile_pass(true).await;
+    assert_eq!(report.findings, [lost(true)]);
+    assert!(
+        test.puck
+            .with(move |c| c.db().is_lost_machine(machine))
+            .await
+    );
+
+    // already recorded
+    let report = test.puck.reconcile_pass(true).await;
+    assert!(report.findings.is_empty());
+}
+
+#[test]
+fn domain_states_map_to_machine_states() {
+    use istruct_common::api::compute::machine::v1::MachineState as S;
+    use virt::domain::{
+        VIR_DOMAIN_BLOCKED, VIR_DOMAIN_CRASHED, VIR_DOMAIN_NOSTATE, VIR_DOMAIN_PAUSED,
+        VIR_DOMAIN_PMSUSPENDED, VIR_DOMAIN_RUNNING, VIR_DOMAIN_SHUTDOWN, VIR_DOMAIN_SHUTOFF,
+    };
+
+    let table = [
+        (VIR_DOMAIN_NOSTATE, 0, S::Off, None),
+        (VIR_DOMAIN_BLOCKED, 0, S::Blocked, None),
+        (VIR_DOMAIN_RUNNING, 0, S::Running, None),
+        (VIR_DOMAIN_RUNNING, 1, S::Running, Some("booted")),
+        (VIR_DOMAIN_RUNNING, 2, S::Running, Some("migrated")),
+        (VIR_DOMAIN_RUNNING, 3, S::Running, Some("restored")),
+        (VIR_DOMAIN_RUNNING, 4, S::Running, Some("from_snapshot")),
+        (VIR_DOMAIN_RUNNING, 5, S::Running, Some("unpaused")),
+        (
+            VIR_DOMAIN_RUNNING,
+            6,
+            S::Running,
+            Some("migration_canceled"),
+        ),
+        (VIR_DOMAIN_RUNNING, 7, S::Running, Some("save_canceled")),
+        (VIR_DOMAIN_RUNNING, 8, S::Running, Some("wakeup")),
+        (VIR_DOMAIN_RUNNING, 9, S::Running, Some("crashed")),
+        (VIR_DOMAIN_RUNNING, 10, S::Running, Some("postcopy")),
+        (VIR_DOMAIN_RUNNING, 11, S::Running, Some("postcopy_failed")),
+        // past the known reasons
+        (VIR_DOMAIN_RUNNING, 12, S::Running, None),
+        (VIR_DOMAIN_PAUSED, 0, S::Suspended, None),
+        (VIR_DOMAIN_PAUSED, 1, S::Suspended, Some("user")),
+        (VIR_DOMAIN_PAUSED, 2, S::Suspended, Some("migration")),
+        (VIR_DOMAIN_PAUSED, 3, S::Suspended, Some("save")),
+        (VIR_DOMAIN_PAUSED, 4, S::Suspended, Some("dump")),
+        (VIR_DOMAIN_PAUSED, 5, S::Suspended, Some("ioerror")),
+        (VIR_DOMAIN_PAUSED, 6, S::Suspended, Some("watchdog")),
+        (VIR_DOMAIN_PAUSED, 7, S::Suspended, Some("from_snapshot")),
+        (VIR_DOMAIN_PAUSED, 8, S::Suspended, Some("shutting_down")),
+        (VIR_DOMAIN_PAUSED, 9, S::Suspended, Some("snapshot")),
+        (VIR_DOMAIN_PAUSED, 10, S::Suspended, Some("api_error")),
+        (VIR_DOMAIN_PAUSED, 11, S::Starting, Some("starting_up")),
+        (VIR_DOMAIN_PAUSED, 12, S::Suspended, Some("postcopy")),
+        (VIR_DOMAIN_PAUSED, 13, S::Suspended, Some("postcopy_failed")),
+        // past the known reasons
+        (VIR_DOMAIN_PAUSED, 14, S::Suspended, None),
+        (VIR_DOMAIN_SHUTDOWN, 0, S::ShuttingDown, None),
+        (VIR_DOMAIN_SHUTDOWN, 1, S::ShuttingDown, Some("user")),
+        // past the known reasons
+        (VIR_DOMAIN_SHUTDOWN, 2, S::ShuttingDown, None),
+        (VIR_DOMAIN_SHUTOFF, 0, S::Off, None),
+        (VIR_DOMAIN_SHUTOFF, 1, S::Off, Some("shutdown")),
+        (VIR_DOMAIN_SHUTOFF, 2, S::Off, Some("destroyed")),
+        (VIR_DOMAIN_SHUTOFF, 3, S::Off, Some("crashed")),
+        (VIR_DOMAIN_SHUTOFF, 4, S::Off, Some("migrated")),
+        (VIR_DOMAIN_SHUTOFF, 5, S::Off, Some("saved")),
+        (VIR_DOMAIN_SHUTOFF, 6, S::Off, Some("failed")),
+        (VIR_DOMAIN_SHUTOFF, 7, S::Off, Some("from_snapshot")),
+        (VIR_DOMAIN_SHUTOFF, 8, S::Off, Some("daemon")),
+        // past the known reasons
+        (VIR_DOMAIN_SHUTOFF, 9, S::Off, None),
+        (VIR_DOMAIN_CRASHED, 0, S::Crashed, None),
+        (VIR_DOMAIN_CRASHED, 1, S::Crashed, Some("panicked")),
+        // past the known reasons
+        (VIR_DOMAIN_CRASHED, 2, S::Crashed, None),
+        (VIR_DOMAIN_PMSUSPENDED, 0, S::PmSuspended, None),
+        // past the known reasons
+        (VIR_DOMAIN_PMSUSPENDED, 1, S::PmSuspended, None),
+    ];
+
+    for (state, reason, expected, expected_reason) in table {
+        let status = virt_domainstate_to_istruct(state, reason);
+
+        assert_eq!(
+            (status.state, status.reason.as_deref()),
+            (expected, expected_reason),
+            "state {} reason {}",
+            state,
+            reason
+        );
+    }
+
+    let status = virt_domainstate_to_istruct(VIR_DOMAIN_RUNNING, -1);
+    assert_eq!((status.state, status.reason), (S::Running, None));
+
+    let status = virt_domainstate_to_istruct(42, 0);
+    assert_eq!(status.state, S::Error);
+    assert_eq!(status.reason.as_deref(), Some("unknown libvirt state 42"));
+}
@@ -0,0 +1,101 @@
+//! Worker threads, each with its own [`Client`].
+//!
+//! libvirt connections can't move between threads, so every worker opens its own,
+//! and jobs are sent to whichever worker is free.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+};
+
+use tokio::sync::oneshot;
+
+use super::Client;
+
+type Job = Box<dyn FnOnce(&Client) + Send>;
+
+#[derive(Debug, Clone)]
+pub struct Workers {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Workers {
+    /// Spawns `count` workers, and waits until all of them have a client.
+    ///
+    /// `connect` is called once per worker, with the index of that worker.
+    pub fn spawn<F>(count: usize, connect: F) -> anyhow::Result<Self>
+    where
+        F: Fn(usize) -> anyhow::Result<Client> + Send + Sync + 'static,
+    {
+        if count == 0 {
+            anyhow::bail!("need at least one worker");
+        }
+
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let connect = Arc::new(connect);
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        for idx in 0..count {
+            let rx = rx.clone();
+            let connect = connect.clone();
+            let ready = ready_tx.clone();
+
+            std::thread::Builder::new()
+                .name(format!("libvirt-worker-{}", idx))
+                .spawn(move || {
+                    let client = match connect(idx) {
+                        Ok(c) => c,
+                        Err(e) => {
+                            let _ = ready.send(Err(e));
+                            return;
+                        }
+                    };
+
+                    let _ = ready.send(Ok(()));
+
+                    loop {
+                        // only held while waiting, not while running the job
+                        let job = rx.lock().unwrap().recv();
+
+                        match job {
+                            Ok(job) => job(&client),
+                            // every sender is gone
+                            Err(_) => return,
+                        }
+                    }
+                })?;
+        }
+
+        drop(ready_tx);
+
+        for _ in 0..count {
+            ready_rx.recv()??;
+        }
+
+        Ok(Self { jobs })
+    }
+
+    /// Runs `f` on a free worker.
+    ///
+    /// Panics in `f` are resumed here, the worker itself keeps running.
+    pub async fn run<R, F>(&self, f: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&Client) -> R + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        self.jobs
+            .send(Box::new(move |c| {
+                let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(|| f(c))));
+            }))
+            .expect("workers are running");
+
+        match rx.await.expect("worker finishes every job") {
+            Ok(r) => r,
+            Err(p) => panic::resume_unwind(p),
+        }
+    }
+}
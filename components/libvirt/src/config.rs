@@ -0,0 +1,323 @@
+//! Configuration of the component binary.
+//!
+//! Read from a TOML file, then overridden by environment variables, then by flags.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::Parser;
+use serde::Deserialize;
+
+/// Every API this component can serve, by prefix.
+pub const APIS: [&str; 8] = [
+    "is.compute.machine",
+    "is.compute.machine.device",
+    "is.compute.devadm",
+    "is.storage.device",
+    "is.network.device",
+    "is.operation",
+    "is.reconcile",
+    "is.backup",
+];
+
+#[derive(Debug, Parser)]
+#[command(version, about = "istruct component for libvirt")]
+pub struct Args {
+    /// TOML configuration file, see `config.example.toml`.
+    #[arg(short, long, env = "ISTRUCT_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Libvirt connection URI.
+    #[arg(long, env = "ISTRUCT_URI")]
+    pub uri: Option<String>,
+
+    /// Database file, created if it doesn't exist.
+    #[arg(long, env = "ISTRUCT_DATABASE")]
+    pub database: Option<PathBuf>,
+
+    /// Directory of block device images.
+    #[arg(long, env = "ISTRUCT_BLOCK_DIR")]
+    pub block_dir: Option<PathBuf>,
+
+    /// Address to listen on, repeat or separate with commas for more.
+    #[arg(long, env = "ISTRUCT_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<SocketAddr>,
+
+    /// API to serve by prefix (e.g. `is.compute.machine`), repeat or separate with commas for more.
+    #[arg(long = "api", env = "ISTRUCT_APIS", value_delimiter = ',')]
+    pub apis: Vec<String>,
+
+    /// Log level or filter, e.g. `info` or `istruct_libvirt=debug,warn`.
+    #[arg(long, env = "ISTRUCT_LOG")]
+    pub log: Option<String>,
+
+    /// Threads talking to libvirt.
+    #[arg(long, env = "ISTRUCT_WORKERS")]
+    pub workers: Option<usize>,
+
+    /// Seconds a machine action waits for the machine to reach its state.
+    #[arg(long, env = "ISTRUCT_ACT_TIMEOUT")]
+    pub act_timeout: Option<u64>,
+
+    /// Seconds between reconciliation passes, 0 turns them off.
+    #[arg(long, env = "ISTRUCT_RECONCILE_INTERVAL")]
+    pub reconcile_interval: Option<u64>,
+
+    /// Repair drift found by the periodic passes, instead of only reporting it.
+    #[arg(long, env = "ISTRUCT_RECONCILE_REPAIR")]
+    pub reconcile_repair: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub uri: String,
+    pub database: PathBuf,
+    pub block_dir: PathBuf,
+    pub listen: Vec<SocketAddr>,
+    /// Defaults to every API in [`APIS`].
+    pub apis: Vec<String>,
+    pub log: String,
+    pub workers: usize,
+    /// Seconds, see [`Config::act_timeout`].
+    pub act_timeout: u64,
+    pub reconcile: Reconcile,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Reconcile {
+    /// Seconds, 0 turns periodic passes off.
+    pub interval: u64,
+    pub repair: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            uri: "qemu:///system".into(),
+            database: "../istruct_data/db.persy".into(),
+            block_dir: "../istruct_data/block/".into(),
+            listen: vec![SocketAddr::from(([0u16; 8], 8989))],
+            apis: APIS.iter().map(|a| a.to_string()).collect(),
+            log: "info".into(),
+            workers: 4,
+            act_timeout: 5 * 60,
+            reconcile: Reconcile::default(),
+        }
+    }
+}
+
+impl Default for Reconcile {
+    fn default() -> Self {
+        Self {
+            // only reported by default
+            interval: 5 * 60,
+            repair: false,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the file of `args`, if any, and applies the rest of `args` on top.
+    pub fn load(args: Args) -> anyhow::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::read(path)?,
+            None => Self::default(),
+        };
+
+        if let Some(uri) = args.uri {
+            config.uri = uri;
+        }
+
+        if let Some(database) = args.database {
+            config.database = database;
+        }
+
+        if let Some(block_dir) = args.block_dir {
+            config.block_dir = block_dir;
+        }
+
+        if !args.listen.is_empty() {
+            config.listen = args.listen;
+        }
+
+        if !args.apis.is_empty() {
+            config.apis = args.apis;
+        }
+
+        if let Some(log) = args.log {
+            config.log = log;
+        }
+
+        if let Some(workers) = args.workers {
+            config.workers = workers;
+        }
+
+        if let Some(timeout) = args.act_timeout {
+            config.act_timeout = timeout;
+        }
+
+        if let Some(interval) = args.reconcile_interval {
+            config.reconcile.interval = interval;
+        }
+
+        if let Some(repair) = args.reconcile_repair {
+            config.reconcile.repair = repair;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+
+        toml::from_str(&s).with_context(|| format!("failed to parse config {}", path.display()))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.listen.is_empty() {
+            anyhow::bail!("need at least one address to listen on");
+        }
+
+        if self.workers == 0 {
+            anyhow::bail!("need at least one worker");
+        }
+
+        if self.act_timeout == 0 {
+            anyhow::bail!("machine actions need a timeout of at least one second");
+        }
+
+        for api in &self.apis {
+            if !APIS.contains(&api.as_str()) {
+                anyhow::bail!("unknown api {}, known are {}", api, APIS.join(", "));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn act_timeout(&self) -> Duration {
+        Duration::from_secs(self.act_timeout)
+    }
+
+    pub fn reconcile_interval(&self) -> Option<Duration> {
+        (self.reconcile.interval > 0).then(|| Duration::from_secs(self.reconcile.interval))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::PathBuf, time::Duration};
+
+    use clap::Parser;
+    use tempfile::NamedTempFile;
+
+    use super::{Args, Config, APIS};
+
+    fn load(args: &[&str]) -> anyhow::Result<Config> {
+        Config::load(Args::try_parse_from(
+            ["istruct-libvirt"].iter().chain(args),
+        )?)
+    }
+
+    /// A config file with `content`, removed on drop.
+    fn file(content: &str) -> NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+
+        file
+    }
+
+    #[test]
+    fn defaults_without_file() {
+        let config = load(&[]).unwrap();
+
+        assert_eq!(config.uri, "qemu:///system");
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.act_timeout(), Duration::from_secs(5 * 60));
+        assert_eq!(config.apis, APIS);
+        assert_eq!(config.reconcile.interval, 5 * 60);
+        assert!(!config.reconcile.repair);
+    }
+
+    #[test]
+    fn flags_override_file_overrides_defaults() {
+        let file = file(
+            r#"
+            uri = "test:///default"
+            workers = 2
+            apis = ["is.compute.machine"]
+
+            [reconcile]
+            repair = true
+            "#,
+        );
+
+        let config = load(&["--config", file.path().to_str().unwrap(), "--workers", "8"]).unwrap();
+
+        // flag
+        assert_eq!(config.workers, 8);
+        // file
+        assert_eq!(config.uri, "test:///default");
+        assert_eq!(config.apis, ["is.compute.machine"]);
+        assert!(config.reconcile.repair);
+        // default, also within a table the file sets
+        assert_eq!(config.database, PathBuf::from("../istruct_data/db.persy"));
+        assert_eq!(config.reconcile.interval, 5 * 60);
+
+        let config = load(&[
+            "--config",
+            file.path().to_str().unwrap(),
+            "--reconcile-repair",
+            "false",
+            "--api",
+            "is.operation,is.backup",
+        ])
+        .unwrap();
+
+        assert!(!config.reconcile.repair);
+        assert_eq!(config.apis, ["is.operation", "is.backup"]);
+    }
+
+    #[test]
+    fn missing_file_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.toml");
+
+        let err = load(&["--config", path.to_str().unwrap()]).unwrap_err();
+        assert!(
+            err.to_string().starts_with("failed to read config"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn unknown_keys_fail() {
+        for content in ["urii = \"test:///default\"", "[reconcile]\nintervall = 1"] {
+            let file = file(content);
+
+            let err = load(&["--config", file.path().to_str().unwrap()]).unwrap_err();
+            assert!(
+                err.to_string().starts_with("failed to parse config"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_values_fail() {
+        assert!(load(&["--workers", "0"]).is_err());
+        assert!(load(&["--act-timeout", "0"]).is_err());
+        assert!(load(&["--api", "is.nothing"]).is_err());
+    }
+}
@@ -1,46 +1,87 @@
-use istruct_common::{api, router::CompositeRouter};
-use istruct_libvirt::client::{Client, ClientPuck};
+use anyhow::Context;
+use clap::Parser;
+use futures_util::future::try_join_all;
+use istruct_common::{
+    api,
+    router::{ComponentIdentity, CompositeRouter, VersionedRouter},
+};
+use istruct_libvirt::client::{Backend, ClientPuck};
+use tracing_subscriber::EnvFilter;
+
+mod config;
+
+use config::{Args, Config};
 
 fn main() -> anyhow::Result<()> {
     use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 
-    let puck = ClientPuck::create(|| {
-        Client::new(
-            "qemu:///system",
-            "../istruct_data/db.persy",
-            "../istruct_data/block/",
-        )
-        .unwrap()
-    });
-
-    let compute_api = api::compute::machine::v1::convert(puck.clone());
-    let compute_dev_api = api::compute::machine::device::v1::convert(puck.clone());
-    let devadm_api = api::compute::devadm::v1::convert(puck.clone());
-    let storage_dev_api = api::storage::device::v1::convert(puck.clone());
-    let network_dev_api = api::network::device::v1::convert(puck.clone());
-
-    let composite = CompositeRouter::new_with([
-        compute_api,
-        compute_dev_api,
-        devadm_api,
-        storage_dev_api,
-        network_dev_api,
-    ])?;
-
-    tracing_subscriber::fmt::init();
-
-    let router = dbg!(composite.assemble())
+    let config = Config::load(Args::parse())?;
+
+    let filter = EnvFilter::try_new(&config.log)
+        .with_context(|| format!("invalid log level {}", config.log))?;
+
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    let mut puck = ClientPuck::new(
+        &config.uri,
+        &config.database,
+        &config.block_dir,
+        config.workers,
+        Backend::default(),
+    )
+    .context("failed to start libvirt client")?;
+
+    puck.set_act_timeout(config.act_timeout());
+
+    let routers = config.apis.iter().map(|api| router(api, &puck));
+
+    let mut composite = CompositeRouter::new_with(routers)?;
+
+    composite.set_identity(ComponentIdentity::new(
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+    ));
+
+    let router = composite
+        .assemble()
         .layer(TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::default()));
 
     let rt = tokio::runtime::Runtime::new()?;
 
-    let sock = "[::]:8989".parse().unwrap();
+    if let Some(interval) = config.reconcile_interval() {
+        rt.spawn(puck.reconcile_every(interval, config.reconcile.repair));
+    }
 
     rt.block_on(async {
-        axum::Server::bind(&sock)
-            .serve(router.into_make_service())
-            .await
+        let mut servers = vec![];
+
+        for addr in &config.listen {
+            let server = axum::Server::try_bind(addr)
+                .with_context(|| format!("failed to listen on {}", addr))?
+                .serve(router.clone().into_make_service());
+
+            servers.push(server);
+        }
+
+        try_join_all(servers).await.context("server failed")
     })?;
 
     Ok(())
 }
+
+/// Only called with APIs from [`config::APIS`].
+fn router(api: &str, puck: &ClientPuck) -> VersionedRouter {
+    let puck = puck.clone();
+
+    match api {
+        "is.compute.machine" => api::compute::machine::v1::convert(puck),
+        "is.compute.machine.device" => api::compute::machine::device::v1::convert(puck),
+        "is.compute.devadm" => api::compute::devadm::v1::convert(puck),
+        "is.storage.device" => api::storage::device::v1::convert(puck),
+        "is.network.device" => api::network::device::v1::convert(puck),
+        "is.operation" => api::operation::v1::convert(puck.ops.clone()),
+        "is.reconcile" => api::reconcile::v1::convert(puck),
+        "is.backup" => api::backup::v1::convert(puck),
+        _ => unreachable!("config is validated"),
+    }
+}
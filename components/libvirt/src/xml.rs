@@ -2,9 +2,11 @@ use serde::{Deserialize, Serialize};
 
 pub mod domain;
 pub mod network;
+mod unmodelled;
 
 pub use domain::*;
 pub use network::*;
+pub use unmodelled::Unmodelled;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -19,3 +21,6 @@ pub enum YesNo {
     Yes,
     No,
 }
+
+#[cfg(test)]
+mod tests;
@@ -1,4 +1,4 @@
-use super::{OnOff, YesNo};
+use super::{OnOff, Unmodelled, YesNo};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -32,23 +32,42 @@ pub struct Domain {
 
     pub os: OperatingSystem,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub features: Option<Features>,
 
     pub devices: Devices,
+
+    /// Everything [`Domain::from_str`] didn't model, [`Domain::to_string`] writes it back.
+    #[serde(skip)]
+    pub unmodelled: Unmodelled,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct DomainDoc {
-    domain: Domain,
+struct DomainDoc<D> {
+    domain: D,
 }
 
 impl Domain {
     pub fn to_string(self) -> Result<String, xml_serde::Error> {
-        xml_serde::to_string(&DomainDoc { domain: self })
+        self.unmodelled.restore(self.serialize()?)
     }
 
     pub fn from_str(s: &str) -> Result<Self, xml_serde::Error> {
-        xml_serde::from_str::<DomainDoc>(s).map(|dd| dd.domain)
+        let mut domain = xml_serde::from_str::<DomainDoc<Domain>>(s)?.domain;
+
+        domain.unmodelled = Unmodelled::new(s, &domain.serialize()?)?;
+
+        Ok(domain)
+    }
+
+    /// Only the model, libvirt doesn't use the `xsi` namespace.
+    fn serialize(&self) -> Result<String, xml_serde::Error> {
+        xml_serde::to_string_custom(
+            &DomainDoc { domain: self },
+            xml_serde::Options {
+                include_schema_location: false,
+            },
+        )
     }
 }
 
@@ -202,6 +221,12 @@ pub struct Memory {
     pub amount: usize,
 }
 
+impl Memory {
+    pub fn bytes(&self) -> u64 {
+        self.amount as u64 * self.unit.bytes()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MaxMemory {
     #[serde(rename = "$attr:unit")]
@@ -248,9 +273,28 @@ pub enum Unit {
     T, // GiB
 }
 
+impl Unit {
+    /// Bytes per unit.
+    pub fn bytes(&self) -> u64 {
+        match self {
+            Unit::Bytes | Unit::B => 1,
+            Unit::KB => 1000,
+            Unit::KiB | Unit::K => 1 << 10,
+            Unit::MB => 1_000_000,
+            Unit::MiB | Unit::M => 1 << 20,
+            Unit::GB => 1_000_000_000,
+            Unit::GiB | Unit::G => 1 << 30,
+            Unit::TB => 1_000_000_000_000,
+            Unit::TiB | Unit::T => 1 << 40,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Devices {
-    pub emulator: String,
+    /// Left out by drivers that don't run an emulator, like `test`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emulator: Option<String>,
 
     #[serde(rename = "disk", default, skip_serializing_if = "Vec::is_empty")]
     pub disks: Vec<Disk>,
@@ -330,6 +374,9 @@ pub struct Disk {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub product: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<Alias>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub address: Option<Address>,
     // todo: auth
@@ -337,6 +384,18 @@ pub struct Disk {
     // todo: blockio
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct DiskDoc {
+    disk: Disk,
+}
+
+impl Disk {
+    /// Serializes as standalone `<disk>`, for live device updates.
+    pub fn to_string(self) -> Result<String, xml_serde::Error> {
+        xml_serde::to_string(&DiskDoc { disk: self })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum DiskType {
@@ -768,6 +827,21 @@ pub struct Address {
     // todo: irq
 }
 
+/// Device alias, user-defined ones have to start with `ua-`, and are kept by libvirt.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Alias {
+    #[serde(rename = "$attr:name")]
+    pub name: String,
+}
+
+impl Alias {
+    pub fn user(name: impl std::fmt::Display) -> Self {
+        Self {
+            name: format!("ua-{}", name),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Controller {
     #[serde(rename = "$attr:type")]
@@ -864,7 +938,9 @@ pub struct NetworkInterface {
     // todo: coalesce
     // todo: rom
     // todo: acpi
-    // todo: alias
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<Alias>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub address: Option<Address>,
 }
@@ -903,7 +979,7 @@ pub struct FromToDevice {
     pub typ: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub source: Option<Source>,
+    pub source: Option<FromToSource>,
     pub target: FromToTarget,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -914,6 +990,9 @@ pub struct FromToDevice {
 pub struct FromToSource {
     #[serde(rename = "$attr:path", skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
+
+    #[serde(rename = "$attr:mode", skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -995,11 +1074,12 @@ pub struct Graphics {
     #[serde(rename = "$attr:type", skip_serializing_if = "Option::is_none")]
     pub typ: Option<String>,
 
+    /// `-1` until a port is allocated.
     #[serde(rename = "$attr:port", skip_serializing_if = "Option::is_none")]
-    pub port: Option<usize>,
+    pub port: Option<isize>,
 
     #[serde(rename = "$attr:tlsPort", skip_serializing_if = "Option::is_none")]
-    pub tls_port: Option<usize>,
+    pub tls_port: Option<isize>,
 
     #[serde(rename = "$attr:autoport", skip_serializing_if = "Option::is_none")]
     pub auto_port: Option<String>,
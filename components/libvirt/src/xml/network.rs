@@ -1,4 +1,4 @@
-use super::YesNo;
+use super::{OnOff, Unmodelled, YesNo};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -16,19 +16,272 @@ pub struct Network {
         skip_serializing_if = "Option::is_none"
     )]
     pub trust_guest_rx_filters: Option<YesNo>,
+
+    /// Isolated without one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forward: Option<Forward>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bridge: Option<Bridge>,
+
+    // todo: mtu
+    // todo: mac
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<NetworkDomain>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns: Option<Dns>,
+
+    #[serde(rename = "ip", default, skip_serializing_if = "Vec::is_empty")]
+    pub ips: Vec<Ip>,
+
+    // todo: route
+    // todo: bandwidth
+    // todo: portgroup
+    /// Everything [`Network::from_str`] didn't model, [`Network::to_string`] writes it back.
+    #[serde(skip)]
+    pub unmodelled: Unmodelled,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct NetworkDoc {
-    network: Network,
+struct NetworkDoc<N> {
+    network: N,
 }
 
 impl Network {
     pub fn to_string(self) -> Result<String, xml_serde::Error> {
-        xml_serde::to_string(&NetworkDoc { network: self })
+        self.unmodelled.restore(self.serialize()?)
     }
 
     pub fn from_str(s: &str) -> Result<Self, xml_serde::Error> {
-        xml_serde::from_str::<NetworkDoc>(s).map(|nd| nd.network)
+        let mut network = xml_serde::from_str::<NetworkDoc<Network>>(s)?.network;
+
+        network.unmodelled = Unmodelled::new(s, &network.serialize()?)?;
+
+        Ok(network)
+    }
+
+    /// Only the model, libvirt doesn't use the `xsi` namespace.
+    fn serialize(&self) -> Result<String, xml_serde::Error> {
+        xml_serde::to_string_custom(
+            &NetworkDoc { network: self },
+            xml_serde::Options {
+                include_schema_location: false,
+            },
+        )
     }
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Forward {
+    #[serde(rename = "$attr:mode", skip_serializing_if = "Option::is_none")]
+    pub mode: Option<ForwardMode>,
+
+    /// Restricts forwarding to this host interface.
+    #[serde(rename = "$attr:dev", skip_serializing_if = "Option::is_none")]
+    pub dev: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nat: Option<ForwardNat>,
+
+    /// Pool of host interfaces for `bridge`, `private`, `vepa` and `passthrough`.
+    #[serde(rename = "interface", default, skip_serializing_if = "Vec::is_empty")]
+    pub interfaces: Vec<ForwardInterface>,
+    // todo: pf
+    // todo: address (hostdev pool)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardMode {
+    NAT,
+    Route,
+    Open,
+    Bridge,
+    Private,
+    VEPA,
+    Passthrough,
+    HostDev,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForwardNat {
+    #[serde(rename = "$attr:ipv6", skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<YesNo>,
+
+    /// Public addresses to masquerade as.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<AddressRange>,
+
+    /// Source ports to masquerade with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<PortRange>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForwardInterface {
+    #[serde(rename = "$attr:dev")]
+    pub dev: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddressRange {
+    #[serde(rename = "$attr:start")]
+    pub start: String,
+
+    #[serde(rename = "$attr:end")]
+    pub end: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PortRange {
+    #[serde(rename = "$attr:start")]
+    pub start: u16,
+
+    #[serde(rename = "$attr:end")]
+    pub end: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bridge {
+    /// Picked by libvirt if left out, `virbr0`, `virbr1` and so on.
+    #[serde(rename = "$attr:name", skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(rename = "$attr:stp", skip_serializing_if = "Option::is_none")]
+    pub stp: Option<OnOff>,
+
+    #[serde(rename = "$attr:delay", skip_serializing_if = "Option::is_none")]
+    pub delay: Option<usize>,
+
+    #[serde(
+        rename = "$attr:macTableManager",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub mac_table_manager: Option<String>,
+
+    #[serde(rename = "$attr:zone", skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+}
+
+/// DNS domain of the DHCP clients.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NetworkDomain {
+    #[serde(rename = "$attr:name")]
+    pub name: String,
+
+    /// Answers queries for the domain locally instead of forwarding them.
+    #[serde(rename = "$attr:localOnly", skip_serializing_if = "Option::is_none")]
+    pub local_only: Option<YesNo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Dns {
+    #[serde(rename = "$attr:enable", skip_serializing_if = "Option::is_none")]
+    pub enable: Option<YesNo>,
+
+    #[serde(
+        rename = "$attr:forwardPlainNames",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub forward_plain_names: Option<YesNo>,
+
+    #[serde(rename = "forwarder", default, skip_serializing_if = "Vec::is_empty")]
+    pub forwarders: Vec<DnsForwarder>,
+
+    #[serde(rename = "txt", default, skip_serializing_if = "Vec::is_empty")]
+    pub txts: Vec<DnsTxt>,
+
+    #[serde(rename = "host", default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<DnsHost>,
+    // todo: srv
+}
+
+/// Without `addr`, queries for `domain` are answered locally.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DnsForwarder {
+    #[serde(rename = "$attr:addr", skip_serializing_if = "Option::is_none")]
+    pub addr: Option<String>,
+
+    #[serde(rename = "$attr:domain", skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DnsTxt {
+    #[serde(rename = "$attr:name")]
+    pub name: String,
+
+    #[serde(rename = "$attr:value")]
+    pub value: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DnsHost {
+    #[serde(rename = "$attr:ip")]
+    pub ip: String,
+
+    #[serde(rename = "hostname", default, skip_serializing_if = "Vec::is_empty")]
+    pub hostnames: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ip {
+    #[serde(rename = "$attr:address", skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+
+    /// IPv4 only, `prefix` works for both families.
+    #[serde(rename = "$attr:netmask", skip_serializing_if = "Option::is_none")]
+    pub netmask: Option<String>,
+
+    #[serde(rename = "$attr:prefix", skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<u8>,
+
+    /// `ipv4` if left out.
+    #[serde(rename = "$attr:family", skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+
+    #[serde(rename = "$attr:localPtr", skip_serializing_if = "Option::is_none")]
+    pub local_ptr: Option<YesNo>,
+
+    // todo: tftp
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dhcp: Option<Dhcp>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Dhcp {
+    #[serde(rename = "range", default, skip_serializing_if = "Vec::is_empty")]
+    pub ranges: Vec<DhcpRange>,
+
+    #[serde(rename = "host", default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<DhcpHost>,
+    // todo: bootp
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DhcpRange {
+    #[serde(rename = "$attr:start")]
+    pub start: String,
+
+    #[serde(rename = "$attr:end")]
+    pub end: String,
+    // todo: lease
+}
+
+/// Fixed address for a client, known by `mac` for IPv4 and by `id` for IPv6.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DhcpHost {
+    #[serde(rename = "$attr:mac", skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+
+    #[serde(rename = "$attr:id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+
+    #[serde(rename = "$attr:name", skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(rename = "$attr:ip", skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    // todo: lease
+}
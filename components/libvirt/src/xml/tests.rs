@@ -0,0 +1,171 @@
+//! `virsh dumpxml` and `net-dumpxml` outputs from real hosts have to survive a parse and write
+//! unchanged.
+
+use super::{
+    unmodelled::{Element, Node},
+    Domain, ForwardMode, Network,
+};
+
+const DOMAINS: &[(&str, &str)] = &[
+    ("q35-running", include_str!("corpus/q35-running.xml")),
+    ("i440fx-shutoff", include_str!("corpus/i440fx-shutoff.xml")),
+    ("test-driver", include_str!("corpus/test-driver.xml")),
+];
+
+const NETWORKS: &[(&str, &str)] = &[
+    (
+        "network-default",
+        include_str!("corpus/network-default.xml"),
+    ),
+    ("network-lab", include_str!("corpus/network-lab.xml")),
+];
+
+/// Attribute order carries no meaning, everything else has to match.
+fn canonical(xml: &str) -> Element {
+    fn sort(element: &mut Element) {
+        element.attributes.sort();
+
+        for child in &mut element.children {
+            if let Node::Element(e) = child {
+                sort(e);
+            }
+        }
+    }
+
+    let mut element = Element::parse(xml).unwrap();
+    sort(&mut element);
+
+    element
+}
+
+fn children<'a>(element: &'a Element, name: &'a str) -> impl Iterator<Item = &'a Element> {
+    element.children.iter().filter_map(move |c| match c {
+        Node::Element(e) if e.name == name => Some(e),
+        _ => None,
+    })
+}
+
+fn child<'a>(element: &'a Element, name: &'a str) -> &'a Element {
+    children(element, name)
+        .next()
+        .unwrap_or_else(|| panic!("<{}> has no <{}>", element.name, name))
+}
+
+/// Compared as text, for a readable diff.
+fn assert_same(name: &str, written: &str, xml: &str) {
+    assert_eq!(
+        canonical(written).write().unwrap(),
+        canonical(xml).write().unwrap(),
+        "{} changed",
+        name
+    );
+}
+
+#[test]
+fn domains_round_trip() {
+    for (name, xml) in DOMAINS {
+        let domain = Domain::from_str(xml).unwrap_or_else(|e| panic!("{}: {:?}", name, e));
+
+        assert_same(name, &domain.to_string().unwrap(), xml);
+    }
+}
+
+#[test]
+fn networks_round_trip() {
+    for (name, xml) in NETWORKS {
+        let network = Network::from_str(xml).unwrap_or_else(|e| panic!("{}: {:?}", name, e));
+
+        assert_same(name, &network.to_string().unwrap(), xml);
+    }
+}
+
+#[test]
+fn networks_are_modelled() {
+    let network = Network::from_str(NETWORKS[1].1).unwrap();
+
+    let forward = network.forward.unwrap();
+    assert!(matches!(forward.mode, Some(ForwardMode::NAT)));
+    assert_eq!(forward.nat.unwrap().port.unwrap().end, 40000);
+    assert_eq!(forward.interfaces[0].dev, "eno1");
+
+    assert_eq!(network.bridge.unwrap().name.as_deref(), Some("virbr7"));
+    assert_eq!(network.domain.unwrap().name, "lab.example.com");
+
+    let dns = network.dns.unwrap();
+    assert_eq!(dns.forwarders.len(), 2);
+    assert_eq!(dns.txts[0].value, "nightly");
+    assert_eq!(dns.hosts[0].hostnames, ["ci", "ci.lab.example.com"]);
+
+    assert_eq!(network.ips.len(), 2);
+
+    let dhcp = network.ips[0].dhcp.as_ref().unwrap();
+    assert_eq!(dhcp.ranges[0].start, "10.7.0.100");
+    assert_eq!(dhcp.hosts.len(), 2);
+    assert_eq!(dhcp.hosts[0].name.as_deref(), Some("ci"));
+
+    assert_eq!(network.ips[1].prefix, Some(64));
+    assert_eq!(
+        network.ips[1].dhcp.as_ref().unwrap().hosts[0].id.as_deref(),
+        Some("0:3:0:1:0:16:3e:11:22:33")
+    );
+}
+
+#[test]
+fn edits_keep_unmodelled() {
+    let mut domain = Domain::from_str(DOMAINS[0].1).unwrap();
+
+    domain.vcpu.amount = 2;
+    domain.current_memory = None;
+    domain
+        .devices
+        .disks
+        .retain(|d| d.target.as_ref().unwrap().dev != "vda");
+
+    let xml = canonical(&domain.to_string().unwrap());
+
+    assert_eq!(child(&xml, "vcpu").children, [Node::Text("2".into())]);
+    assert_eq!(children(&xml, "currentMemory").count(), 0);
+
+    for unmodelled in ["metadata", "cpu", "clock", "on_poweroff", "pm", "seclabel"] {
+        child(&xml, unmodelled);
+    }
+
+    let devices = child(&xml, "devices");
+
+    let disks: Vec<_> = children(devices, "disk").collect();
+    assert_eq!(disks.len(), 1);
+    assert_eq!(
+        child(disks[0], "alias").attributes,
+        [("name".to_string(), "sata0-0-0".to_string())]
+    );
+    assert!(child(disks[0], "source")
+        .attributes
+        .contains(&("index".to_string(), "1".to_string())));
+    assert_eq!(children(disks[0], "backingStore").count(), 1);
+
+    for unmodelled in ["memballoon", "video", "rng", "sound"] {
+        child(devices, unmodelled);
+    }
+
+    let interface = child(devices, "interface");
+    child(interface, "target");
+    assert!(child(interface, "source")
+        .attributes
+        .iter()
+        .any(|(n, _)| n == "portid"));
+
+    // unmodelled parts come back in their original place
+    let names: Vec<_> = xml
+        .children
+        .iter()
+        .filter_map(|c| match c {
+            Node::Element(e) => Some(e.name.as_str()),
+            _ => None,
+        })
+        .take(6)
+        .collect();
+    assert_eq!(
+        names,
+        ["name", "uuid", "metadata", "memory", "vcpu", "resource"]
+    );
+}
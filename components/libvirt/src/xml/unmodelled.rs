@@ -0,0 +1,312 @@
+//! Keeps the parts of libvirt XML the typed model doesn't cover.
+//!
+//! The model is serialized as usual and then merged with the document it was parsed from.
+//! Elements, attributes and text the parse dropped are put back after the sibling they
+//! followed, unless an edit removed the element holding them.
+
+use xml::{
+    namespace::Namespace,
+    reader::{EventReader, ParserConfig, XmlEvent as ReadEvent},
+    writer::{EmitterConfig, EventWriter, XmlEvent as WriteEvent},
+};
+
+/// What a model dropped when it was parsed, nothing for models built in code.
+#[derive(Debug, Clone, Default)]
+pub struct Unmodelled(Option<Box<Sources>>);
+
+#[derive(Debug, Clone)]
+struct Sources {
+    /// The document as parsed.
+    original: Element,
+    /// The model serialized right after parsing, before any edit.
+    parsed: Element,
+}
+
+impl Unmodelled {
+    /// `parsed` is the serialization of the model parsed from `original`.
+    pub(crate) fn new(original: &str, parsed: &str) -> Result<Self, xml_serde::Error> {
+        Ok(Self(Some(Box::new(Sources {
+            original: Element::parse(original)?,
+            parsed: Element::parse(parsed)?,
+        }))))
+    }
+
+    /// Puts back what was dropped into `serialized`, the serialization of the edited model.
+    pub(crate) fn restore(&self, serialized: String) -> Result<String, xml_serde::Error> {
+        let sources = match &self.0 {
+            Some(sources) => sources,
+            None => return Ok(serialized),
+        };
+
+        let mut out = Element::parse(&serialized)?;
+        merge(&mut out, &sources.parsed, &sources.original);
+
+        out.write()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Element {
+    /// Qualified name, with the prefix if it has one.
+    pub name: String,
+    /// Namespace declarations are kept as `xmlns` attributes.
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Node {
+    /// Nodes only pair up with nodes of the same key, text has none.
+    fn key(&self) -> Option<&str> {
+        match self {
+            Node::Element(e) => Some(&e.name),
+            Node::Text(_) => None,
+        }
+    }
+}
+
+impl Element {
+    /// Parses with the same whitespace handling as `xml_serde`, comments are dropped.
+    pub(crate) fn parse(s: &str) -> Result<Self, xml_serde::Error> {
+        let conf = ParserConfig::new()
+            .trim_whitespace(true)
+            .whitespace_to_characters(true);
+
+        let mut stack: Vec<(Element, Namespace)> = vec![];
+
+        for event in EventReader::new_with_config(s.as_bytes(), conf) {
+            match event.map_err(xml_serde::Error::XMLRError)? {
+                ReadEvent::StartElement {
+                    name,
+                    attributes,
+                    namespace,
+                } => {
+                    let inherited = stack.last().map(|(_, ns)| ns);
+
+                    let declarations = namespace
+                        .0
+                        .iter()
+                        .filter(|(prefix, _)| !matches!(prefix.as_str(), "xml" | "xmlns"))
+                        .filter(|(prefix, uri)| match inherited {
+                            Some(ns) => ns.get(prefix.as_str()) != Some(uri.as_str()),
+                            None => !(prefix.is_empty() && uri.is_empty()),
+                        })
+                        .map(|(prefix, uri)| match prefix.as_str() {
+                            "" => ("xmlns".to_string(), uri.clone()),
+                            _ => (format!("xmlns:{}", prefix), uri.clone()),
+                        });
+
+                    let element = Element {
+                        name: name.borrow().to_repr(),
+                        attributes: declarations
+                            .chain(
+                                attributes
+                                    .into_iter()
+                                    .map(|a| (a.name.borrow().to_repr(), a.value)),
+                            )
+                            .collect(),
+                        children: vec![],
+                    };
+
+                    stack.push((element, namespace));
+                }
+                ReadEvent::EndElement { .. } => {
+                    let (element, _) = stack.pop().expect("the parser checks nesting");
+
+                    match stack.last_mut() {
+                        Some((parent, _)) => parent.children.push(Node::Element(element)),
+                        None => return Ok(element),
+                    }
+                }
+                ReadEvent::Characters(text) | ReadEvent::CData(text) => {
+                    if let Some((parent, _)) = stack.last_mut() {
+                        parent.children.push(Node::Text(text));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        unreachable!("the parser fails on documents without a root element")
+    }
+
+    /// Writes with the same settings as `xml_serde`.
+    pub(crate) fn write(&self) -> Result<String, xml_serde::Error> {
+        let mut out = vec![];
+
+        let mut writer = EmitterConfig::new()
+            .perform_indent(true)
+            .write_document_declaration(true)
+            .normalize_empty_elements(true)
+            .cdata_to_characters(true)
+            .keep_element_names_stack(true)
+            .pad_self_closing(false)
+            .create_writer(&mut out);
+
+        self.emit(&mut writer)
+            .map_err(xml_serde::Error::XMLWError)?;
+
+        Ok(String::from_utf8(out).expect("only strings were written"))
+    }
+
+    fn emit<W: std::io::Write>(&self, writer: &mut EventWriter<W>) -> xml::writer::Result<()> {
+        let start = self.attributes.iter().fold(
+            WriteEvent::start_element(self.name.as_str()),
+            |e, (n, v)| e.attr(n.as_str(), v),
+        );
+
+        writer.write(start)?;
+
+        for child in &self.children {
+            match child {
+                Node::Element(e) => e.emit(writer)?,
+                Node::Text(t) => writer.write(WriteEvent::characters(t))?,
+            }
+        }
+
+        writer.write(WriteEvent::end_element())
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Adds to `out` what `original` has and `parsed` doesn't, `out` being `parsed` after edits.
+fn merge(out: &mut Element, parsed: &Element, original: &Element) {
+    for (name, value) in &original.attributes {
+        if parsed.attribute(name).is_none() && out.attribute(name).is_none() {
+            out.attributes.push((name.clone(), value.clone()));
+        }
+    }
+
+    let known = pair_in_order(&original.children, &parsed.children);
+    let edited = pair_edited(&parsed.children, &out.children);
+
+    for (o, &p) in known.iter().enumerate() {
+        let (p, e) = match p.and_then(|p| edited[p].map(|e| (p, e))) {
+            Some(pair) => pair,
+            None => continue,
+        };
+
+        if let (Node::Element(out), Node::Element(parsed), Node::Element(original)) = (
+            &mut out.children[e],
+            &parsed.children[p],
+            &original.children[o],
+        ) {
+            merge(out, parsed, original);
+        }
+    }
+
+    // unknown nodes go after the closest known sibling before them that survived the edit
+    let mut after = 0;
+    let mut inserts = vec![];
+
+    for (o, node) in original.children.iter().enumerate() {
+        match known[o] {
+            Some(p) => {
+                if let Some(e) = edited[p] {
+                    after = e + 1;
+                }
+            }
+            None => inserts.push((after, node.clone())),
+        }
+    }
+
+    // back to front keeps the positions valid, the stable sort keeps runs in order
+    inserts.sort_by_key(|(at, _)| *at);
+
+    for (at, node) in inserts.into_iter().rev() {
+        out.children.insert(at, node);
+    }
+}
+
+/// For each of `a`, the node of `b` with the same key and the same position among those.
+fn pair_in_order(a: &[Node], b: &[Node]) -> Vec<Option<usize>> {
+    let mut used = vec![false; b.len()];
+
+    a.iter()
+        .map(|node| {
+            let i = (0..b.len()).find(|&i| !used[i] && b[i].key() == node.key())?;
+            used[i] = true;
+            Some(i)
+        })
+        .collect()
+}
+
+/// For each of `parsed`, the node of `out` it became, `None` if the edit removed it.
+///
+/// Unchanged nodes pair first, then the ones sharing the most attributes and children, then
+/// the rest in order.
+fn pair_edited(parsed: &[Node], out: &[Node]) -> Vec<Option<usize>> {
+    let mut pairs = vec![None; parsed.len()];
+    let mut used = vec![false; out.len()];
+
+    let mut take = |p: usize, o: usize, pairs: &mut Vec<Option<usize>>| {
+        if pairs[p].is_none() && !used[o] && parsed[p].key() == out[o].key() {
+            pairs[p] = Some(o);
+            used[o] = true;
+        }
+    };
+
+    for (p, node) in parsed.iter().enumerate() {
+        for o in (0..out.len()).filter(|&o| out[o] == *node) {
+            take(p, o, &mut pairs);
+        }
+    }
+
+    let mut similar = vec![];
+
+    for (p, parsed) in parsed.iter().enumerate() {
+        for (o, out) in out.iter().enumerate() {
+            match similarity(parsed, out) {
+                0 => {}
+                score => similar.push((score, p, o)),
+            }
+        }
+    }
+
+    similar.sort_by_key(|(score, _, _)| std::cmp::Reverse(*score));
+
+    for (_, p, o) in similar {
+        take(p, o, &mut pairs);
+    }
+
+    for p in 0..parsed.len() {
+        for o in 0..out.len() {
+            take(p, o, &mut pairs);
+        }
+    }
+
+    pairs
+}
+
+/// Attributes and child elements two elements of the same name have in common.
+fn similarity(a: &Node, b: &Node) -> usize {
+    let (a, b) = match (a, b) {
+        (Node::Element(a), Node::Element(b)) if a.name == b.name => (a, b),
+        _ => return 0,
+    };
+
+    let attributes = a
+        .attributes
+        .iter()
+        .filter(|(n, v)| b.attribute(n) == Some(v.as_str()))
+        .count();
+
+    let children = a
+        .children
+        .iter()
+        .filter(|c| matches!(c, Node::Element(_)) && b.children.contains(c))
+        .count();
+
+    attributes + children
+}
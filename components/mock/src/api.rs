@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_trait::async_trait;
+use tokio::sync::broadcast::error::RecvError;
+
+use istruct_common::{
+    api::{
+        compute::{
+            devadm::v1::{DevAdmApi, DeviceType},
+            machine::{
+                device::v1::{CpuDevice, MachineDevApi, MemoryDevice},
+                v1::{
+                    Adopt, AttrValue, MachineAction, MachineApi, MachineEvent, MachineSpec,
+                    MachineStatus,
+                },
+            },
+        },
+        network::device::v1::NetworkDevApi,
+        operation::v1::Pending,
+        storage::device::v1::{BlockDevice, CdromDevice, StorageDevApi},
+        ApiBase,
+    },
+    error::{Error, Result},
+    etag::{IfMatch, Tagged},
+    event::EventStream,
+    id::{DeviceId, MachineId, OperationId},
+};
+
+use super::{check_media, DeviceKind, Mock};
+
+impl ApiBase for Mock {}
+
+#[async_trait]
+impl MachineApi for Mock {
+    async fn act(&self, machine: MachineId, action: MachineAction) -> Result<OperationId> {
+        self.with(|s| s.act(machine, action))?;
+
+        let op = self.ops.start("act");
+        let id = op.id();
+
+        op.succeed(());
+
+        Ok(id)
+    }
+
+    async fn status(&self, machine: MachineId) -> Result<Option<MachineStatus>> {
+        Ok(self.with(|s| s.status(machine)))
+    }
+
+    async fn get_attr(&self, machine: MachineId, attr: String) -> Result<Tagged<AttrValue>> {
+        self.with(|s| {
+            s.get_attr(machine, &attr)
+                .map(|v| Tagged::new(v, s.etag(machine)))
+        })
+    }
+
+    async fn set_attr(
+        &self,
+        machine: MachineId,
+        attr: String,
+        if_match: IfMatch,
+        value: AttrValue,
+    ) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(machine, &if_match)?;
+            s.set_attr(machine, &attr, value)
+        })
+    }
+
+    async fn delete_attr(&self, machine: MachineId, attr: String, if_match: IfMatch) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(machine, &if_match)?;
+            s.delete_attr(machine, &attr)
+        })
+    }
+
+    async fn get_attrs(&self, machine: MachineId) -> Result<Tagged<BTreeMap<String, AttrValue>>> {
+        self.with(|s| {
+            s.get_attrs(machine)
+                .map(|v| Tagged::new(v, s.etag(machine)))
+        })
+    }
+
+    async fn patch_attrs(
+        &self,
+        machine: MachineId,
+        if_match: IfMatch,
+        attrs: HashMap<String, Option<AttrValue>>,
+    ) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(machine, &if_match)?;
+            s.patch_attrs(machine, attrs)
+        })
+    }
+
+    async fn list_attrs(&self, machine: MachineId) -> Result<Vec<String>> {
+        self.with(|s| s.get_attrs(machine).map(|a| a.into_keys().collect()))
+    }
+
+    async fn dev_list(
+        &self,
+        machine: MachineId,
+    ) -> Result<Option<Tagged<HashMap<DeviceId, String>>>> {
+        Ok(self.with(|s| {
+            s.machine(machine).ok()?;
+
+            let devices = s
+                .attached_to(machine)
+                .map(|(id, d)| (id, d.kind.type_name().to_string()))
+                .collect();
+
+            Some(Tagged::new(devices, s.etag(machine)))
+        }))
+    }
+
+    async fn dev_attach(
+        &self,
+        machine: MachineId,
+        device: DeviceId,
+        if_match: IfMatch,
+    ) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(machine, &if_match)?;
+            s.attach(machine, device)
+        })
+    }
+
+    async fn dev_detach(
+        &self,
+        machine: MachineId,
+        device: DeviceId,
+        if_match: IfMatch,
+    ) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(machine, &if_match)?;
+            s.detach(machine, device)
+        })
+    }
+
+    async fn create(&self, spec: MachineSpec) -> Result<MachineId> {
+        self.with(|s| s.create(spec))
+    }
+
+    async fn destroy(&self, machine: MachineId, if_match: IfMatch) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(machine, &if_match)?;
+            s.destroy(machine)
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<MachineId>> {
+        Ok(self.with(|s| s.machines.keys().copied().collect()))
+    }
+
+    /// There's no backend to adopt from.
+    async fn adoptable(&self) -> Result<Vec<MachineId>> {
+        Ok(vec![])
+    }
+
+    async fn adopt(&self, machine: MachineId, _: Adopt) -> Result<HashMap<DeviceId, String>> {
+        self.with(|s| {
+            if s.machines.contains_key(&machine) {
+                Err(Error::invalid_state("machine is already known"))
+            } else {
+                Err(Error::not_found("machine does not exist"))
+            }
+        })
+    }
+
+    async fn events(&self) -> Result<EventStream<MachineEvent>> {
+        let rx = self.events.subscribe();
+
+        let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    // lagging subscribers skip ahead, they can catch up with /status
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(Box::pin(stream))
+    }
+}
+
+#[async_trait]
+impl DevAdmApi for Mock {
+    async fn all(&self) -> Result<HashMap<DeviceId, DeviceType>> {
+        Ok(self.with(|s| {
+            s.devices
+                .iter()
+                .map(|(id, d)| (*id, d.kind.type_name().to_string()))
+                .collect()
+        }))
+    }
+
+    async fn get_type(&self, dev: DeviceId) -> Result<Option<DeviceType>> {
+        Ok(self.with(|s| s.devices.get(&dev).map(|d| d.kind.type_name().to_string())))
+    }
+}
+
+#[async_trait]
+impl MachineDevApi for Mock {
+    async fn get_memory(&self, device: DeviceId) -> Result<Option<Tagged<MemoryDevice>>> {
+        Ok(self.with(|s| match s.devices.get(&device)?.kind {
+            DeviceKind::Mem(bytes) => Some(Tagged::new(MemoryDevice { bytes }, s.etag(device))),
+            _ => None,
+        }))
+    }
+
+    async fn set_memory(
+        &self,
+        device: DeviceId,
+        if_match: IfMatch,
+        memory: MemoryDevice,
+    ) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(device, &if_match)?;
+            s.set_compute(device, DeviceKind::Mem(memory.bytes))
+        })
+    }
+
+    async fn get_cpu(&self, device: DeviceId) -> Result<Option<Tagged<CpuDevice>>> {
+        Ok(self.with(|s| match s.devices.get(&device)?.kind {
+            DeviceKind::Cpu(cores) => Some(Tagged::new(CpuDevice { cores }, s.etag(device))),
+            _ => None,
+        }))
+    }
+
+    async fn set_cpu(&self, device: DeviceId, if_match: IfMatch, cpu: CpuDevice) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(device, &if_match)?;
+            s.set_compute(device, DeviceKind::Cpu(cpu.cores))
+        })
+    }
+}
+
+#[async_trait]
+impl NetworkDevApi for Mock {
+    async fn create_nat(&self) -> Result<DeviceId> {
+        Ok(self.with(|s| s.add_device(DeviceKind::Nat)))
+    }
+
+    async fn delete_nat(&self, device: DeviceId) -> Result<()> {
+        self.with(|s| {
+            s.delete(
+                device,
+                |k| *k == DeviceKind::Nat,
+                "device was not a nat interface",
+            )
+        })
+    }
+}
+
+#[async_trait]
+impl StorageDevApi for Mock {
+    async fn get_block(&self, device: DeviceId) -> Result<Option<Tagged<BlockDevice>>> {
+        Ok(self.with(|s| match s.devices.get(&device)?.kind {
+            DeviceKind::Block(bytes) => Some(Tagged::new(BlockDevice { bytes }, s.etag(device))),
+            _ => None,
+        }))
+    }
+
+    async fn create_block(&self, block: BlockDevice) -> Result<Pending<DeviceId>> {
+        if block.bytes == 0 {
+            return Err(Error::invalid_argument(
+                "block devices need at least 1 byte",
+            ));
+        }
+
+        let device = self.with(|s| s.add_device(DeviceKind::Block(block.bytes)));
+
+        let op = self.ops.start("create_block");
+
+        let pending = Pending {
+            operation: op.id(),
+            target: device,
+        };
+
+        op.succeed(device);
+
+        Ok(pending)
+    }
+
+    async fn delete_block(&self, device: DeviceId, if_match: IfMatch) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(device, &if_match)?;
+            s.delete(
+                device,
+                |k| matches!(k, DeviceKind::Block(_)),
+                "device was not block storage",
+            )
+        })
+    }
+
+    async fn get_cdrom(&self, device: DeviceId) -> Result<Option<Tagged<CdromDevice>>> {
+        Ok(self.with(|s| match &s.devices.get(&device)?.kind {
+            DeviceKind::Cdrom(media) => Some(Tagged::new(
+                CdromDevice {
+                    media: media.clone(),
+                },
+                s.etag(device),
+            )),
+            _ => None,
+        }))
+    }
+
+    async fn create_cdrom(&self, cdrom: CdromDevice) -> Result<DeviceId> {
+        check_media(&cdrom.media)?;
+
+        Ok(self.with(|s| s.add_device(DeviceKind::Cdrom(cdrom.media))))
+    }
+
+    async fn delete_cdrom(&self, device: DeviceId, if_match: IfMatch) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(device, &if_match)?;
+            s.delete(
+                device,
+                |k| matches!(k, DeviceKind::Cdrom(_)),
+                "device was not a cdrom",
+            )
+        })
+    }
+
+    async fn insert_media(&self, device: DeviceId, if_match: IfMatch, media: String) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(device, &if_match)?;
+            s.set_media(device, Some(media))
+        })
+    }
+
+    async fn eject_media(&self, device: DeviceId, if_match: IfMatch) -> Result<()> {
+        self.with(|s| {
+            s.check_etag(device, &if_match)?;
+            s.set_media(device, None)
+        })
+    }
+}
@@ -0,0 +1,618 @@
+//! In-memory component, with the device semantics of the libvirt one but without a backend.
+//!
+//! Machines change state right away, and cdrom media isn't checked for existence.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use istruct_common::{
+    api::compute::machine::v1::{
+        validate_attr_key, AttrValue, MachineAction, MachineEvent, MachineSpec, MachineState,
+        MachineStatus,
+    },
+    error::{Error, Result},
+    etag::{ETag, IfMatch},
+    id::{DeviceId, MachineId},
+    operation::Operations,
+};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+mod api;
+
+/// How many events a slow subscriber can fall behind before it starts missing them.
+const EVENT_BUFFER: usize = 64;
+
+#[derive(Clone)]
+pub struct Mock {
+    state: Arc<Mutex<State>>,
+    pub ops: Operations,
+    events: broadcast::Sender<MachineEvent>,
+}
+
+#[derive(Default)]
+struct State {
+    machines: HashMap<MachineId, Machine>,
+    devices: HashMap<DeviceId, Device>,
+    /// See [`State::etag`].
+    generations: HashMap<Uuid, u64>,
+    /// Sent once the state is unlocked.
+    events: Vec<MachineEvent>,
+}
+
+struct Machine {
+    name: String,
+    state: MachineState,
+    attrs: BTreeMap<String, AttrValue>,
+}
+
+struct Device {
+    kind: DeviceKind,
+    attached: Option<MachineId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DeviceKind {
+    Cpu(u64),
+    Mem(u64),
+    Block(u64),
+    Cdrom(Option<String>),
+    Nat,
+}
+
+impl DeviceKind {
+    fn type_name(&self) -> &'static str {
+        match self {
+            DeviceKind::Cpu(_) => "is.compute.cpu",
+            DeviceKind::Mem(_) => "is.compute.mem",
+            DeviceKind::Block(_) => "is.storage.block",
+            DeviceKind::Cdrom(_) => "is.storage.cdrom",
+            DeviceKind::Nat => "is.network.nat",
+        }
+    }
+
+    fn is_compute(&self) -> bool {
+        matches!(self, DeviceKind::Cpu(_) | DeviceKind::Mem(_))
+    }
+}
+
+impl Default for Mock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mock {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+
+        Mock {
+            state: Arc::default(),
+            ops: Operations::new(),
+            events,
+        }
+    }
+
+    /// Runs `f` on the locked state, sending the events it emitted afterwards.
+    fn with<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        let (r, events) = {
+            let mut state = self.state.lock().unwrap();
+            let r = f(&mut state);
+
+            (r, std::mem::take(&mut state.events))
+        };
+
+        for event in events {
+            // no subscribers is fine
+            let _ = self.events.send(event);
+        }
+
+        r
+    }
+}
+
+// machines
+impl State {
+    fn emit(&mut self, event: MachineEvent) {
+        self.events.push(event);
+    }
+
+    fn machine(&self, machine: MachineId) -> Result<&Machine> {
+        self.machines
+            .get(&machine)
+            .ok_or_else(|| Error::not_found("machine does not exist"))
+    }
+
+    fn machine_mut(&mut self, machine: MachineId) -> Result<&mut Machine> {
+        self.machines
+            .get_mut(&machine)
+            .ok_or_else(|| Error::not_found("machine does not exist"))
+    }
+
+    fn status(&self, machine: MachineId) -> Option<MachineStatus> {
+        self.machines.get(&machine).map(|m| MachineStatus {
+            state: m.state,
+            reason: None,
+        })
+    }
+
+    fn act(&mut self, machine: MachineId, action: MachineAction) -> Result<()> {
+        let m = self.machine_mut(machine)?;
+
+        action.check(m.state)?;
+
+        // there's nothing to wait for
+        if let Some(state) = action.target() {
+            m.state = state;
+
+            self.emit(MachineEvent::StateChanged {
+                machine,
+                state,
+                reason: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn create(&mut self, spec: MachineSpec) -> Result<MachineId> {
+        spec.validate()?;
+
+        // check devices before anything is created, so we don't have to roll back
+        for device in &spec.devices {
+            let d = self
+                .devices
+                .get(device)
+                .ok_or_else(|| Error::not_found(format!("could not find device {}", device)))?;
+
+            if d.kind.is_compute() {
+                return Err(Error::invalid_argument("cannot reassign compute devices"));
+            }
+
+            if let Some(m) = d.attached {
+                return Err(Error::already_attached(format!(
+                    "device {} is attached to {}",
+                    device, m
+                )));
+            }
+        }
+
+        let uuid = Uuid::new_v4();
+
+        let name = spec.name.unwrap_or_else(|| uuid.to_string());
+
+        if self.machines.values().any(|m| m.name == name) {
+            return Err(Error::invalid_state(format!(
+                "a machine named {} already exists",
+                name
+            )));
+        }
+
+        self.machines.insert(
+            uuid,
+            Machine {
+                name,
+                state: MachineState::Off,
+                attrs: BTreeMap::new(),
+            },
+        );
+
+        for kind in [
+            DeviceKind::Mem(spec.memory_bytes),
+            DeviceKind::Cpu(spec.cores),
+        ] {
+            self.devices.insert(
+                Uuid::new_v4(),
+                Device {
+                    kind,
+                    attached: Some(uuid),
+                },
+            );
+        }
+
+        self.emit(MachineEvent::Created { machine: uuid });
+
+        for device in spec.devices {
+            self.attach(uuid, device)
+                .expect("devices were checked before");
+        }
+
+        Ok(uuid)
+    }
+
+    fn destroy(&mut self, machine: MachineId) -> Result<()> {
+        if self.machine(machine)?.state != MachineState::Off {
+            return Err(Error::invalid_state("machine is not off"));
+        }
+
+        let attached: Vec<_> = self.attached_to(machine).map(|(d, _)| d).collect();
+
+        for device in attached {
+            if self.devices[&device].kind.is_compute() {
+                // these don't outlive their machine
+                self.devices.remove(&device);
+                self.generations.remove(&device);
+            } else {
+                self.detach(machine, device)
+                    .expect("machine is off, removing stuff should be fine");
+            }
+        }
+
+        self.machines.remove(&machine);
+        self.generations.remove(&machine);
+
+        self.emit(MachineEvent::Destroyed { machine });
+
+        Ok(())
+    }
+}
+
+// machine attributes
+impl State {
+    fn get_attr(&self, machine: MachineId, attr: &str) -> Result<AttrValue> {
+        self.machine(machine)?
+            .attrs
+            .get(attr)
+            .cloned()
+            .ok_or_else(|| Error::not_found(format!("attribute {} is not set", attr)))
+    }
+
+    fn get_attrs(&self, machine: MachineId) -> Result<BTreeMap<String, AttrValue>> {
+        Ok(self.machine(machine)?.attrs.clone())
+    }
+
+    fn set_attr(&mut self, machine: MachineId, attr: &str, value: AttrValue) -> Result<()> {
+        self.machine(machine)?;
+        validate_attr_key(attr)?;
+
+        self.machine_mut(machine)?.attrs.insert(attr.into(), value);
+        self.bump_generation(machine);
+
+        Ok(())
+    }
+
+    fn delete_attr(&mut self, machine: MachineId, attr: &str) -> Result<()> {
+        if self.machine_mut(machine)?.attrs.remove(attr).is_none() {
+            return Err(Error::not_found(format!("attribute {} is not set", attr)));
+        }
+
+        self.bump_generation(machine);
+
+        Ok(())
+    }
+
+    fn patch_attrs(
+        &mut self,
+        machine: MachineId,
+        attrs: HashMap<String, Option<AttrValue>>,
+    ) -> Result<()> {
+        self.machine(machine)?;
+
+        for attr in attrs.keys() {
+            validate_attr_key(attr)?;
+        }
+
+        let m = self.machine_mut(machine)?;
+
+        for (attr, value) in attrs {
+            match value {
+                Some(value) => m.attrs.insert(attr, value),
+                None => m.attrs.remove(&attr),
+            };
+        }
+
+        self.bump_generation(machine);
+
+        Ok(())
+    }
+}
+
+// devices
+impl State {
+    fn device(&self, device: DeviceId) -> Result<&Device> {
+        self.devices
+            .get(&device)
+            .ok_or_else(|| Error::not_found("could not find device"))
+    }
+
+    fn attached_to(&self, machine: MachineId) -> impl Iterator<Item = (DeviceId, &Device)> {
+        self.devices
+            .iter()
+            .filter(move |(_, d)| d.attached == Some(machine))
+            .map(|(id, d)| (*id, d))
+    }
+
+    fn add_device(&mut self, kind: DeviceKind) -> DeviceId {
+        let uuid = Uuid::new_v4();
+
+        self.devices.insert(
+            uuid,
+            Device {
+                kind,
+                attached: None,
+            },
+        );
+
+        uuid
+    }
+
+    fn attach(&mut self, machine: MachineId, device: DeviceId) -> Result<()> {
+        let d = self.device(device)?;
+
+        if d.kind.is_compute() {
+            return Err(Error::invalid_argument("cannot reassign compute devices"));
+        }
+
+        match d.attached {
+            Some(m) if m == machine => {
+                return Err(Error::already_attached(
+                    "device already attached to this machine",
+                ))
+            }
+            Some(_) => {
+                return Err(Error::already_attached(
+                    "device already attached to other machine",
+                ))
+            }
+            None => {}
+        }
+
+        self.machine(machine)?;
+
+        self.devices.get_mut(&device).unwrap().attached = Some(machine);
+        self.bump_generation(machine);
+
+        self.emit(MachineEvent::DeviceAttached { machine, device });
+
+        Ok(())
+    }
+
+    fn detach(&mut self, machine: MachineId, device: DeviceId) -> Result<()> {
+        let d = self.device(device)?;
+
+        if d.kind.is_compute() {
+            return Err(Error::invalid_argument("cannot reassign compute devices"));
+        }
+
+        match d.attached {
+            Some(m) if m != machine => {
+                return Err(Error::invalid_state(
+                    "device is attached to different machine",
+                ))
+            }
+            None => return Err(Error::invalid_state("device is not attached")),
+            Some(_) => {}
+        }
+
+        self.devices.get_mut(&device).unwrap().attached = None;
+        self.bump_generation(machine);
+
+        self.emit(MachineEvent::DeviceDetached { machine, device });
+
+        Ok(())
+    }
+
+    /// Deletes an unattached device of the kind `is_kind` accepts.
+    fn delete(
+        &mut self,
+        device: DeviceId,
+        is_kind: impl FnOnce(&DeviceKind) -> bool,
+        not_kind: &str,
+    ) -> Result<()> {
+        let d = self.device(device)?;
+
+        if !is_kind(&d.kind) {
+            return Err(Error::invalid_argument(not_kind.to_string()));
+        }
+
+        if let Some(machine) = d.attached {
+            return Err(Error::already_attached(format!(
+                "device is attached to {}",
+                machine
+            )));
+        }
+
+        self.devices.remove(&device);
+        self.generations.remove(&device);
+
+        Ok(())
+    }
+
+    fn set_compute(&mut self, device: DeviceId, kind: DeviceKind) -> Result<()> {
+        let (amount, not_kind) = match kind {
+            DeviceKind::Cpu(cores) => (cores, "device is not cpu"),
+            DeviceKind::Mem(bytes) => (bytes, "device is not memory"),
+            _ => unreachable!("only compute devices are set"),
+        };
+
+        if amount == 0 {
+            return Err(Error::invalid_argument(match kind {
+                DeviceKind::Cpu(_) => "cores have to be above 0",
+                _ => "memory bytes have to be above 0",
+            }));
+        }
+
+        let d = self
+            .devices
+            .get_mut(&device)
+            .ok_or_else(|| Error::not_found("cannot find device"))?;
+
+        if std::mem::discriminant(&d.kind) != std::mem::discriminant(&kind) {
+            return Err(Error::invalid_argument(not_kind));
+        }
+
+        d.kind = kind;
+        self.bump_generation(device);
+
+        Ok(())
+    }
+
+    fn set_media(&mut self, device: DeviceId, media: Option<String>) -> Result<()> {
+        check_media(&media)?;
+
+        let d = self
+            .devices
+            .get_mut(&device)
+            .ok_or_else(|| Error::not_found("could not find device"))?;
+
+        match &mut d.kind {
+            DeviceKind::Cdrom(m) => *m = media,
+            _ => return Err(Error::invalid_argument("device was not a cdrom")),
+        }
+
+        self.bump_generation(device);
+
+        Ok(())
+    }
+}
+
+/// Only the path is checked, the media doesn't have to exist.
+fn check_media(media: &Option<String>) -> Result<()> {
+    match media {
+        Some(media) if !media.starts_with('/') => {
+            Err(Error::invalid_argument("media path has to be absolute"))
+        }
+        _ => Ok(()),
+    }
+}
+
+// generations, see the libvirt component for what they cover
+impl State {
+    fn etag(&self, resource: Uuid) -> ETag {
+        ETag::new(self.generations.get(&resource).copied().unwrap_or(0))
+    }
+
+    fn check_etag(&self, resource: Uuid, if_match: &IfMatch) -> Result<()> {
+        if_match.check(&self.etag(resource))
+    }
+
+    fn bump_generation(&mut self, resource: Uuid) {
+        *self.generations.entry(resource).or_insert(0) += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use istruct_common::{
+        api::{
+            self,
+            compute::{
+                devadm::v1::DevAdmApi,
+                machine::v1::{MachineAction, MachineApi, MachineSpec},
+            },
+            network::device::v1::NetworkDevApi,
+        },
+        conformance,
+        error::ErrorKind,
+        etag::IfMatch,
+        router::CompositeRouter,
+    };
+
+    use super::Mock;
+
+    #[tokio::test]
+    async fn compute_devices_live_with_their_machine() {
+        let mock = Mock::new();
+
+        let machine = mock.create(MachineSpec::default()).await.unwrap();
+        let devices = mock.dev_list(machine).await.unwrap().unwrap().value;
+
+        assert_eq!(devices.len(), 2);
+
+        let cpu = *devices
+            .iter()
+            .find(|(_, t)| *t == "is.compute.cpu")
+            .unwrap()
+            .0;
+
+        let err = mock.dev_detach(machine, cpu, IfMatch::None).await;
+        assert_eq!(err.unwrap_err().kind, ErrorKind::InvalidArgument);
+
+        mock.destroy(machine, IfMatch::None).await.unwrap();
+
+        assert!(mock.all().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn devices_attach_once() {
+        let mock = Mock::new();
+
+        let nat = mock.create_nat().await.unwrap();
+
+        let a = mock.create(MachineSpec::default()).await.unwrap();
+        let b = mock
+            .create(MachineSpec {
+                name: Some("b".into()),
+                ..MachineSpec::default()
+            })
+            .await
+            .unwrap();
+
+        mock.dev_attach(a, nat, IfMatch::None).await.unwrap();
+
+        let err = mock.dev_attach(b, nat, IfMatch::None).await;
+        assert_eq!(err.unwrap_err().kind, ErrorKind::AlreadyAttached);
+
+        let err = mock.delete_nat(nat).await;
+        assert_eq!(err.unwrap_err().kind, ErrorKind::AlreadyAttached);
+
+        // destroying detaches it
+        mock.destroy(a, IfMatch::None).await.unwrap();
+        mock.dev_attach(b, nat, IfMatch::None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn destroy_only_when_off() {
+        let mock = Mock::new();
+
+        let machine = mock.create(MachineSpec::default()).await.unwrap();
+
+        mock.act(machine, MachineAction::Boot).await.unwrap();
+
+        let err = mock.destroy(machine, IfMatch::None).await;
+        assert_eq!(err.unwrap_err().kind, ErrorKind::InvalidState);
+
+        mock.act(machine, MachineAction::ForceShutdown)
+            .await
+            .unwrap();
+        mock.destroy(machine, IfMatch::None).await.unwrap();
+
+        assert!(mock.status(machine).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn conforms() {
+        let mock = Mock::new();
+
+        let report = conformance::run(&mock, &mock.ops).await;
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn conforms_over_http() {
+        let mock = Mock::new();
+
+        let router = CompositeRouter::new_with([
+            api::compute::machine::v1::convert(mock.clone()),
+            api::compute::machine::device::v1::convert(mock.clone()),
+            api::compute::devadm::v1::convert(mock.clone()),
+            api::storage::device::v1::convert(mock.clone()),
+            api::network::device::v1::convert(mock.clone()),
+            api::operation::v1::convert(mock.ops.clone()),
+        ])
+        .unwrap()
+        .assemble();
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(router.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let report = istruct_client::conformance(format!("http://{}", addr))
+            .await
+            .unwrap();
+        assert!(report.is_ok(), "{}", report);
+    }
+}
@@ -0,0 +1,51 @@
+use std::net::SocketAddr;
+
+use clap::Parser;
+use istruct_common::{
+    api,
+    router::{ComponentIdentity, CompositeRouter},
+};
+use istruct_mock::Mock;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "in-memory istruct component, state is lost on exit")]
+struct Args {
+    /// Address to listen on.
+    #[arg(long, env = "ISTRUCT_LISTEN", default_value = "127.0.0.1:8989")]
+    listen: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    use tower_http::trace::{DefaultMakeSpan, TraceLayer};
+
+    let args = Args::parse();
+
+    tracing_subscriber::fmt::init();
+
+    let mock = Mock::new();
+
+    let mut composite = CompositeRouter::new_with([
+        api::compute::machine::v1::convert(mock.clone()),
+        api::compute::machine::device::v1::convert(mock.clone()),
+        api::compute::devadm::v1::convert(mock.clone()),
+        api::storage::device::v1::convert(mock.clone()),
+        api::network::device::v1::convert(mock.clone()),
+        api::operation::v1::convert(mock.ops.clone()),
+    ])?;
+
+    composite.set_identity(ComponentIdentity::new(
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+    ));
+
+    let router = composite
+        .assemble()
+        .layer(TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::default()));
+
+    axum::Server::try_bind(&args.listen)?
+        .serve(router.into_make_service())
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,444 @@
+//! Proc-macros for `istruct-common`.
+//!
+//! The main one is [`macro@api`], which generates the `convert` function of an API trait.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, FnArg, GenericArgument, Ident, ItemTrait, LitInt, LitStr, Pat,
+    PathArguments, PathSegment, ReturnType, Token, TraitItem, TraitItemFn, Type,
+};
+
+/// Generates `convert<A: Trait>(api: A) -> VersionedRouter` for an API trait.
+///
+/// Has to be placed above `#[async_trait]`;
+///
+/// ```ignore
+/// #[api(prefix = "is.storage.device", major = 0, minor = 1)]
+/// #[async_trait]
+/// pub trait StorageDevApi: ApiBase {
+///     #[route(get, "/block/:did")]
+///     async fn get_block(&self, device: DeviceId) -> Option<BlockDevice>;
+///
+///     #[route(delete, "/block/:did", error = "failed to delete block device")]
+///     async fn delete_block(&self, device: DeviceId) -> Result<()>;
+/// }
+/// ```
+///
+/// Method arguments are taken from the path segments (`:did`) in order,
+/// a single remaining argument is taken as the JSON body.
+/// Routes marked with `default_body` take a missing body as the `Default` of its type.
+/// An argument of type `IfMatch` is taken from the `If-Match` header instead.
+///
+/// Return values are mapped as follows;
+/// - `()` is returned as an empty `200 OK`
+/// - `Option<T>` is returned as JSON, `None` becomes `none` (default `NOT_FOUND`)
+/// - `Result<T, E>` is returned as JSON, `Err` is converted into an `istruct_common::error::Error`,
+///   prefixed with `error`, and returned with the status of its kind
+/// - `Result<Option<T>, E>` is returned like `Result<T, E>`, `Ok(None)` like `None`
+/// - anything else is returned as JSON
+///
+/// `Tagged<T>` values, also inside `Option` and `Result`, additionally set the `ETag` header.
+///
+/// Routes marked with `sse` return an `EventStream<T>`, which is sent as server-sent events,
+/// or a `Result` of one.
+#[proc_macro_attribute]
+pub fn api(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = ApiArgs::default();
+
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("prefix") {
+            args.prefix = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("major") {
+            args.major = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("minor") {
+            args.minor = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("convert") {
+            args.convert = Some(meta.value()?.parse()?);
+        } else {
+            return Err(meta.error("unsupported api property"));
+        }
+        Ok(())
+    });
+
+    parse_macro_input!(attr with parser);
+
+    let item = parse_macro_input!(item as ItemTrait);
+
+    expand(args, item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+struct ApiArgs {
+    prefix: Option<LitStr>,
+    major: Option<LitInt>,
+    minor: Option<LitInt>,
+    convert: Option<Ident>,
+}
+
+struct RouteArgs {
+    method: Ident,
+    path: LitStr,
+    error: Option<LitStr>,
+    none: Option<Ident>,
+    sse: bool,
+    default_body: bool,
+}
+
+impl Parse for RouteArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let method: Ident = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let path: LitStr = input.parse()?;
+
+        let mut args = RouteArgs {
+            method,
+            path,
+            error: None,
+            none: None,
+            sse: false,
+            default_body: false,
+        };
+
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+
+            if input.is_empty() {
+                break;
+            }
+
+            let key: Ident = input.parse()?;
+
+            if key == "sse" {
+                args.sse = true;
+                continue;
+            }
+
+            if key == "default_body" {
+                args.default_body = true;
+                continue;
+            }
+
+            input.parse::<Token![=]>()?;
+
+            match key.to_string().as_str() {
+                "error" => args.error = Some(input.parse()?),
+                "none" => args.none = Some(input.parse()?),
+                _ => return Err(syn::Error::new(key.span(), "unsupported route property")),
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+enum Returns {
+    Unit,
+    Option { tagged: bool },
+    Result { ok_unit: bool, tagged: bool },
+    ResultOption { tagged: bool },
+    Other { tagged: bool },
+}
+
+fn expand(args: ApiArgs, mut item: ItemTrait) -> syn::Result<TokenStream2> {
+    let prefix = args
+        .prefix
+        .ok_or_else(|| syn::Error::new(Span::call_site(), "api requires `prefix`"))?;
+    let major = args
+        .major
+        .ok_or_else(|| syn::Error::new(Span::call_site(), "api requires `major`"))?;
+    let minor = args
+        .minor
+        .ok_or_else(|| syn::Error::new(Span::call_site(), "api requires `minor`"))?;
+    let convert = args.convert.unwrap_or_else(|| format_ident!("convert"));
+
+    // (path, [(method, handler)]), in order of first appearance
+    let mut routes: Vec<(String, Vec<(Ident, TokenStream2)>)> = vec![];
+
+    for trait_item in &mut item.items {
+        let func = match trait_item {
+            TraitItem::Fn(f) => f,
+            _ => continue,
+        };
+
+        let mut route = None;
+
+        for attr in std::mem::take(&mut func.attrs) {
+            if attr.path().is_ident("route") {
+                if route.is_some() {
+                    return Err(syn::Error::new_spanned(attr, "duplicate route attribute"));
+                }
+                route = Some(attr.parse_args::<RouteArgs>()?);
+            } else {
+                func.attrs.push(attr);
+            }
+        }
+
+        let route = match route {
+            Some(r) => r,
+            None => continue,
+        };
+
+        let handler = handler(func, &route)?;
+
+        let path = route.path.value();
+
+        if let Some((_, methods)) = routes.iter_mut().find(|(p, _)| p == &path) {
+            if methods.iter().any(|(m, _)| m == &route.method) {
+                return Err(syn::Error::new(
+                    route.method.span(),
+                    "method already routed for this path",
+                ));
+            }
+            methods.push((route.method, handler));
+        } else {
+            routes.push((path, vec![(route.method, handler)]));
+        }
+    }
+
+    let vis = &item.vis;
+    let trait_ident = &item.ident;
+
+    let routes = routes.into_iter().map(|(path, methods)| {
+        let mut methods = methods.into_iter();
+        let (first, first_handler) = methods.next().expect("route has at least one method");
+
+        let rest = methods.map(|(m, h)| quote! { .#m(#h) });
+
+        quote! {
+            .route(#path, ::axum::routing::#first(#first_handler) #(#rest)*)
+        }
+    });
+
+    Ok(quote! {
+        #item
+
+        #vis fn #convert<A: #trait_ident>(api: A) -> ::istruct_common::router::VersionedRouter {
+            let router = ::axum::Router::new()
+                #(#routes)*
+                .layer(::axum::extract::Extension(::std::sync::Arc::new(api)));
+
+            ::istruct_common::router::VersionedRouter::new(router, #prefix, #major, #minor)
+        }
+    })
+}
+
+fn handler(func: &TraitItemFn, route: &RouteArgs) -> syn::Result<TokenStream2> {
+    let name = &func.sig.ident;
+
+    let mut params = vec![];
+    let mut if_match = None;
+
+    for input in &func.sig.inputs {
+        if let FnArg::Typed(pat) = input {
+            let ident = match &*pat.pat {
+                Pat::Ident(i) => i.ident.clone(),
+                other => {
+                    return Err(syn::Error::new_spanned(
+                        other,
+                        "route arguments must be plain identifiers",
+                    ))
+                }
+            };
+
+            if is_type(&pat.ty, "IfMatch") {
+                if if_match.is_some() {
+                    return Err(syn::Error::new_spanned(pat, "duplicate IfMatch argument"));
+                }
+                if_match = Some(ident);
+            } else {
+                params.push(ident);
+            }
+        }
+    }
+
+    let path = route.path.value();
+    let path_count = path
+        .split('/')
+        .filter(|s| s.starts_with(':') || s.starts_with('*'))
+        .count();
+
+    if params.len() < path_count || params.len() > path_count + 1 {
+        return Err(syn::Error::new(
+            route.path.span(),
+            format!(
+                "route has {} path parameters, method takes {} arguments",
+                path_count,
+                params.len()
+            ),
+        ));
+    }
+
+    let path_params = &params[..path_count];
+    let body_param = params.get(path_count);
+
+    let mut extractors = vec![quote! {
+        ::axum::extract::Extension::<::std::sync::Arc<A>>(api)
+    }];
+
+    match path_params {
+        [] => {}
+        [one] => extractors.push(quote! { ::axum::extract::Path(#one) }),
+        many => extractors.push(quote! { ::axum::extract::Path((#(#many),*)) }),
+    }
+
+    if let Some(if_match) = &if_match {
+        extractors.push(quote! { #if_match: ::istruct_common::etag::IfMatch });
+    }
+
+    // the body has to be extracted last
+    match body_param {
+        Some(body) if route.default_body => {
+            extractors.push(quote! { ::istruct_common::api::JsonOrDefault(#body) })
+        }
+        Some(body) => extractors.push(quote! { ::axum::Json(#body) }),
+        None if route.default_body => {
+            return Err(syn::Error::new(
+                route.path.span(),
+                "route is marked `default_body`, but takes no body",
+            ))
+        }
+        None => {}
+    }
+
+    // arguments are passed in their declared order
+    let args = func.sig.inputs.iter().filter_map(|input| match input {
+        FnArg::Typed(pat) => match &*pat.pat {
+            Pat::Ident(i) => Some(&i.ident),
+            _ => None,
+        },
+        FnArg::Receiver(_) => None,
+    });
+
+    let call = quote! { api.#name(#(#args),*).await };
+
+    let error = route.error.clone().unwrap_or_else(|| {
+        LitStr::new(
+            &format!("failed to {}", name.to_string().replace('_', " ")),
+            name.span(),
+        )
+    });
+    let map_err = quote! {
+        .map_err(|e| ::istruct_common::error::Error::from(e).context(#error))
+    };
+
+    let none = route
+        .none
+        .clone()
+        .unwrap_or_else(|| format_ident!("NOT_FOUND"));
+    let map_some = |tagged| {
+        if tagged {
+            quote! {}
+        } else {
+            quote! { .map(::axum::Json) }
+        }
+    };
+
+    if route.sse {
+        let body = match returns(&func.sig.output) {
+            Returns::Result { .. } => quote! { #call .map(::istruct_common::event::sse) #map_err },
+            _ => quote! { ::istruct_common::event::sse(#call) },
+        };
+
+        return Ok(quote! {
+            |#(#extractors),*| async move { #body }
+        });
+    }
+
+    let body = match returns(&func.sig.output) {
+        Returns::Unit => call,
+        Returns::Option { tagged } => {
+            let map_some = map_some(tagged);
+
+            quote! {
+                #call #map_some .ok_or(::axum::http::StatusCode::#none)
+            }
+        }
+        Returns::Result { ok_unit, tagged } => {
+            let map_ok = map_some(ok_unit || tagged);
+
+            quote! {
+                #call #map_ok #map_err
+            }
+        }
+        Returns::ResultOption { tagged } => {
+            let map_some = map_some(tagged);
+
+            quote! {
+                #call .map(|o| o #map_some .ok_or(::axum::http::StatusCode::#none)) #map_err
+            }
+        }
+        Returns::Other { tagged: true } => call,
+        Returns::Other { tagged: false } => quote! { ::axum::Json(#call) },
+    };
+
+    Ok(quote! {
+        |#(#extractors),*| async move { #body }
+    })
+}
+
+fn returns(output: &ReturnType) -> Returns {
+    let ty = match output {
+        ReturnType::Default => return Returns::Unit,
+        ReturnType::Type(_, ty) => &**ty,
+    };
+
+    let last = match ty {
+        Type::Tuple(t) if t.elems.is_empty() => return Returns::Unit,
+        Type::Path(p) => p.path.segments.last().expect("type path is not empty"),
+        _ => return Returns::Other { tagged: false },
+    };
+
+    let inner = first_argument(last);
+    let inner_tagged = inner.is_some_and(|t| is_type(t, "Tagged"));
+
+    if last.ident == "Option" {
+        return Returns::Option {
+            tagged: inner_tagged,
+        };
+    }
+
+    if last.ident == "Result" {
+        if let Some(Type::Path(p)) = inner.filter(|t| is_type(t, "Option")) {
+            let some = p.path.segments.last().and_then(first_argument);
+
+            return Returns::ResultOption {
+                tagged: some.is_some_and(|t| is_type(t, "Tagged")),
+            };
+        }
+
+        let ok_unit = matches!(inner, Some(Type::Tuple(t)) if t.elems.is_empty());
+
+        return Returns::Result {
+            ok_unit,
+            tagged: inner_tagged,
+        };
+    }
+
+    Returns::Other {
+        tagged: last.ident == "Tagged",
+    }
+}
+
+/// The first generic argument of a path segment, `T` of `Option<T>`.
+fn first_argument(segment: &PathSegment) -> Option<&Type> {
+    match &segment.arguments {
+        PathArguments::AngleBracketed(a) => match a.args.first() {
+            Some(GenericArgument::Type(t)) => Some(t),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether the last segment of a type path is `name`.
+fn is_type(ty: &Type, name: &str) -> bool {
+    match ty {
+        Type::Path(p) => p.path.segments.last().is_some_and(|s| s.ident == name),
+        _ => false,
+    }
+}